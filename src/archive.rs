@@ -0,0 +1,565 @@
+use crate::hasher::{hash_reader_sized, HashType};
+use crate::snapshot::{FileAttributes, FileMetadata, Snapshot};
+use anyhow::{anyhow, Error};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek};
+use std::path::Path;
+use std::str::FromStr;
+use tar::EntryType;
+
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+/// Symlinks followed before giving up on a member, like the kernel's `ELOOP` limit.
+const MAX_LINK_DEPTH: usize = 40;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Tar,
+    TarGz,
+    TarZst,
+    /// Also jar, wheel and other zip based packages.
+    Zip,
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tar" => Ok(ArchiveFormat::Tar),
+            "tar.gz" | "tgz" => Ok(ArchiveFormat::TarGz),
+            "tar.zst" | "tzst" => Ok(ArchiveFormat::TarZst),
+            "zip" | "jar" | "war" | "whl" | "wheel" => Ok(ArchiveFormat::Zip),
+            _ => Err(anyhow!("unknown archive format: {s}")),
+        }
+    }
+}
+
+impl ArchiveFormat {
+    /// Tell the format from the first bytes of an archive.
+    pub fn detect(magic: &[u8]) -> ArchiveFormat {
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            ArchiveFormat::TarGz
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            ArchiveFormat::TarZst
+        } else if magic.starts_with(b"PK\x03\x04") || magic.starts_with(b"PK\x05\x06") {
+            ArchiveFormat::Zip
+        } else {
+            ArchiveFormat::Tar
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ArchiveOptions {
+    /// Detected from the archive's contents when `None`.
+    pub format: Option<ArchiveFormat>,
+    /// Leading path components dropped from member names, like `tar --strip-components`.
+    pub strip_components: usize,
+    /// Also record the stored CRC32 and compression method of zip members.
+    pub zip_details: bool,
+}
+
+/// The path of member `name` relative to the snapshot root, or `None` when nothing is left
+/// after stripping. Names climbing out of the archive with `..` are rejected.
+fn member_path(name: &str, strip_components: usize) -> Result<Option<String>, Error> {
+    let mut components = vec![];
+    for component in name.split('/') {
+        match component {
+            "" | "." => continue,
+            ".." => return Err(anyhow!("member {name} leaves the archive")),
+            component => components.push(component),
+        }
+    }
+    if components.len() <= strip_components {
+        return Ok(None);
+    }
+    Ok(Some(components[strip_components..].join("/")))
+}
+
+/// Resolve symlink `target` found at `path`, both relative to the snapshot root. Absolute
+/// targets point outside the archive and cannot be followed.
+fn resolve_symlink(path: &str, target: &str) -> Option<String> {
+    if target.starts_with('/') {
+        return None;
+    }
+    let mut components: Vec<&str> = path.split('/').collect();
+    components.pop();
+    for component in target.split('/') {
+        match component {
+            "" | "." => continue,
+            ".." => {
+                components.pop()?;
+            }
+            component => components.push(component),
+        }
+    }
+    Some(components.join("/"))
+}
+
+pub(crate) enum Link {
+    Symbolic(String),
+    Hard(String),
+}
+
+/// Snapshot entries for `files` plus every link in `links` that leads to one of them, keyed by
+/// their path relative to `root`.
+pub(crate) fn resolve_links(
+    root: &str,
+    files: HashMap<String, FileMetadata>,
+    links: &HashMap<String, (Link, FileAttributes)>,
+    verbose: bool,
+) -> Vec<FileMetadata> {
+    let mut entries: Vec<FileMetadata> = vec![];
+    for (path, (link, attributes)) in links {
+        let mut current = (path.clone(), link);
+        let mut resolved = None;
+        for _ in 0..MAX_LINK_DEPTH {
+            let next = match current.1 {
+                Link::Symbolic(target) => resolve_symlink(&current.0, target),
+                Link::Hard(target) => Some(target.clone()),
+            };
+            let Some(next) = next else { break };
+            if let Some(file) = files.get(&next) {
+                resolved = Some(file);
+                break;
+            }
+            match links.get(&next) {
+                Some((link, _)) => current = (next, link),
+                None => break,
+            }
+        }
+        match resolved {
+            Some(file) => entries.push(FileMetadata {
+                path: format!("{root}/{path}"),
+                attributes: Some(attributes.clone()),
+                ..file.clone()
+            }),
+            None if verbose => eprintln!("Skipping (unresolved link): {path}"),
+            None => {}
+        }
+    }
+    entries.extend(files.into_values());
+    entries
+}
+
+/// Hash every member of the tar stream `reader`, naming entries after `root_path` joined with
+/// the member's name. Symbolic and hard links take the digest, size and mtime of the member
+/// they point to, as a directory walk following links would; links that leave the archive or
+/// dangle are skipped, as are directories and special files.
+pub fn tar_entries(
+    reader: impl Read,
+    root_path: &str,
+    hash_type: HashType,
+    strip_components: usize,
+    verbose: bool,
+) -> Result<Vec<FileMetadata>, Error> {
+    let root = root_path.trim_end_matches('/');
+    let mut archive = tar::Archive::new(reader);
+    let mut files: HashMap<String, FileMetadata> = HashMap::new();
+    let mut links: HashMap<String, (Link, FileAttributes)> = HashMap::new();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().to_string();
+        let Some(path) = member_path(&name, strip_components)? else {
+            continue;
+        };
+        let header = entry.header();
+        let mut attributes = FileAttributes {
+            mode: header.mode()? & 0o7777,
+            uid: header.uid()?,
+            gid: header.gid()?,
+            ..Default::default()
+        };
+        let entry_type = header.entry_type();
+        let link_name = entry
+            .link_name()?
+            .map(|link| link.to_string_lossy().to_string());
+        match (entry_type, link_name) {
+            (EntryType::Regular | EntryType::Continuous | EntryType::GNUSparse, _) => {
+                if verbose {
+                    eprintln!("{name}");
+                }
+                let mtime = entry.header().mtime()? as i64;
+                let size = entry.size();
+                let (check_sum, size) = hash_reader_sized(&mut entry, size, hash_type)?;
+                attributes.mode |= S_IFREG;
+                links.remove(&path);
+                files.insert(
+                    path.clone(),
+                    FileMetadata {
+                        path: format!("{root}/{path}"),
+                        check_sum,
+                        size,
+                        mtime,
+                        attributes: Some(attributes),
+                        ..Default::default()
+                    },
+                );
+            }
+            (EntryType::Symlink, Some(target)) => {
+                attributes.mode |= S_IFLNK;
+                attributes.link = Some(target.clone());
+                files.remove(&path);
+                links.insert(path, (Link::Symbolic(target), attributes));
+            }
+            (EntryType::Link, Some(target)) => {
+                attributes.mode |= S_IFREG;
+                attributes.link = Some(target.clone());
+                let Some(target) = member_path(&target, strip_components)? else {
+                    continue;
+                };
+                files.remove(&path);
+                links.insert(path, (Link::Hard(target), attributes));
+            }
+            _ => continue,
+        }
+    }
+
+    Ok(resolve_links(root, files, &links, verbose))
+}
+
+/// Seconds since the epoch of a zip member's modification time, preferring the extended
+/// timestamp field over the DOS time, which has no time zone and is read as UTC.
+fn zip_mtime(file: &zip::read::ZipFile<'_>) -> i64 {
+    for field in file.extra_data_fields() {
+        if let zip::extra_fields::ExtraField::ExtendedTimestamp(timestamp) = field {
+            if let Some(mtime) = timestamp.mod_time() {
+                return mtime as i64;
+            }
+        }
+    }
+    file.last_modified()
+        .and_then(|time| {
+            chrono::NaiveDate::from_ymd_opt(
+                time.year() as i32,
+                time.month() as u32,
+                time.day() as u32,
+            )?
+            .and_hms_opt(
+                time.hour() as u32,
+                time.minute() as u32,
+                time.second() as u32,
+            )
+        })
+        .map(|time| time.and_utc().timestamp())
+        .unwrap_or(0)
+}
+
+/// Hash the decompressed contents of every member of the zip archive `reader`, as
+/// [`tar_entries`] does for tar streams. Zip members carry no owner, so `uid` and `gid` are
+/// left at zero, and members without a unix mode are taken as `0644` files. Stored CRC32s are
+/// checked while reading, so a member that does not match its CRC32 is an error.
+pub fn zip_entries(
+    reader: impl Read + Seek,
+    root_path: &str,
+    hash_type: HashType,
+    options: &ArchiveOptions,
+    verbose: bool,
+) -> Result<Vec<FileMetadata>, Error> {
+    let root = root_path.trim_end_matches('/');
+    let mut archive = zip::ZipArchive::new(reader)?;
+    let mut files: HashMap<String, FileMetadata> = HashMap::new();
+    let mut links: HashMap<String, (Link, FileAttributes)> = HashMap::new();
+
+    for index in 0..archive.len() {
+        let mut file = archive.by_index(index)?;
+        let name = file.name().to_string();
+        if file.is_dir() {
+            continue;
+        }
+        let Some(path) = member_path(&name, options.strip_components)? else {
+            continue;
+        };
+        let mut attributes = FileAttributes {
+            mode: file.unix_mode().unwrap_or(S_IFREG | 0o644),
+            ..Default::default()
+        };
+        if options.zip_details {
+            attributes.crc32 = Some(file.crc32());
+            // the method id as stored in the archive, which the zip crate only hands out here
+            #[allow(deprecated)]
+            let compression = file.compression().to_u16();
+            attributes.compression = Some(compression);
+        }
+        if file.is_symlink() {
+            let mut target = String::new();
+            file.read_to_string(&mut target)?;
+            attributes.link = Some(target.clone());
+            files.remove(&path);
+            links.insert(path, (Link::Symbolic(target), attributes));
+            continue;
+        }
+        if verbose {
+            eprintln!("{name}");
+        }
+        attributes.mode = attributes.mode & 0o7777 | S_IFREG;
+        let mtime = zip_mtime(&file);
+        let size = file.size();
+        let (check_sum, size) =
+            hash_reader_sized(&mut file, size, hash_type).map_err(|e| anyhow!("{name}: {e}"))?;
+        links.remove(&path);
+        files.insert(
+            path.clone(),
+            FileMetadata {
+                path: format!("{root}/{path}"),
+                check_sum,
+                size,
+                mtime,
+                attributes: Some(attributes),
+                ..Default::default()
+            },
+        );
+    }
+    Ok(resolve_links(root, files, &links, verbose))
+}
+
+/// A snapshot of the tar or zip archive at `path` as if it were extracted to `root_path`, so it can be
+/// compared with a [`Snapshot::new`] of that directory by [`crate::snapshot::compare_hashes`].
+pub fn archive_snapshot(
+    path: &Path,
+    root_path: &Path,
+    hash_type: HashType,
+    options: &ArchiveOptions,
+    verbose: bool,
+) -> Result<Snapshot, Error> {
+    let root = root_path
+        .to_str()
+        .ok_or_else(|| anyhow!("cannot parse path"))?;
+    let format = match options.format {
+        Some(format) => format,
+        None => {
+            let mut magic = [0u8; 4];
+            let n = File::open(path)?.read(&mut magic)?;
+            ArchiveFormat::detect(&magic[..n])
+        }
+    };
+    let file = BufReader::new(File::open(path)?);
+    let strip_components = options.strip_components;
+    let entries = match format {
+        ArchiveFormat::Tar => tar_entries(file, root, hash_type, strip_components, verbose),
+        ArchiveFormat::TarGz => {
+            let reader = flate2::read::MultiGzDecoder::new(file);
+            tar_entries(reader, root, hash_type, strip_components, verbose)
+        }
+        ArchiveFormat::TarZst => {
+            let reader = zstd::Decoder::with_buffer(file)?;
+            tar_entries(reader, root, hash_type, strip_components, verbose)
+        }
+        ArchiveFormat::Zip => zip_entries(file, root, hash_type, options, verbose),
+    }
+    .map_err(|e| anyhow!("{}: {e}", path.display()))?;
+    Ok(Snapshot::from_entries(root, hash_type, entries))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::compare_hashes;
+    use std::fs;
+    use std::io::Write;
+
+    fn build_tar() -> Vec<u8> {
+        let mut builder = tar::Builder::new(vec![]);
+        let mut add =
+            |name: &str, entry_type: EntryType, mode: u32, contents: &[u8], link: &str| {
+                let mut header = tar::Header::new_gnu();
+                header.set_entry_type(entry_type);
+                header.set_mode(mode);
+                header.set_uid(1000);
+                header.set_gid(100);
+                header.set_mtime(1700000000);
+                header.set_size(contents.len() as u64);
+                if !link.is_empty() {
+                    header.set_link_name(link).unwrap();
+                }
+                builder.append_data(&mut header, name, contents).unwrap();
+            };
+        add("app-1.0/", EntryType::Directory, 0o755, b"", "");
+        add(
+            "app-1.0/bin/run",
+            EntryType::Regular,
+            0o755,
+            b"#!/bin/sh\n",
+            "",
+        );
+        add(
+            "app-1.0/etc/app.conf",
+            EntryType::Regular,
+            0o644,
+            b"a=1\n",
+            "",
+        );
+        add("app-1.0/bin/start", EntryType::Symlink, 0o777, b"", "run");
+        add(
+            "app-1.0/etc/copy.conf",
+            EntryType::Link,
+            0o644,
+            b"",
+            "app-1.0/etc/app.conf",
+        );
+        add(
+            "app-1.0/etc/os",
+            EntryType::Symlink,
+            0o777,
+            b"",
+            "/etc/os-release",
+        );
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn member_paths() {
+        assert_eq!(member_path("./a/b/c", 1).unwrap(), Some("b/c".to_string()));
+        assert_eq!(member_path("a/", 1).unwrap(), None);
+        assert!(member_path("a/../../x", 0).is_err());
+        assert_eq!(resolve_symlink("a/b/c", "../d"), Some("a/d".to_string()));
+        assert_eq!(resolve_symlink("a", "../../d"), None);
+        assert_eq!(resolve_symlink("a/b", "/etc/passwd"), None);
+    }
+
+    #[test]
+    fn tar_snapshots_match_extracted_trees() {
+        let dir = Path::new("./target/build/test_archive");
+        assert!(!dir.exists());
+        let root = dir.join("app");
+        fs::create_dir_all(root.join("bin")).unwrap();
+        fs::create_dir_all(root.join("etc")).unwrap();
+        fs::write(root.join("bin/run"), "#!/bin/sh\n").unwrap();
+        fs::write(root.join("etc/app.conf"), "a=1\n").unwrap();
+        fs::write(root.join("etc/copy.conf"), "a=1\n").unwrap();
+        std::os::unix::fs::symlink("run", root.join("bin/start")).unwrap();
+
+        let tar = build_tar();
+        fs::write(dir.join("app.tar"), &tar).unwrap();
+        let mut gz = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        gz.write_all(&tar).unwrap();
+        fs::write(dir.join("app.tar.gz"), gz.finish().unwrap()).unwrap();
+        fs::write(
+            dir.join("app.tar.zst"),
+            zstd::encode_all(&tar[..], 3).unwrap(),
+        )
+        .unwrap();
+
+        let options = ArchiveOptions {
+            strip_components: 1,
+            ..Default::default()
+        };
+        let disk = Snapshot::new(&root, HashType::SHA256, vec![], false).unwrap();
+        for name in ["app.tar", "app.tar.gz", "app.tar.zst"] {
+            let archive =
+                archive_snapshot(&dir.join(name), &root, HashType::SHA256, &options, false)
+                    .unwrap();
+            assert_eq!(archive.root_path, disk.root_path);
+            assert_eq!(archive.file_hashes.lock().unwrap().len(), 4);
+            let (_, result) = compare_hashes(archive, disk.clone(), false).unwrap();
+            assert!(result.created.is_empty(), "{name}: {result:?}");
+            assert!(result.deleted.is_empty() && result.changed.is_empty());
+        }
+
+        let archive = archive_snapshot(
+            &dir.join("app.tar.zst"),
+            &root,
+            HashType::MD5,
+            &options,
+            false,
+        )
+        .unwrap();
+        let file_hashes = archive.file_hashes.lock().unwrap();
+        let start = &file_hashes[root.join("bin/start").to_str().unwrap()];
+        assert_eq!(start.check_sum, md5::compute("#!/bin/sh\n").to_vec());
+        let attributes = start.attributes.clone().unwrap();
+        assert_eq!(attributes.mode, 0o120777);
+        assert_eq!(attributes.link.as_deref(), Some("run"));
+        let conf = &file_hashes[root.join("etc/app.conf").to_str().unwrap()];
+        assert_eq!(conf.mtime, 1700000000);
+        assert_eq!(
+            conf.attributes,
+            Some(FileAttributes {
+                mode: 0o100644,
+                uid: 1000,
+                gid: 100,
+                ..Default::default()
+            })
+        );
+        drop(file_hashes);
+
+        fs::write(root.join("etc/app.conf"), "a=2\n").unwrap();
+        let disk = Snapshot::new(&root, HashType::MD5, vec![], false).unwrap();
+        let (_, result) = compare_hashes(archive, disk, false).unwrap();
+        assert_eq!(result.changed.len(), 1);
+        assert!(result.changed[0].ends_with("etc/app.conf"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn zip_snapshots_match_extracted_trees() {
+        let dir = Path::new("./target/build/test_archive_zip");
+        assert!(!dir.exists());
+        let root = dir.join("site-packages");
+        fs::create_dir_all(root.join("pkg")).unwrap();
+        fs::write(root.join("pkg/__init__.py"), "").unwrap();
+        fs::write(root.join("pkg/core.py"), "x = 1\n".repeat(100)).unwrap();
+        std::os::unix::fs::symlink("core.py", root.join("pkg/alias.py")).unwrap();
+
+        let stored = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Stored)
+            .unix_permissions(0o644);
+        let deflated = stored.compression_method(zip::CompressionMethod::Deflated);
+        let mut writer = zip::ZipWriter::new(std::io::Cursor::new(vec![]));
+        writer.add_directory("pkg/", stored).unwrap();
+        writer.start_file("pkg/__init__.py", stored).unwrap();
+        writer.start_file("pkg/core.py", deflated).unwrap();
+        writer.write_all("x = 1\n".repeat(100).as_bytes()).unwrap();
+        writer
+            .add_symlink("pkg/alias.py", "core.py", stored)
+            .unwrap();
+        writer.start_file("pkg/secret.txt", stored).unwrap();
+        writer.write_all(b"original").unwrap();
+        let zip = writer.finish().unwrap().into_inner();
+        fs::write(dir.join("pkg-1.0-py3-none-any.whl"), &zip).unwrap();
+        fs::write(root.join("pkg/secret.txt"), "original").unwrap();
+
+        let options = ArchiveOptions {
+            zip_details: true,
+            ..Default::default()
+        };
+        let wheel = dir.join("pkg-1.0-py3-none-any.whl");
+        let archive = archive_snapshot(&wheel, &root, HashType::SHA256, &options, false).unwrap();
+        let disk = Snapshot::new(&root, HashType::SHA256, vec![], false).unwrap();
+        let (_, result) = compare_hashes(archive.clone(), disk, false).unwrap();
+        assert!(result.created.is_empty() && result.deleted.is_empty());
+        assert!(result.changed.is_empty(), "{result:?}");
+
+        let file_hashes = archive.file_hashes.lock().unwrap();
+        let core = &file_hashes[root.join("pkg/core.py").to_str().unwrap()];
+        let attributes = core.attributes.clone().unwrap();
+        assert_eq!(attributes.mode, 0o100644);
+        assert_eq!(attributes.compression, Some(8));
+        let mut crc = flate2::Crc::new();
+        crc.update("x = 1\n".repeat(100).as_bytes());
+        assert_eq!(attributes.crc32, Some(crc.sum()));
+        let alias = &file_hashes[root.join("pkg/alias.py").to_str().unwrap()];
+        assert_eq!(alias.check_sum, core.check_sum);
+        assert_eq!(
+            alias.attributes.clone().unwrap().link.as_deref(),
+            Some("core.py")
+        );
+        drop(file_hashes);
+
+        // a member whose contents no longer match its CRC32
+        let at = zip.windows(8).position(|w| w == b"original").unwrap();
+        let mut tampered = zip.clone();
+        tampered[at..at + 8].copy_from_slice(b"modified");
+        fs::write(dir.join("tampered.jar"), tampered).unwrap();
+        let options = ArchiveOptions::default();
+        let tampered = dir.join("tampered.jar");
+        assert!(archive_snapshot(&tampered, &root, HashType::SHA256, &options, false).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
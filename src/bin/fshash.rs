@@ -0,0 +1,393 @@
+//! `fshash`, the command-line front end to the library.
+//!
+//! Exit codes: `0` when everything matched, `1` when differences or violations were found and
+//! `2` when the command itself failed.
+
+use anyhow::{anyhow, Error};
+use clap::{Parser, Subcommand, ValueEnum};
+#[cfg(target_os = "linux")]
+use filesystem_hashing::daemon::{Daemon, DaemonConfig};
+use filesystem_hashing::encryption::EncryptionKey;
+use filesystem_hashing::hasher::HashType;
+use filesystem_hashing::mac::MacKey;
+use filesystem_hashing::signing::{load_signing_key, load_verifying_key, SignatureMode};
+use filesystem_hashing::snapshot::{
+    compare_hashes, export_with_options, import_with_options, ExportOptions, FileMetadata,
+    ImportOptions, Snapshot, SnapshotChangeType, DEFAULT_THREADS,
+};
+use filesystem_hashing::verify::{verify, verify_paths, VerifyEvent, VerifyOptions};
+use serde_json::json;
+use std::fs;
+use std::path::Path;
+use std::process::ExitCode;
+#[cfg(target_os = "linux")]
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const EXIT_DIFFERENT: u8 = 1;
+const EXIT_FAILURE: u8 = 2;
+
+/// Set by SIGTERM and SIGINT to stop the daemon after the scan in progress.
+#[cfg(target_os = "linux")]
+static STOP: AtomicBool = AtomicBool::new(false);
+
+#[derive(Parser)]
+#[command(
+    name = "fshash",
+    version,
+    about = "Track filesystem integrity via snapshots"
+)]
+struct Cli {
+    /// Print every file as it is hashed.
+    #[arg(short, long, global = true)]
+    verbose: bool,
+    /// Print JSON (one object per line) instead of text.
+    #[arg(long, global = true)]
+    json: bool,
+    /// Refuse snapshots not signed by this Ed25519 public key (PEM).
+    #[arg(long, global = true, value_name = "PEM")]
+    verifying_key: Option<String>,
+    /// Refuse snapshots without a valid mac for the secret in this file; `snap` macs its
+    /// export with it.
+    #[arg(long, global = true, value_name = "FILE")]
+    mac_key: Option<String>,
+    /// Decrypt encrypted snapshots with the hex encoded key in this file; `snap` encrypts its
+    /// export with it.
+    #[arg(long, global = true, value_name = "FILE")]
+    encryption_key: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Take a snapshot of a directory and export it.
+    Snap {
+        /// Directory to snapshot.
+        root: String,
+        /// File to export the snapshot to.
+        #[arg(short, long)]
+        output: String,
+        #[arg(short = 't', long, value_enum, default_value_t = HashArg::Blake3)]
+        hash_type: HashArg,
+        /// Skip paths starting with this prefix; may be repeated.
+        #[arg(short, long = "blacklist")]
+        blacklist: Vec<String>,
+        /// Files hashed at a time.
+        #[arg(short = 'j', long, default_value_t = DEFAULT_THREADS)]
+        threads: usize,
+        /// Replace the output file if it exists.
+        #[arg(long)]
+        overwrite: bool,
+        /// Sign the export with this Ed25519 private key (PEM).
+        #[arg(long, value_name = "PEM")]
+        signing_key: Option<String>,
+        /// Write the signature to `<output>.sig` instead of into the export.
+        #[arg(long, requires = "signing_key")]
+        detached: bool,
+    },
+    /// Compare two exported snapshots.
+    Compare { left: String, right: String },
+    /// Check the live tree against an exported snapshot.
+    Verify {
+        snapshot: String,
+        /// Only check this file or directory; may be repeated.
+        #[arg(short, long = "path")]
+        paths: Vec<String>,
+        /// Stop at the first violation.
+        #[arg(long)]
+        fail_fast: bool,
+        /// Also report changed size, ctime or mtime.
+        #[arg(long)]
+        check_metadata: bool,
+        /// Skip paths starting with this prefix; may be repeated.
+        #[arg(short, long = "blacklist")]
+        blacklist: Vec<String>,
+    },
+    /// List the entries of an exported snapshot.
+    Show { snapshot: String },
+    /// Summarize an exported snapshot.
+    Stats { snapshot: String },
+    /// Scan the roots of a config file on their schedules and report to its sinks.
+    #[cfg(target_os = "linux")]
+    Daemon {
+        config: String,
+        /// Scan every root once and exit.
+        #[arg(long)]
+        once: bool,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum HashArg {
+    Md5,
+    Sha3,
+    Blake3,
+    Sha256,
+    /// Git blob ids, as `git hash-object` prints them.
+    GitSha1,
+}
+
+impl From<HashArg> for HashType {
+    fn from(value: HashArg) -> Self {
+        match value {
+            HashArg::Md5 => HashType::MD5,
+            HashArg::Sha3 => HashType::SHA3,
+            HashArg::Blake3 => HashType::BLAKE3,
+            HashArg::Sha256 => HashType::SHA256,
+            HashArg::GitSha1 => HashType::GITSHA1,
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match run(&cli) {
+        Ok(code) => ExitCode::from(code),
+        Err(e) => {
+            eprintln!("fshash: {e:#}");
+            ExitCode::from(EXIT_FAILURE)
+        }
+    }
+}
+
+fn run(cli: &Cli) -> Result<u8, Error> {
+    let import_options = import_options(cli)?;
+    match &cli.command {
+        Command::Snap {
+            root,
+            output,
+            hash_type,
+            blacklist,
+            threads,
+            overwrite,
+            signing_key,
+            detached,
+        } => {
+            let export_options = ExportOptions {
+                overwrite: *overwrite,
+                signing_key: match signing_key {
+                    Some(path) => Some(load_signing_key(Path::new(path))?),
+                    None => None,
+                },
+                signature_mode: match detached {
+                    true => SignatureMode::Detached,
+                    false => SignatureMode::Embedded,
+                },
+                mac_key: import_options.mac_key.clone(),
+                encryption_key: import_options.encryption_key.clone(),
+                ..Default::default()
+            };
+            let snapshot = Snapshot::with_threads(
+                Path::new(root),
+                (*hash_type).into(),
+                blacklist.clone(),
+                *threads,
+                cli.verbose,
+            )?;
+            export_with_options(
+                snapshot.clone(),
+                output.clone(),
+                &export_options,
+                cli.verbose,
+            )?;
+            print_stats(&snapshot, cli.json)?;
+            Ok(0)
+        }
+        Command::Compare { left, right } => {
+            let left = load(left, &import_options, cli.verbose)?;
+            let right = load(right, &import_options, cli.verbose)?;
+            let (change_type, result) = compare_hashes(left, right, cli.verbose)
+                .ok_or_else(|| anyhow!("unable to compare snapshots"))?;
+            if cli.json {
+                println!("{}", serde_json::to_string(&result)?);
+            } else {
+                for (label, paths) in [
+                    ("created", &result.created),
+                    ("deleted", &result.deleted),
+                    ("changed", &result.changed),
+                ] {
+                    for path in paths {
+                        println!("{label}\t{path}");
+                    }
+                }
+            }
+            Ok(match change_type {
+                SnapshotChangeType::None => 0,
+                _ => EXIT_DIFFERENT,
+            })
+        }
+        Command::Verify {
+            snapshot,
+            paths,
+            fail_fast,
+            check_metadata,
+            blacklist,
+        } => {
+            let baseline = load(snapshot, &import_options, cli.verbose)?;
+            let options = VerifyOptions {
+                fail_fast: *fail_fast,
+                check_metadata: *check_metadata,
+                black_list: blacklist.clone(),
+            };
+            let json = cli.json;
+            let on_event = |event: &VerifyEvent| print_event(event, json);
+            let paths: Vec<&str> = paths.iter().map(String::as_str).collect();
+            let report = if paths.is_empty() {
+                verify(&baseline, &options, cli.verbose, on_event)?
+            } else {
+                verify_paths(&baseline, &paths, &options, cli.verbose, on_event)?
+            };
+            if cli.json {
+                println!("{}", json!({ "kind": "summary", "report": report }));
+            } else {
+                eprintln!(
+                    "checked {}, changed {}, created {}, deleted {}, not in baseline {}, errors {}",
+                    report.checked,
+                    report.changed,
+                    report.created,
+                    report.deleted,
+                    report.not_in_baseline,
+                    report.errors
+                );
+            }
+            Ok(if !report.is_clean() {
+                EXIT_DIFFERENT
+            } else if report.errors > 0 {
+                EXIT_FAILURE
+            } else {
+                0
+            })
+        }
+        Command::Show { snapshot } => {
+            let snapshot = load(snapshot, &import_options, cli.verbose)?;
+            let file_hashes = snapshot
+                .file_hashes
+                .lock()
+                .map_err(|_| anyhow!("unable to lock snapshot"))?;
+            let mut entries: Vec<&FileMetadata> = file_hashes.values().collect();
+            entries.sort_by(|a, b| a.path.cmp(&b.path));
+            for entry in entries {
+                if cli.json {
+                    println!(
+                        "{}",
+                        json!({
+                            "path": entry.path,
+                            "check_sum": hex::encode(&entry.check_sum),
+                            "size": entry.size,
+                            "mtime": entry.mtime,
+                        })
+                    );
+                } else {
+                    println!(
+                        "{}  {:>10}  {}",
+                        hex::encode(&entry.check_sum),
+                        entry.size,
+                        entry.path
+                    );
+                }
+            }
+            Ok(0)
+        }
+        Command::Stats { snapshot } => {
+            print_stats(&load(snapshot, &import_options, cli.verbose)?, cli.json)?;
+            Ok(0)
+        }
+        #[cfg(target_os = "linux")]
+        Command::Daemon { config, once } => {
+            let config = DaemonConfig::from_file(Path::new(config))?;
+            let mut daemon = Daemon::new(config, cli.verbose)?;
+            if *once {
+                let reports = daemon.run_once()?;
+                return Ok(if reports.iter().any(|r| r.has_changes()) {
+                    EXIT_DIFFERENT
+                } else {
+                    0
+                });
+            }
+            let handler: extern "C" fn(libc::c_int) = request_stop;
+            for signal in [libc::SIGTERM, libc::SIGINT] {
+                unsafe { libc::signal(signal, handler as libc::sighandler_t) };
+            }
+            daemon.run(&STOP)?;
+            Ok(0)
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+extern "C" fn request_stop(_signal: libc::c_int) {
+    STOP.store(true, Ordering::Relaxed);
+}
+
+fn import_options(cli: &Cli) -> Result<ImportOptions, Error> {
+    Ok(ImportOptions {
+        verifying_key: match &cli.verifying_key {
+            Some(path) => Some(load_verifying_key(Path::new(path))?),
+            None => None,
+        },
+        mac_key: match &cli.mac_key {
+            Some(path) => Some(MacKey::from_file(Path::new(path))?),
+            None => None,
+        },
+        encryption_key: match &cli.encryption_key {
+            Some(path) => Some(EncryptionKey::from_hex(&fs::read_to_string(path)?)?),
+            None => None,
+        },
+    })
+}
+
+/// Import a snapshot, failing when the file does not exist rather than returning an empty one.
+fn load(path: &str, options: &ImportOptions, verbose: bool) -> Result<Snapshot, Error> {
+    if !Path::new(path).exists() {
+        return Err(anyhow!("no such snapshot: {path}"));
+    }
+    import_with_options(path.to_string(), options, verbose)
+}
+
+fn print_stats(snapshot: &Snapshot, json: bool) -> Result<(), Error> {
+    let file_hashes = snapshot
+        .file_hashes
+        .lock()
+        .map_err(|_| anyhow!("unable to lock snapshot"))?;
+    let files = file_hashes.len();
+    let bytes: u64 = file_hashes.values().map(|entry| entry.size).sum();
+    if json {
+        println!(
+            "{}",
+            json!({
+                "root_path": snapshot.root_path,
+                "uuid": snapshot.uuid,
+                "date_created": snapshot.date_created,
+                "hash_type": snapshot.hash_type,
+                "files": files,
+                "bytes": bytes,
+                "merkle_root": snapshot.merkle_root,
+            })
+        );
+    } else {
+        println!("root_path:    {}", snapshot.root_path);
+        println!("uuid:         {}", snapshot.uuid);
+        println!("date_created: {}", snapshot.date_created);
+        println!("hash_type:    {:?}", snapshot.hash_type);
+        println!("files:        {files}");
+        println!("bytes:        {bytes}");
+        println!("merkle_root:  {}", snapshot.merkle_root);
+    }
+    Ok(())
+}
+
+fn print_event(event: &VerifyEvent, json: bool) {
+    if json {
+        if let Ok(line) = serde_json::to_string(event) {
+            println!("{line}");
+        }
+        return;
+    }
+    match event {
+        VerifyEvent::Created { path, .. } => println!("created\t{path}"),
+        VerifyEvent::Deleted { path, .. } => println!("deleted\t{path}"),
+        VerifyEvent::Changed { path, .. } => println!("changed\t{path}"),
+        VerifyEvent::NotInBaseline { path, .. } => println!("not in baseline\t{path}"),
+        VerifyEvent::Error { path, message } => eprintln!("error\t{path}: {message}"),
+    }
+}
@@ -0,0 +1,504 @@
+use crate::snapshot::{
+    change_records, ChangeRecord, FileMetadata, Snapshot, SnapshotCompareResult,
+};
+use crate::verify::{blacklisted, under, VerifyEvent, VerifyOptions};
+use anyhow::{anyhow, Error};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::str::FromStr;
+
+const SARIF_SCHEMA: &str = "https://json.schemastore.org/sarif-2.1.0.json";
+const ROOT_BASE_ID: &str = "ROOT";
+
+/// Output formats understood by CI systems.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CiFormat {
+    Sarif,
+    Junit,
+}
+
+impl FromStr for CiFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "sarif" => Ok(CiFormat::Sarif),
+            "junit" => Ok(CiFormat::Junit),
+            _ => Err(anyhow!("unknown ci format: {s}")),
+        }
+    }
+}
+
+/// The outcome of a compare or verify run: every monitored path plus what went wrong with some
+/// of them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CiResults {
+    pub root_path: String,
+    pub paths: BTreeSet<String>,
+    pub changes: Vec<ChangeRecord>,
+    /// `(path, message)` for files that could not be read.
+    pub errors: Vec<(String, String)>,
+}
+
+impl CiResults {
+    /// Every path in either snapshot is monitored.
+    pub fn from_compare(
+        result: &SnapshotCompareResult,
+        left: &Snapshot,
+        right: &Snapshot,
+    ) -> Result<CiResults, Error> {
+        let mut paths = BTreeSet::new();
+        for snapshot in [left, right] {
+            let file_hashes = snapshot
+                .file_hashes
+                .lock()
+                .map_err(|_| anyhow!("unable to lock snapshot"))?;
+            paths.extend(file_hashes.keys().cloned());
+        }
+        Ok(CiResults {
+            root_path: right.root_path.clone(),
+            paths,
+            changes: change_records(result, left, right)?,
+            errors: vec![],
+        })
+    }
+
+    /// The baseline entries below `paths` (all of them when `paths` is empty) are monitored,
+    /// along with any path an event was reported for.
+    pub fn from_verify(
+        baseline: &Snapshot,
+        paths: &[&str],
+        options: &VerifyOptions,
+        events: &[VerifyEvent],
+    ) -> Result<CiResults, Error> {
+        let file_hashes = baseline
+            .file_hashes
+            .lock()
+            .map_err(|_| anyhow!("unable to lock snapshot"))?;
+        let mut monitored: BTreeSet<String> = file_hashes
+            .keys()
+            .filter(|path| paths.is_empty() || paths.iter().any(|prefix| under(path, prefix)))
+            .filter(|path| !blacklisted(path, baseline, options))
+            .cloned()
+            .collect();
+        drop(file_hashes);
+
+        let digest = |entry: &FileMetadata| hex::encode(&entry.check_sum);
+        let mut changes = vec![];
+        let mut errors = vec![];
+        for event in events {
+            monitored.insert(event.path().to_string());
+            let (change, old_digest, new_digest) = match event {
+                VerifyEvent::Created { actual, .. } => ("created", None, Some(digest(actual))),
+                VerifyEvent::Deleted { expected, .. } => ("deleted", Some(digest(expected)), None),
+                VerifyEvent::Changed {
+                    expected, actual, ..
+                } => ("changed", Some(digest(expected)), Some(digest(actual))),
+                VerifyEvent::NotInBaseline { actual, .. } => {
+                    ("not_in_baseline", None, actual.as_ref().map(digest))
+                }
+                VerifyEvent::Error { path, message } => {
+                    errors.push((path.clone(), message.clone()));
+                    continue;
+                }
+            };
+            changes.push(ChangeRecord {
+                path: event.path().to_string(),
+                change,
+                old_digest,
+                new_digest,
+            });
+        }
+        Ok(CiResults {
+            root_path: baseline.root_path.clone(),
+            paths: monitored,
+            changes,
+            errors,
+        })
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.changes.is_empty() && self.errors.is_empty()
+    }
+}
+
+pub fn render(results: &CiResults, format: CiFormat) -> Result<String, Error> {
+    match format {
+        CiFormat::Sarif => sarif(results),
+        CiFormat::Junit => Ok(junit(results)),
+    }
+}
+
+/// (rule id, level, description) for each kind of change.
+const RULES: [(&str, &str, &str); 5] = [
+    ("created", "warning", "File is not in the baseline"),
+    ("deleted", "error", "File from the baseline is missing"),
+    ("changed", "error", "File differs from the baseline"),
+    (
+        "not_in_baseline",
+        "warning",
+        "Requested path has no baseline entry",
+    ),
+    ("read_error", "error", "File could not be read"),
+];
+
+fn describe(record: &ChangeRecord) -> String {
+    match (record.change, &record.old_digest, &record.new_digest) {
+        ("changed", Some(old), Some(new)) if old == new => {
+            format!("{} metadata changed", record.path)
+        }
+        ("changed", Some(old), Some(new)) => {
+            format!("{} changed: expected {old}, found {new}", record.path)
+        }
+        ("deleted", Some(old), _) => format!("{} is missing, expected {old}", record.path),
+        ("created", _, Some(new)) => format!("{} is not in the baseline: {new}", record.path),
+        ("not_in_baseline", _, None) => {
+            format!("{} is neither in the baseline nor on disk", record.path)
+        }
+        _ => format!("{} {}", record.path, record.change.replace('_', " ")),
+    }
+}
+
+/// A SARIF 2.1.0 log with one run and one result per change or read error. Locations are given
+/// relative to the snapshot root.
+pub fn sarif(results: &CiResults) -> Result<String, Error> {
+    let rules: Vec<Value> = RULES
+        .iter()
+        .map(|(id, level, text)| {
+            json!({
+                "id": id,
+                "shortDescription": { "text": text },
+                "defaultConfiguration": { "level": level },
+            })
+        })
+        .collect();
+    let result = |rule: &str, path: &str, text: String, fingerprint: Option<&String>| {
+        let index = RULES.iter().position(|(id, ..)| *id == rule).unwrap_or(0);
+        let mut result = json!({
+            "ruleId": rule,
+            "ruleIndex": index,
+            "level": RULES[index].1,
+            "message": { "text": text },
+            "locations": [{
+                "physicalLocation": {
+                    "artifactLocation": {
+                        "uri": relative_uri(&results.root_path, path),
+                        "uriBaseId": ROOT_BASE_ID,
+                    }
+                }
+            }],
+        });
+        if let Some(fingerprint) = fingerprint {
+            result["partialFingerprints"] = json!({ "fileDigest/v1": fingerprint });
+        }
+        result
+    };
+
+    let mut sarif_results = vec![];
+    for record in &results.changes {
+        let fingerprint = record.new_digest.as_ref().or(record.old_digest.as_ref());
+        sarif_results.push(result(
+            record.change,
+            &record.path,
+            describe(record),
+            fingerprint,
+        ));
+    }
+    for (path, message) in &results.errors {
+        sarif_results.push(result(
+            "read_error",
+            path,
+            format!("{path}: {message}"),
+            None,
+        ));
+    }
+
+    let log = json!({
+        "$schema": SARIF_SCHEMA,
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": "fshash",
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "informationUri": env!("CARGO_PKG_REPOSITORY"),
+                    "rules": rules,
+                }
+            },
+            "originalUriBaseIds": {
+                ROOT_BASE_ID: { "uri": root_uri(&results.root_path) }
+            },
+            "results": sarif_results,
+        }]
+    });
+    Ok(serde_json::to_string_pretty(&log)?)
+}
+
+/// A JUnit XML report with one test case per monitored path, grouped into one test suite per
+/// parent directory. Changes are failures and read errors are errors.
+pub fn junit(results: &CiResults) -> String {
+    let changes: BTreeMap<&str, &ChangeRecord> = results
+        .changes
+        .iter()
+        .map(|record| (record.path.as_str(), record))
+        .collect();
+    let errors: BTreeMap<&str, &str> = results
+        .errors
+        .iter()
+        .map(|(path, message)| (path.as_str(), message.as_str()))
+        .collect();
+    let mut suites: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+    for path in &results.paths {
+        let dir = match path.rfind('/') {
+            Some(0) => "/".to_string(),
+            Some(index) => path[..index].to_string(),
+            None => String::new(),
+        };
+        suites.entry(dir).or_default().push(path);
+    }
+
+    let mut out = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <testsuites name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\">\n",
+        escape_xml(&results.root_path),
+        results.paths.len(),
+        changes.len(),
+        errors.len()
+    );
+    for (dir, paths) in suites {
+        let failures = paths
+            .iter()
+            .filter(|path| changes.contains_key(*path))
+            .count();
+        let suite_errors = paths
+            .iter()
+            .filter(|path| errors.contains_key(*path))
+            .count();
+        let _ = writeln!(
+            out,
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{failures}\" errors=\"{suite_errors}\">",
+            escape_xml(&dir),
+            paths.len()
+        );
+        for path in paths {
+            let name = escape_xml(path);
+            let change = changes.get(path);
+            let error = errors.get(path);
+            if change.is_none() && error.is_none() {
+                let _ = writeln!(
+                    out,
+                    "    <testcase classname=\"{}\" name=\"{name}\"/>",
+                    escape_xml(&dir)
+                );
+                continue;
+            }
+            let _ = writeln!(
+                out,
+                "    <testcase classname=\"{}\" name=\"{name}\">",
+                escape_xml(&dir)
+            );
+            if let Some(record) = change {
+                let _ = writeln!(
+                    out,
+                    "      <failure type=\"{}\" message=\"{}\"/>",
+                    record.change,
+                    escape_xml(&describe(record))
+                );
+            }
+            if let Some(message) = error {
+                let _ = writeln!(
+                    out,
+                    "      <error type=\"read_error\" message=\"{}\"/>",
+                    escape_xml(message)
+                );
+            }
+            out.push_str("    </testcase>\n");
+        }
+        out.push_str("  </testsuite>\n");
+    }
+    out.push_str("</testsuites>\n");
+    out
+}
+
+/// Escape text for an XML attribute. Characters XML 1.0 cannot represent at all are replaced
+/// with U+FFFD.
+pub fn escape_xml(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' => escaped.push_str("&#9;"),
+            '\n' => escaped.push_str("&#10;"),
+            '\r' => escaped.push_str("&#13;"),
+            c if (c as u32) < 0x20 || c == '\u{fffe}' || c == '\u{ffff}' => {
+                escaped.push('\u{fffd}')
+            }
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// `file://` uri of the directory `root`, made absolute against the current directory when it is
+/// relative. A relative reference without a scheme is left when that fails.
+fn root_uri(root: &str) -> String {
+    match std::path::absolute(root) {
+        Ok(absolute) => format!(
+            "file://{}/",
+            encode_uri(absolute.to_string_lossy().trim_end_matches('/'))
+        ),
+        Err(_) => format!("{}/", encode_uri(root.trim_end_matches('/'))),
+    }
+}
+
+fn relative_uri(root: &str, path: &str) -> String {
+    let root = root.trim_end_matches('/');
+    let relative = match path.strip_prefix(root) {
+        Some(rest) if rest.starts_with('/') => &rest[1..],
+        _ => path.trim_start_matches('/'),
+    };
+    encode_uri(relative)
+}
+
+/// Percent-encode everything but unreserved characters and `/`.
+fn encode_uri(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => {
+                let _ = write!(encoded, "%{byte:02X}");
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::test_snapshot;
+
+    #[test]
+    fn compare_results() {
+        let left = test_snapshot(
+            "/srv/build",
+            "",
+            0,
+            &[
+                ("/srv/build/app", 1),
+                ("/srv/build/lib/a.so", 2),
+                ("/srv/build/lib/b.so", 3),
+            ],
+        );
+        let right = test_snapshot(
+            "/srv/build",
+            "",
+            0,
+            &[
+                ("/srv/build/app", 9),
+                ("/srv/build/lib/a.so", 2),
+                ("/srv/build/my <file>", 4),
+            ],
+        );
+        let result = SnapshotCompareResult {
+            created: vec!["/srv/build/my <file>".to_string()],
+            deleted: vec!["/srv/build/lib/b.so".to_string()],
+            changed: vec!["/srv/build/app".to_string()],
+        };
+        let results = CiResults::from_compare(&result, &left, &right).unwrap();
+        assert_eq!(results.paths.len(), 4);
+
+        let sarif: Value = serde_json::from_str(&sarif(&results).unwrap()).unwrap();
+        let run = &sarif["runs"][0];
+        assert_eq!(sarif["version"], "2.1.0");
+        assert_eq!(
+            run["originalUriBaseIds"]["ROOT"]["uri"],
+            "file:///srv/build/"
+        );
+        let cwd = std::env::current_dir().unwrap();
+        assert_eq!(
+            root_uri("./target/my build/"),
+            format!(
+                "file://{}/",
+                encode_uri(&cwd.join("target/my build").to_string_lossy())
+            )
+        );
+        assert_eq!(root_uri("/"), "file:///");
+        let sarif_results = run["results"].as_array().unwrap();
+        assert_eq!(sarif_results.len(), 3);
+        assert_eq!(sarif_results[0]["ruleId"], "created");
+        assert_eq!(sarif_results[0]["level"], "warning");
+        assert_eq!(
+            sarif_results[0]["locations"][0]["physicalLocation"]["artifactLocation"]["uri"],
+            "my%20%3Cfile%3E"
+        );
+        assert_eq!(sarif_results[1]["level"], "error");
+        assert_eq!(
+            sarif_results[2]["message"]["text"],
+            "/srv/build/app changed: expected 01, found 09"
+        );
+        let index = sarif_results[2]["ruleIndex"].as_u64().unwrap() as usize;
+        assert_eq!(run["tool"]["driver"]["rules"][index]["id"], "changed");
+
+        let junit = junit(&results);
+        assert!(junit
+            .contains("<testsuites name=\"/srv/build\" tests=\"4\" failures=\"3\" errors=\"0\">"));
+        assert!(junit.contains(
+            "<testsuite name=\"/srv/build/lib\" tests=\"2\" failures=\"1\" errors=\"0\">"
+        ));
+        assert!(
+            junit.contains("<testcase classname=\"/srv/build/lib\" name=\"/srv/build/lib/a.so\"/>")
+        );
+        assert!(junit.contains("name=\"/srv/build/my &lt;file&gt;\">"));
+        assert!(junit.contains(
+            "<failure type=\"deleted\" message=\"/srv/build/lib/b.so is missing, expected 03\"/>"
+        ));
+    }
+
+    #[test]
+    fn verify_results() {
+        let baseline = test_snapshot(
+            "/srv/build",
+            "",
+            0,
+            &[("/srv/build/app", 1), ("/srv/build/lib/a.so", 2)],
+        );
+        let events = vec![
+            VerifyEvent::Changed {
+                path: "/srv/build/app".to_string(),
+                expected: FileMetadata {
+                    check_sum: vec![1],
+                    ..Default::default()
+                },
+                actual: FileMetadata {
+                    check_sum: vec![1],
+                    mtime: 5,
+                    ..Default::default()
+                },
+            },
+            VerifyEvent::Error {
+                path: "/srv/build/app/x".to_string(),
+                message: "permission denied".to_string(),
+            },
+        ];
+        let options = VerifyOptions::default();
+        let results =
+            CiResults::from_verify(&baseline, &["/srv/build/app"], &options, &events).unwrap();
+        assert_eq!(results.paths.len(), 2);
+        assert!(!results.is_clean());
+
+        let junit = render(&results, CiFormat::Junit).unwrap();
+        assert!(junit.contains("tests=\"2\" failures=\"1\" errors=\"1\""));
+        assert!(junit.contains("message=\"/srv/build/app metadata changed\""));
+        assert!(junit.contains("<error type=\"read_error\" message=\"permission denied\"/>"));
+        assert_eq!(escape_xml("a\u{1}'b"), "a\u{fffd}&apos;b");
+    }
+}
@@ -0,0 +1,489 @@
+use crate::hasher::HashType;
+use crate::snapshot::{
+    change_records, compare_hashes, ChangeRecord, Snapshot, SnapshotCompareResult,
+};
+use crate::store::SnapshotStore;
+use crate::syslog::{
+    journald_entries, send_datagrams, syslog_messages, SyslogOptions, JOURNALD_SOCKET,
+};
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Daemon configuration, usually read from a TOML file:
+///
+/// ```toml
+/// state_dir = "/var/lib/fshash"
+///
+/// [[root]]
+/// path = "/etc"
+/// hash_type = "BLAKE3"
+/// black_list = ["/etc/mtab"]
+/// interval = 3600
+///
+/// [[sink]]
+/// type = "syslog"
+/// changes_only = true
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+pub struct DaemonConfig {
+    /// Directory holding the last snapshot of each root.
+    pub state_dir: PathBuf,
+    /// Lock held while scanning, `<state_dir>/fshash.lock` when not set.
+    #[serde(default)]
+    pub lock_file: Option<PathBuf>,
+    #[serde(default, rename = "root")]
+    pub roots: Vec<RootConfig>,
+    #[serde(default, rename = "sink")]
+    pub sinks: Vec<SinkConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RootConfig {
+    pub path: String,
+    #[serde(default = "default_hash_type")]
+    pub hash_type: HashType,
+    #[serde(default)]
+    pub black_list: Vec<String>,
+    /// Seconds between scans.
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SinkConfig {
+    #[serde(flatten)]
+    pub kind: SinkKind,
+    /// Only send reports of scans that found differences.
+    #[serde(default)]
+    pub changes_only: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SinkKind {
+    /// One JSON object per line on stdout.
+    Stdout,
+    /// One JSON object per line appended to `path`.
+    File { path: PathBuf },
+    /// RFC 5424 messages, one per change and a summary, sent to a local datagram socket.
+    Syslog {
+        #[serde(default = "default_syslog_socket")]
+        socket: PathBuf,
+    },
+    /// journald entries with `FSHASH_*` fields, one per change and a summary.
+    Journald {
+        #[serde(default = "default_journald_socket")]
+        socket: PathBuf,
+    },
+    /// An HTTP POST of the JSON report. Only `http://` URLs on a loopback address are allowed.
+    Webhook { url: String },
+}
+
+fn default_hash_type() -> HashType {
+    HashType::BLAKE3
+}
+
+fn default_interval() -> u64 {
+    3600
+}
+
+fn default_syslog_socket() -> PathBuf {
+    PathBuf::from("/dev/log")
+}
+
+fn default_journald_socket() -> PathBuf {
+    PathBuf::from(JOURNALD_SOCKET)
+}
+
+impl DaemonConfig {
+    pub fn from_file(path: &Path) -> Result<DaemonConfig, Error> {
+        let config: DaemonConfig = toml::from_str(&fs::read_to_string(path)?)?;
+        if config.roots.is_empty() {
+            return Err(anyhow!("no roots configured in {:?}", path));
+        }
+        Ok(config)
+    }
+}
+
+/// The outcome of one scan of one root, as sent to the sinks.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ScanReport {
+    pub root_path: String,
+    pub uuid: String,
+    pub date_created: i64,
+    /// The snapshot this scan was compared with, `None` on the first scan of a root.
+    pub previous_uuid: Option<String>,
+    pub files: usize,
+    pub created: Vec<String>,
+    pub deleted: Vec<String>,
+    pub changed: Vec<String>,
+}
+
+impl ScanReport {
+    pub fn has_changes(&self) -> bool {
+        !(self.created.is_empty() && self.deleted.is_empty() && self.changed.is_empty())
+    }
+
+    pub fn compare_result(&self) -> SnapshotCompareResult {
+        SnapshotCompareResult {
+            created: self.created.clone(),
+            deleted: self.deleted.clone(),
+            changed: self.changed.clone(),
+        }
+    }
+}
+
+/// An exclusive `flock` on a file, released when dropped or when the process exits.
+#[derive(Debug)]
+pub struct ScanLock {
+    file: File,
+}
+
+impl ScanLock {
+    /// Take the lock, failing straight away when another process holds it.
+    pub fn acquire(path: &Path) -> Result<ScanLock, Error> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(path)?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::WouldBlock {
+                return Err(anyhow!("another scan holds {:?}", path));
+            }
+            return Err(e.into());
+        }
+        file.set_len(0)?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(ScanLock { file })
+    }
+}
+
+impl Drop for ScanLock {
+    fn drop(&mut self) {
+        unsafe {
+            libc::flock(self.file.as_raw_fd(), libc::LOCK_UN);
+        }
+    }
+}
+
+/// Scans the configured roots on their schedules, compares each scan with the previous one and
+/// sends the results to the configured sinks. Only the last snapshot of each root is kept.
+pub struct Daemon {
+    config: DaemonConfig,
+    store: SnapshotStore,
+    next_scan: Vec<Instant>,
+    verbose: bool,
+}
+
+impl Daemon {
+    pub fn new(config: DaemonConfig, verbose: bool) -> Result<Daemon, Error> {
+        let store = SnapshotStore::open(&config.state_dir)?;
+        let next_scan = vec![Instant::now(); config.roots.len()];
+        Ok(Daemon {
+            config,
+            store,
+            next_scan,
+            verbose,
+        })
+    }
+
+    fn lock_path(&self) -> PathBuf {
+        self.config
+            .lock_file
+            .clone()
+            .unwrap_or_else(|| self.config.state_dir.join("fshash.lock"))
+    }
+
+    /// Scan one root now, store the snapshot and send the report to the sinks.
+    pub fn scan(&self, root: &RootConfig) -> Result<ScanReport, Error> {
+        let _lock = ScanLock::acquire(&self.lock_path())?;
+        let snapshot = Snapshot::new(
+            Path::new(&root.path),
+            root.hash_type,
+            root.black_list.clone(),
+            self.verbose,
+        )?;
+        let previous = self.store.latest(&snapshot.root_path, self.verbose)?;
+
+        let mut report = ScanReport {
+            root_path: snapshot.root_path.clone(),
+            uuid: snapshot.uuid.clone(),
+            date_created: snapshot.date_created,
+            previous_uuid: None,
+            files: snapshot
+                .file_hashes
+                .lock()
+                .map_err(|_| anyhow!("unable to lock snapshot"))?
+                .len(),
+            created: vec![],
+            deleted: vec![],
+            changed: vec![],
+        };
+        let mut records = vec![];
+        if let Some(previous) = previous {
+            report.previous_uuid = Some(previous.uuid.clone());
+            if let Some((_, result)) =
+                compare_hashes(previous.clone(), snapshot.clone(), self.verbose)
+            {
+                records = change_records(&result, &previous, &snapshot)?;
+                report.created = result.created;
+                report.deleted = result.deleted;
+                report.changed = result.changed;
+            }
+        }
+        for list in [
+            &mut report.created,
+            &mut report.deleted,
+            &mut report.changed,
+        ] {
+            list.sort();
+        }
+
+        // scans in the same second share a date, so keep the new snapshot by uuid rather than
+        // by retention order
+        self.store.save(&snapshot, self.verbose)?;
+        for record in self.store.list(&snapshot.root_path)? {
+            if record.uuid != snapshot.uuid {
+                self.store.delete(&record)?;
+            }
+        }
+
+        for sink in self.config.sinks.iter() {
+            if sink.changes_only && !report.has_changes() {
+                continue;
+            }
+            if let Err(e) = send(&sink.kind, &report, &records) {
+                eprintln!("Warning: {:?} sink failed: {e}", sink.kind);
+            }
+        }
+        Ok(report)
+    }
+
+    /// Scan every root once, regardless of schedule.
+    pub fn run_once(&mut self) -> Result<Vec<ScanReport>, Error> {
+        let mut reports = vec![];
+        for (i, root) in self.config.roots.iter().enumerate() {
+            reports.push(self.scan(root)?);
+            self.next_scan[i] = Instant::now() + Duration::from_secs(root.interval);
+        }
+        Ok(reports)
+    }
+
+    /// Scan each root whenever its interval has passed, starting with all of them, until `stop`
+    /// is set. A scan that finds another one running is retried on the next interval.
+    pub fn run(&mut self, stop: &AtomicBool) -> Result<(), Error> {
+        while !stop.load(Ordering::Relaxed) {
+            let now = Instant::now();
+            for i in 0..self.config.roots.len() {
+                if self.next_scan[i] > now {
+                    continue;
+                }
+                let root = self.config.roots[i].clone();
+                if let Err(e) = self.scan(&root) {
+                    eprintln!("Warning: scan of {} failed: {e}", root.path);
+                }
+                self.next_scan[i] = Instant::now() + Duration::from_secs(root.interval);
+            }
+            let next = self.next_scan.iter().min().copied().unwrap_or(now);
+            let wait = next.saturating_duration_since(Instant::now());
+            thread::sleep(wait.min(Duration::from_secs(1)));
+        }
+        Ok(())
+    }
+}
+
+fn send(sink: &SinkKind, report: &ScanReport, records: &[ChangeRecord]) -> Result<(), Error> {
+    let json = serde_json::to_string(report)?;
+    match sink {
+        SinkKind::Stdout => println!("{json}"),
+        SinkKind::File { path } => {
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(file, "{json}")?;
+        }
+        SinkKind::Syslog { socket } => {
+            let messages = syslog_messages(
+                &report.root_path,
+                &report.compare_result(),
+                records,
+                &SyslogOptions::default(),
+            );
+            send_datagrams(socket, &messages)?;
+        }
+        SinkKind::Journald { socket } => {
+            let entries = journald_entries(
+                &report.root_path,
+                &report.compare_result(),
+                records,
+                &SyslogOptions::default(),
+            );
+            send_datagrams(socket, &entries)?;
+        }
+        SinkKind::Webhook { url } => post_json(url, &json)?,
+    }
+    Ok(())
+}
+
+/// POST `body` to a loopback `http://` URL and check for a 2xx status.
+fn post_json(url: &str, body: &str) -> Result<(), Error> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| anyhow!("webhook url must start with http://: {url}"))?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let address = authority
+        .to_socket_addrs()
+        .or_else(|_| format!("{authority}:80").to_socket_addrs())?
+        .next()
+        .ok_or_else(|| anyhow!("cannot resolve {authority}"))?;
+    if !address.ip().is_loopback() {
+        return Err(anyhow!("webhook must be a local endpoint: {url}"));
+    }
+
+    let mut stream = TcpStream::connect_timeout(&address, Duration::from_secs(5))?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let request = format!(
+        "POST {path} HTTP/1.1\r\nHost: {authority}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(request.as_bytes())?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let status = response
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse::<u16>().ok())
+        .ok_or_else(|| anyhow!("invalid response from {url}"))?;
+    if !(200..300).contains(&status) {
+        return Err(anyhow!("{url} returned {status}"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::os::unix::net::UnixDatagram;
+
+    #[test]
+    fn scans_compare_and_dispatch() {
+        assert!(!Path::new("./target/build/test_daemon/").exists());
+        fs::create_dir_all(Path::new("./target/build/test_daemon/root/")).unwrap();
+        fs::write("./target/build/test_daemon/root/a", "a").unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = vec![];
+            let mut buffer = [0; 4096];
+            while !String::from_utf8_lossy(&request).contains("\r\n\r\n{") {
+                let n = stream.read(&mut buffer).unwrap();
+                request.extend_from_slice(&buffer[..n]);
+            }
+            while !request.ends_with(b"}") {
+                let n = stream.read(&mut buffer).unwrap();
+                request.extend_from_slice(&buffer[..n]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            String::from_utf8_lossy(&request).to_string()
+        });
+        let syslog = UnixDatagram::bind("./target/build/test_daemon/log").unwrap();
+
+        let config: DaemonConfig = toml::from_str(&format!(
+            r#"
+            state_dir = "./target/build/test_daemon/state"
+
+            [[root]]
+            path = "./target/build/test_daemon/root"
+            hash_type = "SHA3"
+
+            [[sink]]
+            type = "file"
+            path = "./target/build/test_daemon/reports.jsonl"
+
+            [[sink]]
+            type = "webhook"
+            url = "http://127.0.0.1:{port}/alerts"
+            changes_only = true
+
+            [[sink]]
+            type = "syslog"
+            socket = "./target/build/test_daemon/log"
+            changes_only = true
+            "#
+        ))
+        .unwrap();
+        assert_eq!(config.roots[0].interval, 3600);
+        let mut daemon = Daemon::new(config, true).unwrap();
+
+        let first = daemon.run_once().unwrap().remove(0);
+        assert_eq!(first.previous_uuid, None);
+        assert_eq!(first.files, 1);
+
+        fs::write("./target/build/test_daemon/root/a", "changed").unwrap();
+        fs::write("./target/build/test_daemon/root/b", "b").unwrap();
+        let second = daemon.run_once().unwrap().remove(0);
+        assert_eq!(second.previous_uuid, Some(first.uuid));
+        assert_eq!(
+            second.changed,
+            vec!["./target/build/test_daemon/root/a".to_string()]
+        );
+        assert_eq!(
+            second.created,
+            vec!["./target/build/test_daemon/root/b".to_string()]
+        );
+
+        // only the last snapshot is kept
+        assert_eq!(
+            daemon
+                .store
+                .list("./target/build/test_daemon/root")
+                .unwrap()
+                .len(),
+            1
+        );
+        let lines = fs::read_to_string("./target/build/test_daemon/reports.jsonl").unwrap();
+        assert_eq!(lines.lines().count(), 2);
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /alerts HTTP/1.1\r\n"));
+        assert!(request.ends_with(&serde_json::to_string(&second).unwrap()));
+        let mut message = vec![0; 64 * 1024];
+        let n = syslog.recv(&mut message).unwrap();
+        let message = String::from_utf8_lossy(&message[..n]).to_string();
+        assert!(message.starts_with("<28>1 "));
+        assert!(message.contains(r#"path="./target/build/test_daemon/root/b" change="created""#));
+
+        // a held lock stops overlapping scans
+        let lock = ScanLock::acquire(&daemon.lock_path()).unwrap();
+        assert!(daemon.run_once().is_err());
+        drop(lock);
+        assert!(daemon.run_once().is_ok());
+        fs::remove_dir_all(Path::new("./target/build/test_daemon/")).unwrap();
+    }
+
+    #[test]
+    fn webhook_must_be_local() {
+        assert!(post_json("https://127.0.0.1/alerts", "{}").is_err());
+        assert!(post_json("http://192.0.2.1:8080/alerts", "{}").is_err());
+    }
+}
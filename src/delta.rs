@@ -0,0 +1,254 @@
+use crate::hasher::HashType;
+use crate::merkle;
+use crate::snapshot::{
+    open_payload, path_resolve, write_payload, ExportOptions, FileMetadata, ImportOptions, Snapshot,
+};
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// The entries that changed between a parent snapshot and a child snapshot.
+///
+/// A delta names its parent by uuid and Merkle root, and records the Merkle root of the snapshot
+/// it produces, so a chain of deltas can be replayed onto a full snapshot and checked at every
+/// step.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeltaSnapshot {
+    pub uuid: String,
+    pub parent_uuid: String,
+    pub parent_merkle_root: String,
+    pub root_path: String,
+    pub hash_type: HashType,
+    pub date_created: i64,
+    pub merkle_root: String,
+    pub created: Vec<FileMetadata>,
+    pub changed: Vec<FileMetadata>,
+    pub deleted: Vec<String>,
+}
+
+impl DeltaSnapshot {
+    pub fn is_empty(&self) -> bool {
+        self.created.is_empty() && self.changed.is_empty() && self.deleted.is_empty()
+    }
+}
+
+/// Record how `child` differs from `parent`. Entries count as changed when any of their metadata
+/// differs, so replaying the delta reproduces `child` exactly.
+pub fn diff(parent: &Snapshot, child: &Snapshot) -> Result<DeltaSnapshot, Error> {
+    let parent_hashes = parent
+        .file_hashes
+        .lock()
+        .map_err(|_| anyhow!("unable to lock snapshot"))?
+        .clone();
+    let child_hashes = child
+        .file_hashes
+        .lock()
+        .map_err(|_| anyhow!("unable to lock snapshot"))?
+        .clone();
+
+    let mut created = vec![];
+    let mut changed = vec![];
+    let mut deleted = vec![];
+    for (path, entry) in child_hashes.iter() {
+        match parent_hashes.get(path) {
+            None => created.push(entry.clone()),
+            Some(parent_entry) if parent_entry != entry => changed.push(entry.clone()),
+            Some(_) => {}
+        }
+    }
+    for path in parent_hashes.keys() {
+        if !child_hashes.contains_key(path) {
+            deleted.push(path.clone());
+        }
+    }
+    created.sort_by(|a, b| a.path.cmp(&b.path));
+    changed.sort_by(|a, b| a.path.cmp(&b.path));
+    deleted.sort();
+
+    Ok(DeltaSnapshot {
+        uuid: child.uuid.clone(),
+        parent_uuid: parent.uuid.clone(),
+        parent_merkle_root: merkle::build(&parent.root_path, &parent_hashes).root,
+        root_path: child.root_path.clone(),
+        hash_type: child.hash_type,
+        date_created: child.date_created,
+        merkle_root: merkle::build(&child.root_path, &child_hashes).root,
+        created,
+        changed,
+        deleted,
+    })
+}
+
+/// Replay `delta` onto `parent`, which must be the snapshot the delta was taken against.
+pub fn apply(parent: &Snapshot, delta: &DeltaSnapshot) -> Result<Snapshot, Error> {
+    let mut file_hashes = parent
+        .file_hashes
+        .lock()
+        .map_err(|_| anyhow!("unable to lock snapshot"))?
+        .clone();
+    if delta.parent_uuid != parent.uuid
+        || merkle::build(&parent.root_path, &file_hashes).root != delta.parent_merkle_root
+    {
+        return Err(anyhow!(
+            "delta {} does not apply to snapshot {}",
+            delta.uuid,
+            parent.uuid
+        ));
+    }
+
+    for path in delta.deleted.iter() {
+        file_hashes.remove(path);
+    }
+    for entry in delta.created.iter().chain(delta.changed.iter()) {
+        file_hashes.insert(entry.path.clone(), entry.clone());
+    }
+
+    let merkle_root = merkle::build(&delta.root_path, &file_hashes).root;
+    if merkle_root != delta.merkle_root {
+        return Err(anyhow!(
+            "delta {} produced an unexpected merkle root",
+            delta.uuid
+        ));
+    }
+    Ok(Snapshot {
+        file_hashes: Arc::new(Mutex::new(file_hashes)),
+        black_list: parent.black_list.clone(),
+        root_path: delta.root_path.clone(),
+        hash_type: delta.hash_type,
+        uuid: delta.uuid.clone(),
+        date_created: delta.date_created,
+        merkle_root,
+    })
+}
+
+/// Replay a chain of deltas, oldest first, onto the full snapshot it starts from. The result is
+/// a full snapshot equal to the last snapshot of the chain, so this also compacts a chain.
+pub fn materialize(base: &Snapshot, chain: &[DeltaSnapshot]) -> Result<Snapshot, Error> {
+    let mut snapshot = base.clone();
+    for delta in chain {
+        snapshot = apply(&snapshot, delta)?;
+    }
+    Ok(snapshot)
+}
+
+/// Merge a chain of deltas, oldest first, into a single delta against the chain's base.
+pub fn squash(base: &Snapshot, chain: &[DeltaSnapshot]) -> Result<DeltaSnapshot, Error> {
+    let last = materialize(base, chain)?;
+    diff(base, &last)
+}
+
+/// Take a full snapshot of `path` and record it as a delta against `parent`.
+pub fn delta_since(
+    parent: &Snapshot,
+    path: &str,
+    black_list: Vec<String>,
+    verbose: bool,
+) -> Result<DeltaSnapshot, Error> {
+    let child = Snapshot::new(Path::new(path), parent.hash_type, black_list, verbose)?;
+    diff(parent, &child)
+}
+
+pub fn export_delta(
+    delta: &DeltaSnapshot,
+    path: String,
+    options: &ExportOptions,
+    verbose: bool,
+) -> Result<(), Error> {
+    let full_path = PathBuf::from(path_resolve(path));
+    write_payload(&full_path, serde_json::to_vec(delta)?, options, verbose)
+}
+
+pub fn import_delta(
+    path: String,
+    options: &ImportOptions,
+    verbose: bool,
+) -> Result<DeltaSnapshot, Error> {
+    let full_path = PathBuf::from(path_resolve(path));
+    let payload = open_payload(&full_path, fs::read(&full_path)?, options, verbose)?;
+    Ok(serde_json::from_slice::<DeltaSnapshot>(&payload)?)
+}
+
+/// Index a set of deltas by parent uuid and return the chain that starts at `base_uuid`, oldest
+/// first.
+pub fn chain_from(base_uuid: &str, deltas: Vec<DeltaSnapshot>) -> Vec<DeltaSnapshot> {
+    let mut by_parent: HashMap<String, DeltaSnapshot> = deltas
+        .into_iter()
+        .map(|d| (d.parent_uuid.clone(), d))
+        .collect();
+    let mut chain = vec![];
+    let mut current = base_uuid.to_string();
+    while let Some(delta) = by_parent.remove(&current) {
+        current = delta.uuid.clone();
+        chain.push(delta);
+    }
+    chain
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::test_snapshot;
+
+    #[test]
+    fn diff_and_replay_chain() {
+        let base = test_snapshot("/etc", "a", 0, &[("/etc/hosts", 1), ("/etc/passwd", 1)]);
+        let second = test_snapshot(
+            "/etc",
+            "b",
+            0,
+            &[("/etc/hosts", 2), ("/etc/passwd", 1), ("/etc/new", 1)],
+        );
+        let third = test_snapshot("/etc", "c", 0, &[("/etc/hosts", 2), ("/etc/new", 3)]);
+
+        let first_delta = diff(&base, &second).unwrap();
+        assert_eq!(first_delta.created.len(), 1);
+        assert_eq!(first_delta.changed.len(), 1);
+        assert!(first_delta.deleted.is_empty());
+        let second_delta = diff(&second, &third).unwrap();
+        assert_eq!(second_delta.deleted, vec!["/etc/passwd".to_string()]);
+
+        let chain = chain_from("a", vec![second_delta.clone(), first_delta.clone()]);
+        assert_eq!(chain, vec![first_delta.clone(), second_delta.clone()]);
+
+        let materialized = materialize(&base, &chain).unwrap();
+        assert_eq!(materialized.uuid, "c");
+        assert_eq!(materialized.merkle_root, third.merkle_root);
+        assert_eq!(
+            *materialized.file_hashes.lock().unwrap(),
+            *third.file_hashes.lock().unwrap()
+        );
+
+        let squashed = squash(&base, &chain).unwrap();
+        assert_eq!(squashed.parent_uuid, "a");
+        assert_eq!(squashed.deleted, vec!["/etc/passwd".to_string()]);
+        assert!(diff(&third, &materialized).unwrap().is_empty());
+
+        // out of order replay is refused
+        assert!(apply(&base, &second_delta).is_err());
+    }
+
+    #[test]
+    fn export_and_import_delta() {
+        assert!(!Path::new("./target/build/test_delta/").exists());
+        let base = test_snapshot("/etc", "a", 0, &[("/etc/hosts", 1)]);
+        let delta = diff(&base, &test_snapshot("/etc", "b", 0, &[("/etc/hosts", 2)])).unwrap();
+        export_delta(
+            &delta,
+            "./target/build/test_delta/b.delta".to_string(),
+            &ExportOptions::default(),
+            true,
+        )
+        .unwrap();
+        let imported = import_delta(
+            "./target/build/test_delta/b.delta".to_string(),
+            &ImportOptions::default(),
+            true,
+        )
+        .unwrap();
+        assert_eq!(imported, delta);
+        fs::remove_dir_all(Path::new("./target/build/test_delta/")).unwrap();
+    }
+}
@@ -0,0 +1,440 @@
+use crate::hasher::{hash_path, HashType};
+use crate::manifest::parse_manifest_line;
+use crate::snapshot::{FileMetadata, Snapshot};
+use anyhow::{anyhow, Error};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const DPKG_ADMIN_DIR: &str = "/var/lib/dpkg";
+
+/// Options for [`read_packages`] and [`verify_packages`].
+#[derive(Debug, Clone)]
+pub struct DpkgOptions {
+    /// The dpkg database, holding `status` and `info/`.
+    pub admin_dir: PathBuf,
+    /// Directory the packages are installed under.
+    pub root: PathBuf,
+    /// Only these packages, by name or `name:arch`; every installed package when empty.
+    pub packages: Vec<String>,
+    /// Also check conffiles against the digests recorded in `status`.
+    pub conffiles: bool,
+}
+
+impl Default for DpkgOptions {
+    fn default() -> Self {
+        DpkgOptions {
+            admin_dir: PathBuf::from(DPKG_ADMIN_DIR),
+            root: PathBuf::from("/"),
+            packages: vec![],
+            conffiles: true,
+        }
+    }
+}
+
+/// An installed package and the MD5 digests dpkg recorded for its files. Paths are absolute,
+/// as seen inside the package's root.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DpkgPackage {
+    pub name: String,
+    pub architecture: String,
+    pub files: Vec<(String, Vec<u8>)>,
+    pub conffiles: Vec<(String, Vec<u8>)>,
+}
+
+/// What [`verify_packages`] found for one package.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct PackageReport {
+    pub package: String,
+    pub architecture: String,
+    pub checked: u64,
+    pub modified: Vec<String>,
+    pub missing: Vec<String>,
+    /// Conffiles are meant to be edited, so they are kept apart from the package's own files.
+    pub modified_conffiles: Vec<String>,
+    pub missing_conffiles: Vec<String>,
+    /// `(path, message)` for files that could not be read.
+    pub errors: Vec<(String, String)>,
+}
+
+impl PackageReport {
+    /// Whether the package's files, not counting conffiles, are intact.
+    pub fn is_clean(&self) -> bool {
+        self.modified.is_empty() && self.missing.is_empty()
+    }
+
+    fn has_findings(&self) -> bool {
+        !self.is_clean()
+            || !self.modified_conffiles.is_empty()
+            || !self.missing_conffiles.is_empty()
+            || !self.errors.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DpkgReport {
+    pub packages_checked: u64,
+    pub files_checked: u64,
+    /// Only packages with modified, missing or unreadable files.
+    pub packages: Vec<PackageReport>,
+}
+
+impl DpkgReport {
+    pub fn is_clean(&self) -> bool {
+        self.packages.iter().all(PackageReport::is_clean)
+    }
+}
+
+/// The stanzas of a dpkg `status` file as (field, value) pairs, continuation lines joined
+/// with newlines.
+fn parse_stanzas(status: &str) -> Vec<Vec<(String, String)>> {
+    let mut stanzas = vec![];
+    let mut fields: Vec<(String, String)> = vec![];
+    for line in status.lines() {
+        if line.trim().is_empty() {
+            if !fields.is_empty() {
+                stanzas.push(std::mem::take(&mut fields));
+            }
+        } else if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = fields.last_mut() {
+                value.push('\n');
+                value.push_str(line.trim());
+            }
+        } else if let Some((field, value)) = line.split_once(':') {
+            fields.push((field.to_string(), value.trim().to_string()));
+        }
+    }
+    if !fields.is_empty() {
+        stanzas.push(fields);
+    }
+    stanzas
+}
+
+/// The `Conffiles` field: one `path digest [obsolete|remove-on-upgrade]` per line. Obsolete
+/// conffiles and ones dpkg has no digest for yet (`newconffile`) are skipped.
+fn parse_conffiles(value: &str) -> Vec<(String, Vec<u8>)> {
+    value
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            let path = words.next()?;
+            let digest = hex::decode(words.next()?).ok()?;
+            if words.any(|flag| flag == "obsolete") || digest.len() != 16 {
+                return None;
+            }
+            Some((path.to_string(), digest))
+        })
+        .collect()
+}
+
+fn read_md5sums(path: &Path) -> Result<Vec<(String, Vec<u8>)>, Error> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(anyhow!("{}: {e}", path.display())),
+    };
+    let mut files = vec![];
+    for (number, line) in contents.lines().enumerate() {
+        match parse_manifest_line(line) {
+            Ok(Some((digest, name))) => {
+                files.push((format!("/{}", name.trim_start_matches('/')), digest))
+            }
+            Ok(None) => {}
+            Err(e) => return Err(anyhow!("{}:{}: {e}", path.display(), number + 1)),
+        }
+    }
+    Ok(files)
+}
+
+/// The `diversions` file: for each diverted path, where it was moved to and by which package
+/// (`:` for local diversions).
+fn read_diversions(admin_dir: &Path) -> HashMap<String, (String, String)> {
+    let contents = fs::read_to_string(admin_dir.join("diversions")).unwrap_or_default();
+    let lines: Vec<&str> = contents.lines().collect();
+    lines
+        .chunks_exact(3)
+        .map(|chunk| {
+            (
+                chunk[0].to_string(),
+                (chunk[1].to_string(), chunk[2].to_string()),
+            )
+        })
+        .collect()
+}
+
+/// Read the installed packages from the dpkg database, with the digests from their
+/// `info/<package>.md5sums` files and the conffile digests from `status`. Files another
+/// package diverted are looked for where the diversion moved them.
+pub fn read_packages(options: &DpkgOptions) -> Result<Vec<DpkgPackage>, Error> {
+    let status_path = options.admin_dir.join("status");
+    let status =
+        fs::read_to_string(&status_path).map_err(|e| anyhow!("{}: {e}", status_path.display()))?;
+    let diversions = read_diversions(&options.admin_dir);
+    let mut packages = vec![];
+    for stanza in parse_stanzas(&status) {
+        let field = |name: &str| {
+            stanza
+                .iter()
+                .find(|(field, _)| field.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+        };
+        let Some(name) = field("Package") else {
+            continue;
+        };
+        if field("Status").and_then(|s| s.split_whitespace().nth(2)) != Some("installed") {
+            continue;
+        }
+        let architecture = field("Architecture").unwrap_or_default();
+        let qualified = format!("{name}:{architecture}");
+        if !options.packages.is_empty()
+            && !options
+                .packages
+                .iter()
+                .any(|wanted| wanted == name || *wanted == qualified)
+        {
+            continue;
+        }
+        // Multi-Arch: same packages keep their files under the qualified name
+        let info = options.admin_dir.join("info");
+        let md5sums = [qualified.as_str(), name]
+            .iter()
+            .map(|stem| info.join(format!("{stem}.md5sums")))
+            .find(|path| path.exists());
+        let mut files = match md5sums {
+            Some(path) => read_md5sums(&path)?,
+            None => vec![],
+        };
+        for (path, _) in files.iter_mut() {
+            if let Some((diverted_to, by)) = diversions.get(path.as_str()) {
+                if by != name {
+                    *path = diverted_to.clone();
+                }
+            }
+        }
+        packages.push(DpkgPackage {
+            name: name.to_string(),
+            architecture: architecture.to_string(),
+            files,
+            conffiles: match options.conffiles {
+                true => field("Conffiles").map(parse_conffiles).unwrap_or_default(),
+                false => vec![],
+            },
+        });
+    }
+    if let Some(missing) = options.packages.iter().find(|wanted| {
+        !packages
+            .iter()
+            .any(|p| p.name == **wanted || format!("{}:{}", p.name, p.architecture) == **wanted)
+    }) {
+        return Err(anyhow!("package is not installed: {missing}"));
+    }
+    Ok(packages)
+}
+
+fn under_root(root: &Path, path: &str) -> PathBuf {
+    root.join(path.trim_start_matches('/'))
+}
+
+/// A baseline MD5 snapshot of every file and conffile of `packages`, for use with
+/// [`crate::verify::verify_paths`] or [`crate::snapshot::compare_hashes`].
+pub fn dpkg_baseline(packages: &[DpkgPackage], root: &Path) -> Result<Snapshot, Error> {
+    let root_path = root.to_str().ok_or_else(|| anyhow!("cannot parse path"))?;
+    let mut entries = vec![];
+    for package in packages {
+        for (path, digest) in package.files.iter().chain(package.conffiles.iter()) {
+            let path = under_root(root, path);
+            entries.push(FileMetadata {
+                path: path
+                    .to_str()
+                    .ok_or_else(|| anyhow!("cannot parse path"))?
+                    .to_string(),
+                check_sum: digest.clone(),
+                ..Default::default()
+            });
+        }
+    }
+    Ok(Snapshot::from_entries(root_path, HashType::MD5, entries))
+}
+
+/// Hash every file of the selected packages and report, per package, the ones that are
+/// modified or missing. Like `dpkg --verify`, only the listed files are checked.
+pub fn verify_packages(options: &DpkgOptions, verbose: bool) -> Result<DpkgReport, Error> {
+    let packages = read_packages(options)?;
+    let mut report = DpkgReport {
+        packages_checked: packages.len() as u64,
+        ..Default::default()
+    };
+    for package in packages {
+        let mut package_report = PackageReport {
+            package: package.name.clone(),
+            architecture: package.architecture.clone(),
+            ..Default::default()
+        };
+        for (files, conffile) in [(&package.files, false), (&package.conffiles, true)] {
+            for (path, expected) in files {
+                let full_path = under_root(&options.root, path);
+                package_report.checked += 1;
+                if fs::symlink_metadata(&full_path).is_err() {
+                    match conffile {
+                        true => package_report.missing_conffiles.push(path.clone()),
+                        false => package_report.missing.push(path.clone()),
+                    }
+                    continue;
+                }
+                match hash_path(&full_path, HashType::MD5, verbose) {
+                    Ok(actual) if actual.check_sum == *expected => {}
+                    Ok(_) => match conffile {
+                        true => package_report.modified_conffiles.push(path.clone()),
+                        false => package_report.modified.push(path.clone()),
+                    },
+                    Err(e) => package_report.errors.push((path.clone(), e.to_string())),
+                }
+            }
+        }
+        report.files_checked += package_report.checked;
+        if package_report.has_findings() {
+            report.packages.push(package_report);
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verify::{verify_paths, VerifyOptions};
+
+    const STATUS: &str = "\
+Package: hello
+Status: install ok installed
+Architecture: amd64
+Version: 2.10-3
+Conffiles:
+ /etc/hello.conf 5d41402abc4b2a76b9719d911017c592
+ /etc/hello.d/old.conf 0123456789abcdef0123456789abcdef obsolete
+Description: example package
+ Prints a greeting.
+
+Package: libfoo1
+Status: install ok installed
+Architecture: arm64
+Multi-Arch: same
+
+Package: gone
+Status: deinstall ok config-files
+Architecture: all
+";
+
+    fn md5(contents: &str) -> String {
+        hex::encode(md5::compute(contents).0)
+    }
+
+    fn fixture(base: &Path) -> DpkgOptions {
+        let admin = base.join("admin");
+        let root = base.join("root");
+        fs::create_dir_all(admin.join("info")).unwrap();
+        fs::create_dir_all(root.join("usr/bin")).unwrap();
+        fs::create_dir_all(root.join("usr/lib")).unwrap();
+        fs::create_dir_all(root.join("etc")).unwrap();
+        fs::write(admin.join("status"), STATUS).unwrap();
+        fs::write(
+            admin.join("info/hello.md5sums"),
+            format!(
+                "{}  usr/bin/hello\n{}  usr/share/doc/hello/README\n",
+                md5("hello"),
+                md5("readme")
+            ),
+        )
+        .unwrap();
+        fs::write(
+            admin.join("info/libfoo1:arm64.md5sums"),
+            format!("{}  usr/lib/libfoo.so.1\n", md5("foo")),
+        )
+        .unwrap();
+        fs::write(admin.join("info/gone.md5sums"), "").unwrap();
+        fs::write(
+            admin.join("diversions"),
+            "/usr/lib/libfoo.so.1\n/usr/lib/libfoo.so.1.real\nlibfoo-wrapper\n\
+             /usr/bin/hello\n/usr/bin/hello.orig\nhello\n",
+        )
+        .unwrap();
+        fs::write(root.join("usr/bin/hello"), "hello").unwrap();
+        fs::write(root.join("usr/lib/libfoo.so.1"), "wrapper").unwrap();
+        fs::write(root.join("usr/lib/libfoo.so.1.real"), "foo").unwrap();
+        // "hello" is the conffile's recorded digest; the admin changed it
+        fs::write(root.join("etc/hello.conf"), "edited").unwrap();
+        DpkgOptions {
+            admin_dir: admin,
+            root,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn verifies_packages() {
+        let base = Path::new("./target/build/test_dpkg");
+        assert!(!base.exists());
+        let mut options = fixture(base);
+
+        let packages = read_packages(&options).unwrap();
+        let names: Vec<&str> = packages.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["hello", "libfoo1"]);
+        assert_eq!(packages[0].files[0].0, "/usr/bin/hello");
+        assert_eq!(packages[0].conffiles.len(), 1);
+        assert_eq!(packages[1].files.len(), 1);
+
+        let report = verify_packages(&options, false).unwrap();
+        assert_eq!(report.packages_checked, 2);
+        assert_eq!(report.files_checked, 4);
+        assert_eq!(report.packages.len(), 1);
+        let hello = &report.packages[0];
+        assert_eq!(hello.package, "hello");
+        assert!(hello.modified.is_empty());
+        assert_eq!(hello.missing, ["/usr/share/doc/hello/README"]);
+        assert_eq!(hello.modified_conffiles, ["/etc/hello.conf"]);
+        assert!(!report.is_clean());
+
+        fs::write(options.root.join("usr/lib/libfoo.so.1.real"), "bar").unwrap();
+        options.packages = vec!["libfoo1:arm64".to_string()];
+        let report = verify_packages(&options, false).unwrap();
+        assert_eq!(report.packages_checked, 1);
+        assert_eq!(report.packages[0].package, "libfoo1");
+        assert_eq!(report.packages[0].architecture, "arm64");
+        assert_eq!(report.packages[0].modified, ["/usr/lib/libfoo.so.1.real"]);
+
+        options.packages = vec!["gone".to_string()];
+        assert!(verify_packages(&options, false).is_err());
+
+        options.packages = vec!["hello".to_string()];
+        options.conffiles = false;
+        let packages = read_packages(&options).unwrap();
+        let baseline = dpkg_baseline(&packages, &options.root).unwrap();
+        assert_eq!(baseline.hash_type, HashType::MD5);
+        let bin = options.root.join("usr/bin");
+        let mut deleted = vec![];
+        let report = verify_paths(
+            &baseline,
+            &[bin.to_str().unwrap()],
+            &VerifyOptions::default(),
+            false,
+            |event| deleted.push(event.path().to_string()),
+        )
+        .unwrap();
+        assert!(report.is_clean(), "{deleted:?}");
+        assert_eq!(report.checked, 1);
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn parses_status() {
+        let stanzas = parse_stanzas(STATUS);
+        assert_eq!(stanzas.len(), 3);
+        let conffiles = &stanzas[0].iter().find(|(f, _)| f == "Conffiles").unwrap().1;
+        let parsed = parse_conffiles(conffiles);
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(parsed[0].0, "/etc/hello.conf");
+        assert!(parse_conffiles(" /etc/new newconffile").is_empty());
+    }
+}
@@ -0,0 +1,163 @@
+use anyhow::{anyhow, Error};
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use std::fmt;
+
+/// Prefix identifying an encrypted snapshot file.
+const MAGIC: &[u8; 8] = b"FSHENC01";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const HEADER_LEN: usize = MAGIC.len() + 1 + SALT_LEN + NONCE_LEN;
+
+const KIND_KEY: u8 = 0;
+const KIND_PASSPHRASE: u8 = 1;
+
+/// Key material used to encrypt or decrypt an exported snapshot with XChaCha20-Poly1305.
+#[derive(Clone)]
+pub enum EncryptionKey {
+    /// A raw 256 bit key.
+    Key([u8; 32]),
+    /// A passphrase, stretched with Argon2id and a random per-file salt.
+    Passphrase(String),
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncryptionKey::Key(_) => f.write_str("Key(..)"),
+            EncryptionKey::Passphrase(_) => f.write_str("Passphrase(..)"),
+        }
+    }
+}
+
+impl EncryptionKey {
+    pub fn generate() -> EncryptionKey {
+        EncryptionKey::Key(rand::random::<[u8; 32]>())
+    }
+
+    /// Parse a hex encoded 256 bit key.
+    pub fn from_hex(key: &str) -> Result<EncryptionKey, Error> {
+        let bytes = hex::decode(key.trim())?;
+        let key: [u8; 32] = bytes
+            .try_into()
+            .map_err(|_| anyhow!("encryption key must be 32 bytes"))?;
+        Ok(EncryptionKey::Key(key))
+    }
+
+    fn kind(&self) -> u8 {
+        match self {
+            EncryptionKey::Key(_) => KIND_KEY,
+            EncryptionKey::Passphrase(_) => KIND_PASSPHRASE,
+        }
+    }
+
+    fn derive(&self, salt: &[u8]) -> Result<[u8; 32], Error> {
+        match self {
+            EncryptionKey::Key(key) => Ok(*key),
+            EncryptionKey::Passphrase(passphrase) => {
+                let mut key = [0u8; 32];
+                Argon2::default()
+                    .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+                    .map_err(|e| anyhow!("unable to derive key: {}", e))?;
+                Ok(key)
+            }
+        }
+    }
+}
+
+pub fn is_encrypted(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Encrypt `plaintext`, returning a self describing blob of header followed by ciphertext.
+/// The header is authenticated along with the ciphertext.
+pub fn encrypt(key: &EncryptionKey, plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+    let salt = rand::random::<[u8; SALT_LEN]>();
+    let nonce = rand::random::<[u8; NONCE_LEN]>();
+
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(MAGIC);
+    header.push(key.kind());
+    header.extend_from_slice(&salt);
+    header.extend_from_slice(&nonce);
+
+    let cipher = XChaCha20Poly1305::new(&key.derive(&salt)?.into());
+    let ciphertext = cipher
+        .encrypt(
+            XNonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext,
+                aad: &header,
+            },
+        )
+        .map_err(|_| anyhow!("unable to encrypt snapshot"))?;
+
+    header.extend_from_slice(&ciphertext);
+    Ok(header)
+}
+
+pub fn decrypt(key: &EncryptionKey, bytes: &[u8]) -> Result<Vec<u8>, Error> {
+    if bytes.len() < HEADER_LEN || !is_encrypted(bytes) {
+        return Err(anyhow!("not an encrypted snapshot"));
+    }
+    let (header, ciphertext) = bytes.split_at(HEADER_LEN);
+    if header[MAGIC.len()] != key.kind() {
+        return Err(anyhow!(
+            "snapshot was encrypted with a different kind of key"
+        ));
+    }
+    let salt = &header[MAGIC.len() + 1..MAGIC.len() + 1 + SALT_LEN];
+    let nonce = &header[MAGIC.len() + 1 + SALT_LEN..];
+
+    let cipher = XChaCha20Poly1305::new(&key.derive(salt)?.into());
+    cipher
+        .decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: header,
+            },
+        )
+        .map_err(|_| anyhow!("unable to decrypt snapshot"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_round_trip() {
+        let key = EncryptionKey::generate();
+        let encrypted = encrypt(&key, b"/root/.ssh/id_ed25519").unwrap();
+        assert!(is_encrypted(&encrypted));
+        assert!(!encrypted
+            .windows(b".ssh".len())
+            .any(|window| window == b".ssh"));
+        assert_eq!(decrypt(&key, &encrypted).unwrap(), b"/root/.ssh/id_ed25519");
+        assert!(decrypt(&EncryptionKey::generate(), &encrypted).is_err());
+    }
+
+    #[test]
+    fn passphrase_round_trip() {
+        let key = EncryptionKey::Passphrase("hunter2".to_string());
+        let mut encrypted = encrypt(&key, b"snapshot").unwrap();
+        assert_eq!(decrypt(&key, &encrypted).unwrap(), b"snapshot");
+        assert!(decrypt(
+            &EncryptionKey::Passphrase("hunter3".to_string()),
+            &encrypted
+        )
+        .is_err());
+
+        let last = encrypted.len() - 1;
+        encrypted[last] ^= 1;
+        assert!(decrypt(&key, &encrypted).is_err());
+    }
+
+    #[test]
+    fn hex_keys() {
+        assert!(EncryptionKey::from_hex(&"ab".repeat(32)).is_ok());
+        assert!(EncryptionKey::from_hex("abcd").is_err());
+        assert!(EncryptionKey::from_hex("not hex").is_err());
+    }
+}
@@ -0,0 +1,375 @@
+use crate::snapshot::{Snapshot, SnapshotCompareResult};
+use crate::watch::{wait_readable, Tracker, WatchOptions};
+use anyhow::{anyhow, Error};
+use std::ffi::{CString, OsStr};
+use std::fs::{self, File};
+use std::io;
+use std::mem::size_of;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const EVENT_MASK: u64 =
+    libc::FAN_CLOSE_WRITE | libc::FAN_CREATE | libc::FAN_DELETE | libc::FAN_MOVE | libc::FAN_ONDIR;
+
+/// Size of the `fsid` that precedes the file handle in a fid info record.
+const FSID_SIZE: usize = 8;
+
+#[derive(Debug, PartialEq, Eq)]
+enum FanotifyEvent {
+    Overflow,
+    Path { mask: u64, path: PathBuf },
+}
+
+/// Watches a whole filesystem with fanotify and rehashes the files of a baseline's tree as they
+/// change. Unlike [`crate::watch::Watcher`] it needs no per-directory watches, but it needs
+/// `CAP_SYS_ADMIN` and `CAP_DAC_READ_SEARCH`.
+///
+/// Changes are reported the same way as by [`crate::watch::Watcher`]; a queue overflow triggers
+/// a rescan of the whole tree. Events for directories that no longer exist are dropped: they
+/// are as likely to come from elsewhere on the filesystem, and a directory removed from the
+/// watched tree is reported through the event for its parent.
+pub struct FanotifyWatcher {
+    fd: OwnedFd,
+    mount: File,
+    root: PathBuf,
+    tracker: Tracker,
+    buffer: Vec<u8>,
+}
+
+impl FanotifyWatcher {
+    pub fn new(baseline: &Snapshot, options: WatchOptions) -> Result<FanotifyWatcher, Error> {
+        let tracker = Tracker::new(baseline, options)?;
+        let root = fs::canonicalize(tracker.root())?;
+        let fd = unsafe {
+            libc::fanotify_init(
+                libc::FAN_CLASS_NOTIF
+                    | libc::FAN_CLOEXEC
+                    | libc::FAN_NONBLOCK
+                    | libc::FAN_REPORT_DFID_NAME,
+                (libc::O_RDONLY | libc::O_LARGEFILE) as u32,
+            )
+        };
+        if fd < 0 {
+            return Err(anyhow!(
+                "fanotify_init failed: {}",
+                io::Error::last_os_error()
+            ));
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let c_root = CString::new(root.as_os_str().as_bytes())?;
+        let marked = unsafe {
+            libc::fanotify_mark(
+                fd.as_raw_fd(),
+                libc::FAN_MARK_ADD | libc::FAN_MARK_FILESYSTEM,
+                EVENT_MASK,
+                libc::AT_FDCWD,
+                c_root.as_ptr(),
+            )
+        };
+        if marked < 0 {
+            return Err(anyhow!(
+                "fanotify_mark failed: {}",
+                io::Error::last_os_error()
+            ));
+        }
+
+        Ok(FanotifyWatcher {
+            fd,
+            mount: File::open(&root)?,
+            root,
+            tracker,
+            buffer: vec![0; 64 * 1024],
+        })
+    }
+
+    /// The current state of the watched tree.
+    pub fn snapshot(&mut self) -> &Snapshot {
+        self.tracker.snapshot()
+    }
+
+    /// Wait up to `timeout` for filesystem events, handle them and return what changed. `None`
+    /// when nothing did.
+    pub fn poll(&mut self, timeout: Duration) -> Result<Option<SnapshotCompareResult>, Error> {
+        if wait_readable(self.fd.as_raw_fd(), timeout)? {
+            let events = self.read_events()?;
+            if events.contains(&FanotifyEvent::Overflow) {
+                // one rescan covers every event of the batch
+                self.tracker.rescan(&self.tracker.root());
+            } else {
+                for event in events {
+                    self.handle(event);
+                }
+            }
+        }
+        Ok(self.tracker.take())
+    }
+
+    /// Call `on_change` with each batch of changes until it returns false.
+    pub fn run<F>(&mut self, mut on_change: F) -> Result<(), Error>
+    where
+        F: FnMut(&SnapshotCompareResult) -> bool,
+    {
+        loop {
+            if let Some(result) = self.poll(Duration::from_secs(1))? {
+                if !on_change(&result) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn handle(&mut self, event: FanotifyEvent) {
+        let (mask, path) = match event {
+            FanotifyEvent::Path { mask, path } => (mask, path),
+            _ => return,
+        };
+        // the mark covers the whole filesystem, so most events are for other trees
+        let key = match path.strip_prefix(&self.root) {
+            Ok(relative) if relative.as_os_str().is_empty() => self.tracker.root(),
+            Ok(relative) => self.tracker.root().join(relative),
+            Err(_) => return,
+        };
+        if mask & libc::FAN_ONDIR != 0 {
+            if mask & (libc::FAN_CREATE | libc::FAN_MOVED_TO) != 0 {
+                self.tracker.rescan(&key);
+            } else if mask & (libc::FAN_DELETE | libc::FAN_MOVED_FROM) != 0 {
+                self.tracker.remove(&key.to_string_lossy());
+            }
+        } else if mask & (libc::FAN_DELETE | libc::FAN_MOVED_FROM) != 0 {
+            self.tracker.remove(&key.to_string_lossy());
+        } else {
+            self.tracker.rehash(&key);
+        }
+    }
+
+    fn read_events(&mut self) -> Result<Vec<FanotifyEvent>, Error> {
+        let n = unsafe {
+            libc::read(
+                self.fd.as_raw_fd(),
+                self.buffer.as_mut_ptr() as *mut libc::c_void,
+                self.buffer.len(),
+            )
+        };
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            return match e.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => Ok(vec![]),
+                _ => Err(e.into()),
+            };
+        }
+
+        let mut events = vec![];
+        for (metadata, info) in parse_events(&self.buffer[..n as usize])? {
+            if metadata.fd >= 0 {
+                drop(unsafe { OwnedFd::from_raw_fd(metadata.fd) });
+            }
+            if metadata.mask & libc::FAN_Q_OVERFLOW != 0 {
+                events.push(FanotifyEvent::Overflow);
+                continue;
+            }
+            if let Some(path) = info.and_then(|(handle, name)| self.resolve(handle, name)) {
+                events.push(FanotifyEvent::Path {
+                    mask: metadata.mask,
+                    path,
+                });
+            }
+        }
+        Ok(events)
+    }
+
+    /// Turn a directory file handle and an entry name into a path.
+    fn resolve(&self, handle: &[u8], name: &OsStr) -> Option<PathBuf> {
+        // struct file_handle must be 4 byte aligned
+        let mut aligned = vec![0u32; handle.len().div_ceil(4)];
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                handle.as_ptr(),
+                aligned.as_mut_ptr() as *mut u8,
+                handle.len(),
+            );
+        }
+        let fd = unsafe {
+            libc::open_by_handle_at(
+                self.mount.as_raw_fd(),
+                aligned.as_mut_ptr() as *mut libc::file_handle,
+                libc::O_PATH | libc::O_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return None;
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let dir = fs::read_link(format!("/proc/self/fd/{}", fd.as_raw_fd())).ok()?;
+        if name.is_empty() || name == "." {
+            Some(dir)
+        } else {
+            Some(dir.join(name))
+        }
+    }
+}
+
+/// A directory file handle and an entry name.
+type FidInfo<'a> = (&'a [u8], &'a OsStr);
+
+/// Split a buffer read from a fanotify descriptor into events, each with its
+/// `FAN_EVENT_INFO_TYPE_DFID_NAME` record when it has one.
+fn parse_events(
+    buffer: &[u8],
+) -> Result<Vec<(libc::fanotify_event_metadata, Option<FidInfo<'_>>)>, Error> {
+    let mut events = vec![];
+    let mut offset = 0;
+    while offset + size_of::<libc::fanotify_event_metadata>() <= buffer.len() {
+        let metadata = unsafe {
+            std::ptr::read_unaligned(
+                buffer[offset..].as_ptr() as *const libc::fanotify_event_metadata
+            )
+        };
+        let event_len = metadata.event_len as usize;
+        if metadata.vers != libc::FANOTIFY_METADATA_VERSION {
+            return Err(anyhow!("unsupported fanotify version {}", metadata.vers));
+        }
+        if event_len < size_of::<libc::fanotify_event_metadata>()
+            || offset + event_len > buffer.len()
+        {
+            break;
+        }
+        let event = &buffer[offset..offset + event_len];
+        let mut info = None;
+        let mut record = metadata.metadata_len as usize;
+        let header_size = size_of::<libc::fanotify_event_info_header>();
+        while record + header_size <= event.len() {
+            let header = unsafe {
+                std::ptr::read_unaligned(
+                    event[record..].as_ptr() as *const libc::fanotify_event_info_header
+                )
+            };
+            let len = header.len as usize;
+            if len < header_size || record + len > event.len() {
+                break;
+            }
+            if header.info_type == libc::FAN_EVENT_INFO_TYPE_DFID_NAME {
+                info = parse_dfid_name(&event[record + header_size..record + len]);
+            }
+            record += len;
+        }
+        events.push((metadata, info));
+        offset += event_len;
+    }
+    Ok(events)
+}
+
+/// Parse the body of a DFID_NAME record: an fsid, a `struct file_handle` and a nul terminated
+/// name.
+fn parse_dfid_name(body: &[u8]) -> Option<FidInfo<'_>> {
+    let handle_start = FSID_SIZE;
+    let handle_header = size_of::<u32>() + size_of::<i32>();
+    let bytes = body.get(handle_start..handle_start + size_of::<u32>())?;
+    let handle_bytes = u32::from_ne_bytes(bytes.try_into().ok()?) as usize;
+    let handle_end = handle_start + handle_header + handle_bytes;
+    let handle = body.get(handle_start..handle_end)?;
+    let name = body.get(handle_end..)?;
+    let name = &name[..name.iter().position(|b| *b == 0).unwrap_or(name.len())];
+    Some((handle, OsStr::from_bytes(name)))
+}
+
+/// Whether this process may use [`FanotifyWatcher`] on `path`.
+pub fn available(path: &Path) -> bool {
+    let fd = unsafe {
+        libc::fanotify_init(
+            libc::FAN_CLASS_NOTIF | libc::FAN_CLOEXEC | libc::FAN_REPORT_DFID_NAME,
+            libc::O_RDONLY as u32,
+        )
+    };
+    if fd < 0 {
+        return false;
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    let c_path = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(c_path) => c_path,
+        Err(_) => return false,
+    };
+    let marked = unsafe {
+        libc::fanotify_mark(
+            fd.as_raw_fd(),
+            libc::FAN_MARK_ADD | libc::FAN_MARK_FILESYSTEM,
+            EVENT_MASK,
+            libc::AT_FDCWD,
+            c_path.as_ptr(),
+        )
+    };
+    marked == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hasher::HashType;
+    use std::time::Instant;
+
+    #[test]
+    fn reports_changes_on_the_filesystem() {
+        assert!(!Path::new("./target/build/test_fanotify/").exists());
+        fs::create_dir_all(Path::new("./target/build/test_fanotify/sub/")).unwrap();
+        if !available(Path::new("./target/build/test_fanotify/")) {
+            println!("skipping: fanotify needs CAP_SYS_ADMIN");
+            fs::remove_dir_all(Path::new("./target/build/test_fanotify/")).unwrap();
+            return;
+        }
+        fs::write("./target/build/test_fanotify/a", "a").unwrap();
+        fs::write("./target/build/test_fanotify/sub/b", "b").unwrap();
+        let baseline = Snapshot::new(
+            Path::new("./target/build/test_fanotify"),
+            HashType::BLAKE3,
+            vec![],
+            false,
+        )
+        .unwrap();
+        let mut watcher = FanotifyWatcher::new(&baseline, WatchOptions::default()).unwrap();
+
+        fs::write("./target/build/test_fanotify/a", "changed").unwrap();
+        fs::remove_file("./target/build/test_fanotify/sub/b").unwrap();
+        fs::create_dir_all(Path::new("./target/build/test_fanotify/new/")).unwrap();
+        fs::write("./target/build/test_fanotify/new/c", "c").unwrap();
+        fs::rename(
+            "./target/build/test_fanotify/new/c",
+            "./target/build/test_fanotify/d",
+        )
+        .unwrap();
+
+        let (mut created, mut deleted, mut changed) = (vec![], vec![], vec![]);
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(10)
+            && (created.is_empty() || deleted.is_empty() || changed.is_empty())
+        {
+            if let Some(result) = watcher.poll(Duration::from_millis(100)).unwrap() {
+                created.extend(result.created);
+                deleted.extend(result.deleted);
+                changed.extend(result.changed);
+            }
+        }
+        assert_eq!(changed, vec!["./target/build/test_fanotify/a".to_string()]);
+        assert_eq!(
+            deleted,
+            vec!["./target/build/test_fanotify/sub/b".to_string()]
+        );
+        assert_eq!(created, vec!["./target/build/test_fanotify/d".to_string()]);
+        assert_eq!(watcher.snapshot().file_hashes.lock().unwrap().len(), 2);
+        fs::remove_dir_all(Path::new("./target/build/test_fanotify/")).unwrap();
+    }
+
+    #[test]
+    fn parses_dfid_name_records() {
+        let mut body = vec![0u8; FSID_SIZE];
+        body.extend(4u32.to_ne_bytes());
+        body.extend(1i32.to_ne_bytes());
+        body.extend([1, 2, 3, 4]);
+        body.extend(b"name\0\0\0");
+        let (handle, name) = parse_dfid_name(&body).unwrap();
+        assert_eq!(handle.len(), 12);
+        assert_eq!(name, OsStr::new("name"));
+        assert!(parse_dfid_name(&body[..10]).is_none());
+    }
+}
@@ -0,0 +1,903 @@
+use crate::archive::{resolve_links, Link};
+use crate::hasher::{hash_reader, HashType};
+use crate::snapshot::{FileAttributes, FileMetadata, Snapshot};
+use anyhow::{anyhow, Error};
+use flate2::read::ZlibDecoder;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// A git object id.
+pub type ObjectId = [u8; 20];
+
+const S_IFMT: u32 = 0o170000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFDIR: u32 = 0o040000;
+/// Submodule entries in a tree, which point at commits of another repository.
+const S_IFGITLINK: u32 = 0o160000;
+
+/// Deltas followed before giving up, git's own `pack.depth` limit.
+const MAX_DELTA_DEPTH: usize = 4095;
+/// Symbolic refs followed before giving up.
+const MAX_REF_DEPTH: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectKind {
+    Commit,
+    Tree,
+    Blob,
+    Tag,
+}
+
+impl ObjectKind {
+    fn from_name(name: &str) -> Result<ObjectKind, Error> {
+        match name {
+            "commit" => Ok(ObjectKind::Commit),
+            "tree" => Ok(ObjectKind::Tree),
+            "blob" => Ok(ObjectKind::Blob),
+            "tag" => Ok(ObjectKind::Tag),
+            _ => Err(anyhow!("unknown object type {name}")),
+        }
+    }
+
+    fn from_pack_type(kind: u8) -> Result<ObjectKind, Error> {
+        match kind {
+            1 => Ok(ObjectKind::Commit),
+            2 => Ok(ObjectKind::Tree),
+            3 => Ok(ObjectKind::Blob),
+            4 => Ok(ObjectKind::Tag),
+            _ => Err(anyhow!("unknown pack object type {kind}")),
+        }
+    }
+}
+
+fn parse_id(hex_id: &str) -> Result<ObjectId, Error> {
+    let mut id = [0u8; 20];
+    hex::decode_to_slice(hex_id, &mut id).map_err(|e| anyhow!("bad object id {hex_id}: {e}"))?;
+    Ok(id)
+}
+
+/// A pack file and its version 2 index.
+struct Pack {
+    path: PathBuf,
+    index: Vec<u8>,
+    count: usize,
+}
+
+impl Pack {
+    fn open(index_path: &Path) -> Result<Pack, Error> {
+        let index = fs::read(index_path)?;
+        if index.get(..8) != Some(&[0xff, 0x74, 0x4f, 0x63, 0, 0, 0, 2][..]) {
+            return Err(anyhow!(
+                "{}: unsupported pack index version",
+                index_path.display()
+            ));
+        }
+        let count = be32(&index, 8 + 255 * 4)? as usize;
+        // names, crc32s and offsets
+        if index.len() < 8 + 1024 + count * 28 {
+            return Err(anyhow!("{}: truncated pack index", index_path.display()));
+        }
+        Ok(Pack {
+            path: index_path.with_extension("pack"),
+            index,
+            count,
+        })
+    }
+
+    fn name(&self, position: usize) -> &[u8] {
+        let at = 8 + 1024 + position * 20;
+        &self.index[at..at + 20]
+    }
+
+    /// Positions of the names starting with `first`, from the fan-out table.
+    fn fanout_range(&self, first: u8) -> Result<(usize, usize), Error> {
+        let start = match first {
+            0 => 0,
+            first => be32(&self.index, 8 + (first as usize - 1) * 4)? as usize,
+        };
+        let end = be32(&self.index, 8 + first as usize * 4)? as usize;
+        Ok((start, end.min(self.count)))
+    }
+
+    fn find(&self, id: &ObjectId) -> Result<Option<u64>, Error> {
+        let (mut low, mut high) = self.fanout_range(id[0])?;
+        while low < high {
+            let middle = (low + high) / 2;
+            match self.name(middle).cmp(&id[..]) {
+                std::cmp::Ordering::Less => low = middle + 1,
+                std::cmp::Ordering::Greater => high = middle,
+                std::cmp::Ordering::Equal => return self.offset(middle).map(Some),
+            }
+        }
+        Ok(None)
+    }
+
+    fn offset(&self, position: usize) -> Result<u64, Error> {
+        let offsets = 8 + 1024 + self.count * 24;
+        let offset = be32(&self.index, offsets + position * 4)?;
+        if offset & 0x8000_0000 == 0 {
+            return Ok(offset as u64);
+        }
+        // offsets past 2 GiB live in a table of 64 bit offsets
+        let at = offsets + self.count * 4 + (offset & 0x7fff_ffff) as usize * 8;
+        let bytes = self
+            .index
+            .get(at..at + 8)
+            .ok_or_else(|| anyhow!("truncated pack index"))?;
+        Ok(u64::from_be_bytes(bytes.try_into()?))
+    }
+
+    fn ids_with_prefix(&self, prefix: &str) -> Result<Vec<ObjectId>, Error> {
+        let first = u8::from_str_radix(&prefix[..2], 16)?;
+        let (start, end) = self.fanout_range(first)?;
+        Ok((start..end)
+            .map(|position| self.name(position))
+            .filter(|name| hex::encode(name).starts_with(prefix))
+            .map(|name| name.try_into().expect("names are 20 bytes"))
+            .collect())
+    }
+}
+
+fn be32(bytes: &[u8], at: usize) -> Result<u32, Error> {
+    let slice = bytes
+        .get(at..at + 4)
+        .ok_or_else(|| anyhow!("truncated pack index"))?;
+    Ok(u32::from_be_bytes(slice.try_into()?))
+}
+
+fn read_byte(reader: &mut impl Read) -> Result<u8, Error> {
+    let mut byte = [0u8];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn inflate(reader: impl Read, size: usize) -> Result<Vec<u8>, Error> {
+    // the size comes from the object header, so let the buffer grow with the data instead
+    let mut data = Vec::new();
+    ZlibDecoder::new(reader)
+        .take(size as u64)
+        .read_to_end(&mut data)?;
+    if data.len() != size {
+        return Err(anyhow!("object is {} bytes, expected {size}", data.len()));
+    }
+    Ok(data)
+}
+
+/// Rebuild an object from its delta base and a delta, as stored in packs.
+fn apply_delta(base: &[u8], delta: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut at = 0;
+    let mut varint = || -> Result<usize, Error> {
+        let mut value = 0usize;
+        let mut shift = 0;
+        loop {
+            let byte = *delta.get(at).ok_or_else(|| anyhow!("truncated delta"))?;
+            at += 1;
+            if shift > 63 {
+                return Err(anyhow!("delta size varint too long"));
+            }
+            value |= ((byte & 0x7f) as usize) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+    };
+    let base_size = varint()?;
+    let size = varint()?;
+    if base_size != base.len() {
+        return Err(anyhow!(
+            "delta base is {} bytes, expected {base_size}",
+            base.len()
+        ));
+    }
+    let mut result = Vec::new();
+    let mut next = || -> Result<u8, Error> {
+        let byte = *delta.get(at).ok_or_else(|| anyhow!("truncated delta"))?;
+        at += 1;
+        Ok(byte)
+    };
+    while result.len() < size {
+        let op = next()?;
+        if op & 0x80 != 0 {
+            // copy from the base, offset and size bytes present as flagged
+            let mut offset = 0usize;
+            let mut length = 0usize;
+            for bit in 0..4 {
+                if op & (1 << bit) != 0 {
+                    offset |= (next()? as usize) << (bit * 8);
+                }
+            }
+            for bit in 0..3 {
+                if op & (0x10 << bit) != 0 {
+                    length |= (next()? as usize) << (bit * 8);
+                }
+            }
+            if length == 0 {
+                length = 0x10000;
+            }
+            let copied = base
+                .get(offset..offset + length)
+                .ok_or_else(|| anyhow!("delta copies past its base"))?;
+            result.extend_from_slice(copied);
+        } else if op != 0 {
+            for _ in 0..op {
+                result.push(next()?);
+            }
+        } else {
+            return Err(anyhow!("reserved delta instruction"));
+        }
+    }
+    if result.len() != size {
+        return Err(anyhow!(
+            "delta result is {} bytes, expected {size}",
+            result.len()
+        ));
+    }
+    Ok(result)
+}
+
+/// A local repository, read straight from its object database without running git.
+pub struct Repository {
+    git_dir: PathBuf,
+    /// Where objects and shared refs live; differs from `git_dir` in linked worktrees.
+    common_dir: PathBuf,
+    packs: Vec<Pack>,
+}
+
+impl Repository {
+    /// Open the repository at `path`, either a work tree or a bare repository.
+    pub fn open(path: &Path) -> Result<Repository, Error> {
+        let dot_git = path.join(".git");
+        let git_dir = if dot_git.is_dir() {
+            dot_git
+        } else if dot_git.is_file() {
+            // a linked worktree or submodule: "gitdir: <path>"
+            let contents = fs::read_to_string(&dot_git)?;
+            let target = contents
+                .trim()
+                .strip_prefix("gitdir: ")
+                .ok_or_else(|| anyhow!("{}: not a gitdir file", dot_git.display()))?;
+            path.join(target)
+        } else if path.join("objects").is_dir() && path.join("HEAD").is_file() {
+            path.to_path_buf()
+        } else {
+            return Err(anyhow!("{} is not a git repository", path.display()));
+        };
+        let common_dir = match fs::read_to_string(git_dir.join("commondir")) {
+            Ok(common_dir) => git_dir.join(common_dir.trim()),
+            Err(_) => git_dir.clone(),
+        };
+
+        let mut packs = vec![];
+        if let Ok(dir) = fs::read_dir(common_dir.join("objects/pack")) {
+            for entry in dir.flatten() {
+                let path = entry.path();
+                if path.extension().is_some_and(|extension| extension == "idx") {
+                    packs.push(Pack::open(&path)?);
+                }
+            }
+        }
+        Ok(Repository {
+            git_dir,
+            common_dir,
+            packs,
+        })
+    }
+
+    /// Resolve `rev` to an object id: a full or abbreviated id, `HEAD`, or a branch, tag or
+    /// remote ref, looked up in the order `git rev-parse` uses.
+    pub fn resolve(&self, rev: &str) -> Result<ObjectId, Error> {
+        if rev.len() == 40 && rev.chars().all(|c| c.is_ascii_hexdigit()) {
+            return parse_id(rev);
+        }
+        for name in [
+            rev.to_string(),
+            format!("refs/{rev}"),
+            format!("refs/tags/{rev}"),
+            format!("refs/heads/{rev}"),
+            format!("refs/remotes/{rev}"),
+            format!("refs/remotes/{rev}/HEAD"),
+        ] {
+            if let Some(id) = self.read_ref(&name, 0)? {
+                return Ok(id);
+            }
+        }
+        if rev.len() >= 4 && rev.chars().all(|c| c.is_ascii_hexdigit()) {
+            let mut ids = self.ids_with_prefix(&rev.to_ascii_lowercase())?;
+            ids.sort();
+            ids.dedup();
+            return match ids.as_slice() {
+                [id] => Ok(*id),
+                [] => Err(anyhow!("unknown revision {rev}")),
+                _ => Err(anyhow!("ambiguous revision {rev}")),
+            };
+        }
+        Err(anyhow!("unknown revision {rev}"))
+    }
+
+    fn read_ref(&self, name: &str, depth: usize) -> Result<Option<ObjectId>, Error> {
+        if depth > MAX_REF_DEPTH {
+            return Err(anyhow!("too many levels of symbolic refs at {name}"));
+        }
+        if name
+            .split('/')
+            .any(|component| component == ".." || component.is_empty())
+        {
+            return Ok(None);
+        }
+        for dir in [&self.git_dir, &self.common_dir] {
+            let path = dir.join(name);
+            if !path.is_file() {
+                continue;
+            }
+            let contents = fs::read_to_string(&path)?;
+            let contents = contents.trim();
+            return match contents.strip_prefix("ref: ") {
+                Some(target) => self.read_ref(target, depth + 1),
+                None => parse_id(contents).map(Some),
+            };
+        }
+        let packed = fs::read_to_string(self.common_dir.join("packed-refs")).unwrap_or_default();
+        for line in packed.lines() {
+            if line.starts_with('#') || line.starts_with('^') {
+                continue;
+            }
+            if let Some((id, ref_name)) = line.split_once(' ') {
+                if ref_name == name {
+                    return parse_id(id).map(Some);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn ids_with_prefix(&self, prefix: &str) -> Result<Vec<ObjectId>, Error> {
+        let mut ids = vec![];
+        let loose = self.common_dir.join("objects").join(&prefix[..2]);
+        if let Ok(dir) = fs::read_dir(loose) {
+            for entry in dir.flatten() {
+                let name = format!("{}{}", &prefix[..2], entry.file_name().to_string_lossy());
+                if name.len() == 40 && name.starts_with(prefix) {
+                    ids.push(parse_id(&name)?);
+                }
+            }
+        }
+        for pack in &self.packs {
+            ids.extend(pack.ids_with_prefix(prefix)?);
+        }
+        Ok(ids)
+    }
+
+    /// Read an object, loose or packed, returning its type and contents.
+    pub fn read_object(&self, id: &ObjectId) -> Result<(ObjectKind, Vec<u8>), Error> {
+        self.read_object_at_depth(id, 0)
+    }
+
+    fn read_object_at_depth(
+        &self,
+        id: &ObjectId,
+        depth: usize,
+    ) -> Result<(ObjectKind, Vec<u8>), Error> {
+        let hex_id = hex::encode(id);
+        let loose = self
+            .common_dir
+            .join("objects")
+            .join(&hex_id[..2])
+            .join(&hex_id[2..]);
+        if let Ok(file) = File::open(&loose) {
+            let mut contents = vec![];
+            ZlibDecoder::new(BufReader::new(file)).read_to_end(&mut contents)?;
+            let nul = contents
+                .iter()
+                .position(|b| *b == 0)
+                .ok_or_else(|| anyhow!("object {hex_id} has no header"))?;
+            let header = String::from_utf8_lossy(&contents[..nul]).to_string();
+            let (kind, size) = header
+                .split_once(' ')
+                .ok_or_else(|| anyhow!("object {hex_id} has a bad header"))?;
+            let data = contents.split_off(nul + 1);
+            if size.parse::<usize>().ok() != Some(data.len()) {
+                return Err(anyhow!("object {hex_id} is truncated"));
+            }
+            return Ok((ObjectKind::from_name(kind)?, data));
+        }
+        for pack in &self.packs {
+            if let Some(offset) = pack.find(id)? {
+                return self
+                    .read_packed(pack, offset, depth)
+                    .map_err(|e| anyhow!("object {hex_id}: {e}"));
+            }
+        }
+        Err(anyhow!("object {hex_id} not found"))
+    }
+
+    fn read_packed(
+        &self,
+        pack: &Pack,
+        offset: u64,
+        depth: usize,
+    ) -> Result<(ObjectKind, Vec<u8>), Error> {
+        if depth > MAX_DELTA_DEPTH {
+            return Err(anyhow!("delta chain too long"));
+        }
+        let mut reader = BufReader::new(File::open(&pack.path)?);
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut byte = read_byte(&mut reader)?;
+        let kind = (byte >> 4) & 7;
+        let mut size = (byte & 0x0f) as usize;
+        let mut shift = 4;
+        while byte & 0x80 != 0 {
+            byte = read_byte(&mut reader)?;
+            if shift > 63 {
+                return Err(anyhow!("object size varint too long"));
+            }
+            size |= ((byte & 0x7f) as usize) << shift;
+            shift += 7;
+        }
+        match kind {
+            // offset delta: the base sits a variable length distance before this object
+            6 => {
+                let mut byte = read_byte(&mut reader)?;
+                let mut distance = (byte & 0x7f) as u64;
+                while byte & 0x80 != 0 {
+                    byte = read_byte(&mut reader)?;
+                    distance = ((distance + 1) << 7) | (byte & 0x7f) as u64;
+                }
+                let base_offset = offset
+                    .checked_sub(distance)
+                    .ok_or_else(|| anyhow!("delta base before the start of the pack"))?;
+                let delta = inflate(reader, size)?;
+                let (kind, base) = self.read_packed(pack, base_offset, depth + 1)?;
+                Ok((kind, apply_delta(&base, &delta)?))
+            }
+            // ref delta: the base is named by its id
+            7 => {
+                let mut base_id = [0u8; 20];
+                reader.read_exact(&mut base_id)?;
+                let delta = inflate(reader, size)?;
+                let (kind, base) = self.read_object_at_depth(&base_id, depth + 1)?;
+                Ok((kind, apply_delta(&base, &delta)?))
+            }
+            kind => Ok((ObjectKind::from_pack_type(kind)?, inflate(reader, size)?)),
+        }
+    }
+
+    /// Peel `id` through tags and commits to a tree, returning it and the committer time of the
+    /// commit it came from, if any.
+    pub fn peel_to_tree(&self, id: &ObjectId) -> Result<(ObjectId, Option<i64>), Error> {
+        let mut id = *id;
+        for _ in 0..MAX_REF_DEPTH {
+            let (kind, data) = self.read_object(&id)?;
+            let text = String::from_utf8_lossy(&data);
+            match kind {
+                ObjectKind::Tree => return Ok((id, None)),
+                ObjectKind::Blob => return Err(anyhow!("{} is a blob", hex::encode(id))),
+                ObjectKind::Tag => {
+                    let target = text
+                        .lines()
+                        .find_map(|line| line.strip_prefix("object "))
+                        .ok_or_else(|| anyhow!("tag {} has no object", hex::encode(id)))?;
+                    id = parse_id(target)?;
+                }
+                ObjectKind::Commit => {
+                    let tree = text
+                        .lines()
+                        .find_map(|line| line.strip_prefix("tree "))
+                        .ok_or_else(|| anyhow!("commit {} has no tree", hex::encode(id)))?;
+                    // committer Name <email> 1700000000 +0000
+                    let time = text
+                        .lines()
+                        .take_while(|line| !line.is_empty())
+                        .find_map(|line| line.strip_prefix("committer "))
+                        .and_then(|line| line.rsplit(' ').nth(1)?.parse().ok());
+                    return Ok((parse_id(tree)?, time));
+                }
+            }
+        }
+        Err(anyhow!("too many levels of tags at {}", hex::encode(id)))
+    }
+
+    /// The entries of tree `id` as `(mode, name, id)`.
+    pub fn read_tree(&self, id: &ObjectId) -> Result<Vec<(u32, String, ObjectId)>, Error> {
+        let (kind, data) = self.read_object(id)?;
+        if kind != ObjectKind::Tree {
+            return Err(anyhow!("{} is not a tree", hex::encode(id)));
+        }
+        let mut entries = vec![];
+        let mut rest = &data[..];
+        while !rest.is_empty() {
+            let malformed = || anyhow!("malformed tree {}", hex::encode(id));
+            let space = rest.iter().position(|b| *b == b' ').ok_or_else(malformed)?;
+            let nul = rest.iter().position(|b| *b == 0).ok_or_else(malformed)?;
+            let mode = std::str::from_utf8(&rest[..space]).map_err(|_| malformed())?;
+            let mode = u32::from_str_radix(mode, 8).map_err(|_| malformed())?;
+            let name = String::from_utf8_lossy(&rest[space + 1..nul]).to_string();
+            let entry_id = rest.get(nul + 1..nul + 21).ok_or_else(malformed)?;
+            entries.push((mode, name, entry_id.try_into()?));
+            rest = &rest[nul + 21..];
+        }
+        Ok(entries)
+    }
+
+    /// Every blob below tree `id` as `(path, mode, id)`, paths relative to the tree. Submodules
+    /// are skipped.
+    fn walk(
+        &self,
+        id: &ObjectId,
+        prefix: &str,
+        out: &mut Vec<(String, u32, ObjectId)>,
+    ) -> Result<(), Error> {
+        for (mode, name, entry_id) in self.read_tree(id)? {
+            let path = format!("{prefix}{name}");
+            match mode & S_IFMT {
+                S_IFDIR => self.walk(&entry_id, &format!("{path}/"), out)?,
+                S_IFGITLINK => continue,
+                _ => out.push((path, mode, entry_id)),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GitOptions {
+    /// Commit, tag, branch or tree to read.
+    pub rev: String,
+    /// Directory of the repository that maps to the snapshot root, the whole tree when `None`.
+    pub subdir: Option<String>,
+}
+
+impl Default for GitOptions {
+    fn default() -> Self {
+        GitOptions {
+            rev: "HEAD".to_string(),
+            subdir: None,
+        }
+    }
+}
+
+/// A snapshot of the files at `options.rev` in the repository at `repo`, as if
+/// `options.subdir` were checked out to `root_path`, for comparing with the deployed directory
+/// by [`crate::snapshot::compare_hashes`]. With [`HashType::GITSHA1`] the digests are the blob
+/// ids from the trees, so no blob needs hashing. Entries take the committer time as their
+/// mtime, and symlinks resolve like in [`crate::archive::tar_entries`].
+pub fn git_snapshot(
+    repo: &Path,
+    root_path: &Path,
+    hash_type: HashType,
+    options: &GitOptions,
+    verbose: bool,
+) -> Result<Snapshot, Error> {
+    let root_path = root_path
+        .to_str()
+        .ok_or_else(|| anyhow!("cannot parse path"))?;
+    let repository = Repository::open(repo)?;
+    let (mut tree, commit_time) = repository.peel_to_tree(&repository.resolve(&options.rev)?)?;
+    let subdir = options.subdir.as_deref().unwrap_or_default();
+    for component in subdir.split('/').filter(|c| !c.is_empty() && *c != ".") {
+        tree = repository
+            .read_tree(&tree)?
+            .into_iter()
+            .find(|(mode, name, _)| mode & S_IFMT == S_IFDIR && name == component)
+            .map(|(_, _, id)| id)
+            .ok_or_else(|| anyhow!("{subdir} is not a directory in {}", options.rev))?;
+    }
+    let mut blobs = vec![];
+    repository.walk(&tree, "", &mut blobs)?;
+
+    let root = root_path.trim_end_matches('/');
+    let mut files: HashMap<String, FileMetadata> = HashMap::new();
+    let mut links: HashMap<String, (Link, FileAttributes)> = HashMap::new();
+    for (path, mode, id) in blobs {
+        let (kind, data) = repository.read_object(&id)?;
+        if kind != ObjectKind::Blob {
+            return Err(anyhow!("{path} is not a blob"));
+        }
+        let mut attributes = FileAttributes {
+            mode,
+            ..Default::default()
+        };
+        if mode & S_IFMT == S_IFLNK {
+            let target = String::from_utf8_lossy(&data).to_string();
+            attributes.link = Some(target.clone());
+            links.insert(path, (Link::Symbolic(target), attributes));
+            continue;
+        }
+        if mode & S_IFMT != S_IFREG {
+            continue;
+        }
+        if verbose {
+            eprintln!("{path}");
+        }
+        let check_sum = match hash_type {
+            // the blob id already is the digest
+            HashType::GITSHA1 => id.to_vec(),
+            hash_type => hash_reader(&data[..], hash_type)?.0,
+        };
+        files.insert(
+            path.clone(),
+            FileMetadata {
+                path: format!("{root}/{path}"),
+                check_sum,
+                size: data.len() as u64,
+                mtime: commit_time.unwrap_or(0),
+                attributes: Some(attributes),
+                ..Default::default()
+            },
+        );
+    }
+    let entries = resolve_links(root, files, &links, verbose);
+    Ok(Snapshot::from_entries(root_path, hash_type, entries))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hasher::git_blob_id;
+    use crate::snapshot::compare_hashes;
+    use flate2::write::ZlibEncoder;
+    use std::io::Write;
+
+    fn compress(data: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn object_id(kind: &str, data: &[u8]) -> ObjectId {
+        let mut hasher = <sha1::Sha1 as sha1::Digest>::new();
+        sha1::Digest::update(&mut hasher, format!("{kind} {}\0", data.len()));
+        sha1::Digest::update(&mut hasher, data);
+        sha1::Digest::finalize(hasher).into()
+    }
+
+    fn write_loose(git_dir: &Path, kind: &str, data: &[u8]) -> ObjectId {
+        let id = object_id(kind, data);
+        let hex_id = hex::encode(id);
+        let dir = git_dir.join("objects").join(&hex_id[..2]);
+        fs::create_dir_all(&dir).unwrap();
+        let mut raw = format!("{kind} {}\0", data.len()).into_bytes();
+        raw.extend(data);
+        fs::write(dir.join(&hex_id[2..]), compress(&raw)).unwrap();
+        id
+    }
+
+    fn tree(entries: &[(&str, &str, ObjectId)]) -> Vec<u8> {
+        let mut data = vec![];
+        for (mode, name, id) in entries {
+            data.extend(format!("{mode} {name}\0").as_bytes());
+            data.extend(id);
+        }
+        data
+    }
+
+    /// A delta that keeps the first `keep` bytes of `base` and appends `suffix`.
+    fn delta(base: &[u8], keep: usize, suffix: &[u8]) -> Vec<u8> {
+        let varint = |mut value: usize, out: &mut Vec<u8>| loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(byte);
+                break;
+            }
+            out.push(byte | 0x80);
+        };
+        let mut delta = vec![];
+        varint(base.len(), &mut delta);
+        varint(keep + suffix.len(), &mut delta);
+        // copy from offset 0, one size byte
+        delta.extend([0x80 | 0x10, keep as u8]);
+        delta.push(suffix.len() as u8);
+        delta.extend(suffix);
+        delta
+    }
+
+    /// Write a pack holding `base`, an offset delta and a ref delta on it, and its index.
+    fn write_pack(git_dir: &Path, base: &[u8], ofs: &[u8], rf: &[u8]) {
+        let header = |kind: u8, size: usize, out: &mut Vec<u8>| {
+            let mut byte = (kind << 4) | (size & 0x0f) as u8;
+            let mut size = size >> 4;
+            while size > 0 {
+                out.push(byte | 0x80);
+                byte = (size & 0x7f) as u8;
+                size >>= 7;
+            }
+            out.push(byte);
+        };
+        let mut pack = b"PACK\0\0\0\x02\0\0\0\x03".to_vec();
+        let base_offset = pack.len();
+        header(3, base.len(), &mut pack);
+        pack.extend(compress(base));
+
+        let ofs_offset = pack.len();
+        let ofs_delta = delta(base, 10, &ofs[10..]);
+        header(6, ofs_delta.len(), &mut pack);
+        // distances below 128 take a single byte
+        pack.push((ofs_offset - base_offset) as u8);
+        pack.extend(compress(&ofs_delta));
+
+        let ref_offset = pack.len();
+        let ref_delta = delta(base, 5, &rf[5..]);
+        header(7, ref_delta.len(), &mut pack);
+        pack.extend(object_id("blob", base));
+        pack.extend(compress(&ref_delta));
+        pack.extend([0u8; 20]);
+
+        let mut objects = [
+            (object_id("blob", base), base_offset),
+            (object_id("blob", ofs), ofs_offset),
+            (object_id("blob", rf), ref_offset),
+        ];
+        objects.sort();
+        let mut index = vec![0xff, 0x74, 0x4f, 0x63, 0, 0, 0, 2];
+        for first in 0..=255u8 {
+            let count = objects.iter().filter(|(id, _)| id[0] <= first).count();
+            index.extend((count as u32).to_be_bytes());
+        }
+        objects.iter().for_each(|(id, _)| index.extend(id));
+        objects.iter().for_each(|_| index.extend([0u8; 4]));
+        objects
+            .iter()
+            .for_each(|(_, offset)| index.extend((*offset as u32).to_be_bytes()));
+        index.extend([0u8; 40]);
+
+        let pack_dir = git_dir.join("objects/pack");
+        fs::create_dir_all(&pack_dir).unwrap();
+        fs::write(pack_dir.join("pack-test.pack"), pack).unwrap();
+        fs::write(pack_dir.join("pack-test.idx"), index).unwrap();
+    }
+
+    #[test]
+    fn applies_deltas() {
+        let base = b"0123456789abcdef";
+        assert_eq!(
+            apply_delta(base, &delta(base, 4, b"xy")).unwrap(),
+            b"0123xy"
+        );
+        assert!(apply_delta(b"short", &delta(base, 4, b"xy")).is_err());
+        // a size varint running past 64 bits
+        let mut overlong = vec![0x10];
+        overlong.extend([0xff; 10]);
+        overlong.push(0x01);
+        assert!(apply_delta(base, &overlong).is_err());
+    }
+
+    #[test]
+    fn snapshots_match_checked_out_trees() {
+        let dir = Path::new("./target/build/test_git");
+        assert!(!dir.exists());
+        let repo = dir.join("repo");
+        let git_dir = repo.join(".git");
+
+        let base = "server=a\n".repeat(10);
+        let ofs = format!("{}port=8080\n", &base[..10]);
+        let rf = format!("{}name=b\n", &base[..5]);
+        write_pack(&git_dir, base.as_bytes(), ofs.as_bytes(), rf.as_bytes());
+        let conf = write_loose(&git_dir, "blob", b"a=1\n");
+        let link = write_loose(&git_dir, "blob", b"app.conf");
+        let app = write_loose(
+            &git_dir,
+            "tree",
+            &tree(&[
+                ("100644", "app.conf", conf),
+                ("100755", "base.conf", object_id("blob", base.as_bytes())),
+                ("120000", "current.conf", link),
+                ("100644", "ofs.conf", object_id("blob", ofs.as_bytes())),
+                ("100644", "ref.conf", object_id("blob", rf.as_bytes())),
+            ]),
+        );
+        let readme = write_loose(&git_dir, "blob", b"docs\n");
+        let root_tree = write_loose(
+            &git_dir,
+            "tree",
+            &tree(&[("100644", "README", readme), ("40000", "app", app)]),
+        );
+        let commit = write_loose(
+            &git_dir,
+            "commit",
+            format!(
+                "tree {}\nauthor A <a@example.com> 1600000000 +0000\n\
+                 committer C <c@example.com> 1700000000 +0100\n\nconfig\n",
+                hex::encode(root_tree)
+            )
+            .as_bytes(),
+        );
+        let tag = write_loose(
+            &git_dir,
+            "tag",
+            format!(
+                "object {}\ntype commit\ntag v1\ntagger C <c@example.com> 1700000000 +0000\n\nv1\n",
+                hex::encode(commit)
+            )
+            .as_bytes(),
+        );
+        fs::create_dir_all(git_dir.join("refs/heads")).unwrap();
+        fs::write(git_dir.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+        fs::write(
+            git_dir.join("refs/heads/main"),
+            format!("{}\n", hex::encode(commit)),
+        )
+        .unwrap();
+        fs::write(
+            git_dir.join("packed-refs"),
+            format!(
+                "# pack-refs with: peeled\n{} refs/tags/v1\n^{}\n",
+                hex::encode(tag),
+                hex::encode(commit)
+            ),
+        )
+        .unwrap();
+
+        // the deployed directory
+        let root = dir.join("etc/app");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("app.conf"), "a=1\n").unwrap();
+        fs::write(root.join("base.conf"), &base).unwrap();
+        fs::write(root.join("ofs.conf"), &ofs).unwrap();
+        fs::write(root.join("ref.conf"), &rf).unwrap();
+        std::os::unix::fs::symlink("app.conf", root.join("current.conf")).unwrap();
+
+        let repository = Repository::open(&repo).unwrap();
+        assert_eq!(repository.resolve("HEAD").unwrap(), commit);
+        assert_eq!(repository.resolve("v1").unwrap(), tag);
+        assert_eq!(repository.resolve("main").unwrap(), commit);
+        assert_eq!(
+            repository.resolve(&hex::encode(commit)[..8]).unwrap(),
+            commit
+        );
+        assert!(repository.resolve("missing").is_err());
+        assert_eq!(
+            repository.peel_to_tree(&tag).unwrap(),
+            (root_tree, Some(1700000000))
+        );
+        let (kind, data) = repository
+            .read_object(&object_id("blob", rf.as_bytes()))
+            .unwrap();
+        assert_eq!((kind, data), (ObjectKind::Blob, rf.clone().into_bytes()));
+
+        for hash_type in [HashType::GITSHA1, HashType::SHA256] {
+            let disk = Snapshot::new(&root, hash_type, vec![], false).unwrap();
+            for rev in ["HEAD", "v1", &hex::encode(root_tree)] {
+                let options = GitOptions {
+                    rev: rev.to_string(),
+                    subdir: Some("app".to_string()),
+                };
+                let snapshot = git_snapshot(&repo, &root, hash_type, &options, false).unwrap();
+                assert_eq!(snapshot.file_hashes.lock().unwrap().len(), 5);
+                let (_, result) = compare_hashes(snapshot, disk.clone(), false).unwrap();
+                assert!(result.created.is_empty(), "{rev}: {result:?}");
+                assert!(result.deleted.is_empty() && result.changed.is_empty());
+            }
+        }
+
+        let snapshot = git_snapshot(
+            &repo,
+            &root,
+            HashType::GITSHA1,
+            &GitOptions::default(),
+            false,
+        )
+        .unwrap();
+        let file_hashes = snapshot.file_hashes.lock().unwrap();
+        // without a subdir the whole tree maps to the root
+        let conf_entry = &file_hashes[root.join("app/app.conf").to_str().unwrap()];
+        assert_eq!(conf_entry.check_sum, git_blob_id(b"a=1\n"));
+        assert_eq!(conf_entry.mtime, 1700000000);
+        let current = &file_hashes[root.join("app/current.conf").to_str().unwrap()];
+        let attributes = current.attributes.clone().unwrap();
+        assert_eq!(attributes.mode, 0o120000);
+        assert_eq!(attributes.link.as_deref(), Some("app.conf"));
+        drop(file_hashes);
+
+        let options = GitOptions {
+            subdir: Some("README".to_string()),
+            ..Default::default()
+        };
+        assert!(git_snapshot(&repo, &root, HashType::GITSHA1, &options, false).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
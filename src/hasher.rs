@@ -1,6 +1,7 @@
 use crate::snapshot::FileMetadata;
 use anyhow::{anyhow, Error};
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 use sha3::{Digest, Sha3_256};
 use std::collections::HashMap;
 use std::fs::File;
@@ -10,11 +11,15 @@ use std::path::Path;
 use std::sync::MutexGuard;
 use std::{env, fs};
 
-#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub enum HashType {
     MD5,
     SHA3,
     BLAKE3,
+    SHA256,
+    /// Git's blob id, the SHA-1 of `blob <size>\0` followed by the contents, so digests match
+    /// `git hash-object` and the ids in a repository's trees.
+    GITSHA1,
 }
 
 pub struct HashResult {
@@ -24,13 +29,19 @@ pub struct HashResult {
     pub ctime: i64,
     pub mtime: i64,
 }
-#[allow(unused)]
 pub fn hash_file(
     path: &Path,
     file_hashes: &mut MutexGuard<HashMap<String, FileMetadata>>,
     hash_type: HashType,
     verbose: bool,
 ) -> Result<(), Error> {
+    let metadata = hash_path(path, hash_type, verbose)?;
+    file_hashes.insert(metadata.path.clone(), metadata);
+    Ok(())
+}
+
+/// Hash a single file, returning its snapshot entry keyed by `path` as given.
+pub fn hash_path(path: &Path, hash_type: HashType, verbose: bool) -> Result<FileMetadata, Error> {
     let mut full_path = String::new();
     if path.starts_with("./") {
         if let Ok(cwd) = env::current_dir() {
@@ -77,40 +88,104 @@ pub fn hash_file(
         ino = metadata.ino();
     }
 
-    let mut file_hash: Vec<u8> = Vec::new();
-    let mut file_buffer: Vec<u8> = Vec::new();
-
     if let Some(p) =path.to_str() {
         if verbose {
-            println!("{}", p)
+            eprintln!("{}", p)
         }
     }
-    
+
     let byte_hash: Result<Vec<u8>, Error> = match hash_type {
         HashType::MD5 => hash_md5(path),
         HashType::SHA3 => hash_sha3(path),
         HashType::BLAKE3 => hash_blake3(path),
+        HashType::SHA256 => hash_sha256(path),
+        HashType::GITSHA1 => hash_git_blob(path),
     };
 
     match path.to_str() {
-        None => return Err(anyhow!("cannot parse path")),
-        Some(p) => {
-            file_hashes.insert(
-                p.to_string(),
-                FileMetadata {
-                    path: p.to_string(),
-                    check_sum: byte_hash?,
-                    size,
-                    ino,
-                    ctime,
-                    mtime,
-                },
-            );
-        }
+        None => Err(anyhow!("cannot parse path")),
+        Some(p) => Ok(FileMetadata {
+            path: p.to_string(),
+            check_sum: byte_hash?,
+            size,
+            ino,
+            ctime,
+            mtime,
+            mac: None,
+            attributes: None,
+        }),
+    }
+}
+
+/// Hash everything `reader` yields, such as an archive member, returning the digest and the
+/// number of bytes read. Git blob ids need the size up front, so with [`HashType::GITSHA1`] the
+/// contents are buffered; use [`hash_reader_sized`] when the size is known.
+pub fn hash_reader(mut reader: impl Read, hash_type: HashType) -> Result<(Vec<u8>, u64), Error> {
+    if hash_type == HashType::GITSHA1 {
+        let mut contents = vec![];
+        reader.read_to_end(&mut contents)?;
+        return Ok((git_blob_id(&contents).to_vec(), contents.len() as u64));
     }
+    hash_stream(reader, None, hash_type)
+}
 
-    drop(file_hash);
-    Ok(())
+/// Like [`hash_reader`] for a reader that yields exactly `size` bytes, streaming git blobs
+/// rather than buffering them. Fails when the reader yields a different number of bytes.
+pub fn hash_reader_sized(
+    reader: impl Read,
+    size: u64,
+    hash_type: HashType,
+) -> Result<(Vec<u8>, u64), Error> {
+    let (check_sum, read) = hash_stream(reader, Some(size), hash_type)?;
+    if read != size {
+        return Err(anyhow!("read {read} bytes, expected {size}"));
+    }
+    Ok((check_sum, read))
+}
+
+/// Stream `reader` through the hasher of `hash_type`. Git blobs are only hashed correctly when
+/// `size` is given.
+fn hash_stream(
+    mut reader: impl Read,
+    size: Option<u64>,
+    hash_type: HashType,
+) -> Result<(Vec<u8>, u64), Error> {
+    let mut md5 = md5::Context::new();
+    let mut sha3 = Sha3_256::new();
+    let mut blake3 = blake3::Hasher::new();
+    let mut sha256 = <Sha256 as sha2::Digest>::new();
+    let mut git_blob = <sha1::Sha1 as sha1::Digest>::new();
+    if let Some(size) = size {
+        sha1::Digest::update(&mut git_blob, format!("blob {size}\0"));
+    }
+    let mut chunk = vec![0u8; 0x4000];
+    let mut read = 0u64;
+    loop {
+        let n = match reader.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        read += n as u64;
+        match hash_type {
+            HashType::MD5 => md5.consume(&chunk[..n]),
+            HashType::SHA3 => sha3::digest::Update::update(&mut sha3, &chunk[..n]),
+            HashType::BLAKE3 => {
+                blake3.update(&chunk[..n]);
+            }
+            HashType::SHA256 => sha2::Digest::update(&mut sha256, &chunk[..n]),
+            HashType::GITSHA1 => sha1::Digest::update(&mut git_blob, &chunk[..n]),
+        }
+    }
+    let check_sum = match hash_type {
+        HashType::MD5 => md5.compute().0.to_vec(),
+        HashType::SHA3 => sha3.finalize().to_vec(),
+        HashType::BLAKE3 => blake3.finalize().as_bytes().to_vec(),
+        HashType::SHA256 => sha2::Digest::finalize(sha256).to_vec(),
+        HashType::GITSHA1 => sha1::Digest::finalize(git_blob).to_vec(),
+    };
+    Ok((check_sum, read))
 }
 
 fn hash_sha3(bytes: &Path) -> Result<Vec<u8>, Error> {
@@ -188,6 +263,53 @@ fn hash_blake3(bytes: &Path) -> Result<Vec<u8>, Error> {
     Ok(hasher.finalize().as_bytes().to_vec())
 }
 
+fn hash_sha256(bytes: &Path) -> Result<Vec<u8>, Error> {
+    let mut hasher = <Sha256 as sha2::Digest>::new();
+    if let Ok(mut f) = File::open(bytes) {
+        let chunk_size = 0x4000;
+        if let Ok(meta) = f.metadata() {
+            if meta.is_file() {
+                loop {
+                    let mut chunk = Vec::with_capacity(chunk_size);
+                    let n = std::io::Read::by_ref(&mut f)
+                        .take(chunk_size as u64)
+                        .read_to_end(&mut chunk)?;
+                    if n == 0 {
+                        break;
+                    }
+                    sha2::Digest::update(&mut hasher, &chunk);
+                    if n < chunk_size {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    Ok(sha2::Digest::finalize(hasher).to_vec())
+}
+
+/// The git object id of a blob holding `contents`.
+pub fn git_blob_id(contents: &[u8]) -> [u8; 20] {
+    let mut hasher = <sha1::Sha1 as sha1::Digest>::new();
+    sha1::Digest::update(&mut hasher, format!("blob {}\0", contents.len()));
+    sha1::Digest::update(&mut hasher, contents);
+    sha1::Digest::finalize(hasher).into()
+}
+
+fn hash_git_blob(bytes: &Path) -> Result<Vec<u8>, Error> {
+    if let Ok(mut f) = File::open(bytes) {
+        if let Ok(meta) = f.metadata() {
+            if meta.is_file() {
+                // the header carries the size, so a file changing while it is read is an error
+                let (check_sum, _) = hash_reader_sized(&mut f, meta.len(), HashType::GITSHA1)
+                    .map_err(|e| anyhow!("{}: {e}", bytes.display()))?;
+                return Ok(check_sum);
+            }
+        }
+    }
+    Ok(git_blob_id(&[]).to_vec())
+}
+
 #[cfg(test)]
 mod tests {
     use sha3::Digest;
@@ -235,4 +357,32 @@ mod tests {
             ]
         )
     }
+
+    #[test]
+    fn git_blob() {
+        // git hash-object of a file holding "hello\n"
+        assert_eq!(
+            hex::encode(super::git_blob_id(b"hello\n")),
+            "ce013625030ba8dba906f756967f9e9ca394464a"
+        );
+        let (check_sum, size) =
+            super::hash_reader(&b"hello\n"[..], super::HashType::GITSHA1).unwrap();
+        assert_eq!(check_sum, super::git_blob_id(b"hello\n"));
+        assert_eq!(size, 6);
+        let (check_sum, size) =
+            super::hash_reader_sized(&b"hello\n"[..], 6, super::HashType::GITSHA1).unwrap();
+        assert_eq!(check_sum, super::git_blob_id(b"hello\n"));
+        assert_eq!(size, 6);
+        assert!(super::hash_reader_sized(&b"hello\n"[..], 7, super::HashType::GITSHA1).is_err());
+    }
+
+    #[test]
+    fn sha256() {
+        let test_string = "abc".as_bytes();
+        let hashed = <sha2::Sha256 as sha2::Digest>::digest(test_string).to_vec();
+        assert_eq!(
+            hex::encode(hashed),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        )
+    }
 }
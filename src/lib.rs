@@ -3,12 +3,40 @@ extern crate core;
 
 use crate::hasher::HashType;
 use crate::snapshot::{
-    compare_hashes, export, import, Snapshot, SnapshotChangeType, SnapshotCompareResult,
+    compare_hashes, export, export_with_options, import, import_with_options, ExportOptions,
+    ImportOptions, Snapshot, SnapshotChangeType, SnapshotCompareResult,
 };
+use crate::verify::{verify, verify_paths, VerifyEvent, VerifyOptions, VerifyReport};
 use anyhow::Error;
 use std::path::Path;
+pub mod archive;
+pub mod ci;
+#[cfg(target_os = "linux")]
+pub mod daemon;
+pub mod delta;
+pub mod dpkg;
+pub mod encryption;
+#[cfg(target_os = "linux")]
+pub mod fanotify;
+pub mod git;
 pub mod hasher;
+pub mod mac;
+pub mod manifest;
+pub mod merkle;
+pub mod mtree;
+pub mod report;
+pub mod rpm;
+pub mod siem;
+pub mod signing;
 pub mod snapshot;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod store;
+pub mod syslog;
+pub mod util;
+pub mod verify;
+#[cfg(target_os = "linux")]
+pub mod watch;
 
 pub fn create_snapshot(
     path: &str,
@@ -39,6 +67,48 @@ pub fn export_snapshot(snapshot: Snapshot, path: String, overwrite: bool, verbos
     export(snapshot, path, overwrite, verbose)
 }
 
+pub fn export_snapshot_with_options(
+    snapshot: Snapshot,
+    path: String,
+    options: &ExportOptions,
+    verbose: bool,
+) -> Result<(), Error> {
+    export_with_options(snapshot, path, options, verbose)
+}
+
 pub fn import_snapshot(path: String, verbose: bool) -> Result<Snapshot, Error> {
     import(path, verbose)
 }
+
+pub fn import_snapshot_with_options(
+    path: String,
+    options: &ImportOptions,
+    verbose: bool,
+) -> Result<Snapshot, Error> {
+    import_with_options(path, options, verbose)
+}
+
+pub fn verify_snapshot<F>(
+    baseline: &Snapshot,
+    options: &VerifyOptions,
+    verbose: bool,
+    on_event: F,
+) -> Result<VerifyReport, Error>
+where
+    F: FnMut(&VerifyEvent),
+{
+    verify(baseline, options, verbose, on_event)
+}
+
+pub fn verify_snapshot_paths<F>(
+    baseline: &Snapshot,
+    paths: &[&str],
+    options: &VerifyOptions,
+    verbose: bool,
+    on_event: F,
+) -> Result<VerifyReport, Error>
+where
+    F: FnMut(&VerifyEvent),
+{
+    verify_paths(baseline, paths, options, verbose, on_event)
+}
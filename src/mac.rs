@@ -0,0 +1,169 @@
+use crate::snapshot::FileMetadata;
+use anyhow::{anyhow, Error};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+const KEY_CONTEXT: &str = "filesystem-hashing snapshot mac v1";
+
+/// Secret used to authenticate snapshots with keyed BLAKE3.
+///
+/// The key is derived from an arbitrary secret so passphrases and random key files can both be
+/// used directly.
+#[derive(Clone)]
+pub struct MacKey([u8; 32]);
+
+impl MacKey {
+    pub fn from_secret(secret: &[u8]) -> MacKey {
+        MacKey(blake3::derive_key(KEY_CONTEXT, secret))
+    }
+
+    /// Read the secret from a file, ignoring a trailing newline.
+    pub fn from_file(path: &Path) -> Result<MacKey, Error> {
+        let secret = fs::read(path)?;
+        let secret = secret.strip_suffix(b"\n").unwrap_or(&secret);
+        if secret.is_empty() {
+            return Err(anyhow!("mac key file is empty: {:?}", path));
+        }
+        Ok(MacKey::from_secret(secret))
+    }
+}
+
+impl fmt::Debug for MacKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MacKey(..)")
+    }
+}
+
+/// MAC over every field of `entry` except the MAC itself. Entries without attributes hash the
+/// same as before attributes were recorded, so their existing MACs stay valid.
+pub fn entry_mac(key: &MacKey, entry: &FileMetadata) -> Vec<u8> {
+    let mut hasher = blake3::Hasher::new_keyed(&key.0);
+    hasher.update(&(entry.path.len() as u64).to_le_bytes());
+    hasher.update(entry.path.as_bytes());
+    hasher.update(&(entry.check_sum.len() as u64).to_le_bytes());
+    hasher.update(&entry.check_sum);
+    hasher.update(&entry.size.to_le_bytes());
+    hasher.update(&entry.ino.to_le_bytes());
+    hasher.update(&entry.ctime.to_le_bytes());
+    hasher.update(&entry.mtime.to_le_bytes());
+    if let Some(attributes) = &entry.attributes {
+        hasher.update(&[1]);
+        hasher.update(&attributes.mode.to_le_bytes());
+        hasher.update(&attributes.uid.to_le_bytes());
+        hasher.update(&attributes.gid.to_le_bytes());
+        match &attributes.link {
+            Some(link) => hasher
+                .update(&[1])
+                .update(&(link.len() as u64).to_le_bytes())
+                .update(link.as_bytes()),
+            None => hasher.update(&[0]),
+        };
+        match attributes.crc32 {
+            Some(crc32) => hasher.update(&[1]).update(&crc32.to_le_bytes()),
+            None => hasher.update(&[0]),
+        };
+        match attributes.compression {
+            Some(compression) => hasher.update(&[1]).update(&compression.to_le_bytes()),
+            None => hasher.update(&[0]),
+        };
+    }
+    hasher.finalize().as_bytes().to_vec()
+}
+
+pub fn verify_entry(key: &MacKey, entry: &FileMetadata) -> bool {
+    match &entry.mac {
+        Some(mac) => constant_time_eq(&entry_mac(key, entry), mac),
+        None => false,
+    }
+}
+
+/// MAC over a serialized snapshot, hex encoded.
+pub fn snapshot_mac(key: &MacKey, bytes: &[u8]) -> String {
+    blake3::keyed_hash(&key.0, bytes).to_hex().to_string()
+}
+
+pub fn verify_snapshot(key: &MacKey, bytes: &[u8], mac: &str) -> bool {
+    match hex::decode(mac.trim()) {
+        Ok(mac) => constant_time_eq(blake3::keyed_hash(&key.0, bytes).as_bytes(), &mac),
+        Err(_) => false,
+    }
+}
+
+fn constant_time_eq(left: &[u8], right: &[u8]) -> bool {
+    if left.len() != right.len() {
+        return false;
+    }
+    left.iter()
+        .zip(right)
+        .fold(0u8, |acc, (l, r)| acc | (l ^ r))
+        == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::FileAttributes;
+
+    #[test]
+    fn entry_mac_covers_metadata() {
+        let key = MacKey::from_secret(b"secret");
+        let mut entry = FileMetadata {
+            path: "/etc/passwd".to_string(),
+            check_sum: vec![1, 2, 3],
+            ..Default::default()
+        };
+        entry.mac = Some(entry_mac(&key, &entry));
+        assert!(verify_entry(&key, &entry));
+        assert!(!verify_entry(&MacKey::from_secret(b"other"), &entry));
+
+        entry.attributes = Some(FileAttributes {
+            mode: 0o100644,
+            ..Default::default()
+        });
+        assert!(!verify_entry(&key, &entry));
+        entry.mac = Some(entry_mac(&key, &entry));
+        assert!(verify_entry(&key, &entry));
+        for attributes in [
+            FileAttributes {
+                mode: 0o104755,
+                ..Default::default()
+            },
+            FileAttributes {
+                mode: 0o100644,
+                uid: 1000,
+                ..Default::default()
+            },
+            FileAttributes {
+                mode: 0o100644,
+                link: Some("/etc/shadow".to_string()),
+                ..Default::default()
+            },
+            FileAttributes {
+                mode: 0o100644,
+                crc32: Some(0),
+                ..Default::default()
+            },
+        ] {
+            let tampered = FileMetadata {
+                attributes: Some(attributes),
+                ..entry.clone()
+            };
+            assert!(!verify_entry(&key, &tampered));
+        }
+
+        entry.check_sum = vec![1, 2, 4];
+        assert!(!verify_entry(&key, &entry));
+        entry.mac = None;
+        assert!(!verify_entry(&key, &entry));
+    }
+
+    #[test]
+    fn snapshot_mac_round_trip() {
+        let key = MacKey::from_secret(b"secret");
+        let mac = snapshot_mac(&key, b"snapshot");
+        assert!(verify_snapshot(&key, b"snapshot", &mac));
+        assert!(!verify_snapshot(&key, b"snapshot!", &mac));
+        assert!(!verify_snapshot(&key, b"snapshot", "zz"));
+    }
+}
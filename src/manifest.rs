@@ -0,0 +1,326 @@
+use crate::hasher::HashType;
+use crate::snapshot::{write_to_file, ExportOptions, FileMetadata, Snapshot, SnapshotError};
+use crate::util::normalize;
+use anyhow::{anyhow, Error};
+use std::fs;
+use std::path::Path;
+
+/// Length in bytes of the digests `hash_type` produces.
+pub fn digest_len(hash_type: HashType) -> usize {
+    match hash_type {
+        HashType::MD5 => 16,
+        HashType::SHA3 | HashType::BLAKE3 | HashType::SHA256 => 32,
+        HashType::GITSHA1 => 20,
+    }
+}
+
+/// Guess the hash type of a manifest from its file name: `SHA256SUMS`, `MD5SUMS` and the like,
+/// or an extension such as `release.b3` or `image.iso.sha256`.
+pub fn manifest_hash_type(path: &Path) -> Option<HashType> {
+    let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+    [
+        ("sha256", HashType::SHA256),
+        ("sha3", HashType::SHA3),
+        ("md5", HashType::MD5),
+        ("b3", HashType::BLAKE3),
+        ("blake3", HashType::BLAKE3),
+    ]
+    .into_iter()
+    .find(|(tag, _)| {
+        name == format!("{tag}sums")
+            || name.ends_with(&format!(".{tag}"))
+            || name.ends_with(&format!(".{tag}sum"))
+    })
+    .map(|(_, hash_type)| hash_type)
+}
+
+/// One manifest line in the format of `sha256sum`, `md5sum` and `b3sum`. Names containing a
+/// backslash or line break are escaped and the line is prefixed with a backslash.
+pub fn manifest_line(check_sum: &[u8], name: &str) -> String {
+    if name.contains(['\\', '\n', '\r']) {
+        let escaped = name
+            .replace('\\', "\\\\")
+            .replace('\n', "\\n")
+            .replace('\r', "\\r");
+        format!("\\{}  {escaped}", hex::encode(check_sum))
+    } else {
+        format!("{}  {name}", hex::encode(check_sum))
+    }
+}
+
+/// Parse a manifest line into its digest and file name. Both the default format, in text
+/// (`  `) or binary (` *`) mode, and the BSD style `--tag` format are understood. Returns `None`
+/// for blank lines.
+pub fn parse_manifest_line(line: &str) -> Result<Option<(Vec<u8>, String)>, Error> {
+    let line = line.strip_suffix('\r').unwrap_or(line);
+    if line.trim().is_empty() {
+        return Ok(None);
+    }
+    let (escaped, line) = match line.strip_prefix('\\') {
+        Some(rest) => (true, rest),
+        None => (false, line),
+    };
+
+    let tagged = line
+        .split_once(" (")
+        .filter(|(tag, _)| !tag.is_empty() && !tag.contains(' '));
+    let (digest, name) = if let Some((_, rest)) = tagged {
+        // SHA256 (name) = digest
+        let (name, digest) = rest
+            .rsplit_once(") = ")
+            .ok_or_else(|| anyhow!("malformed tagged line: {line}"))?;
+        (digest, name)
+    } else {
+        let (digest, rest) = line
+            .split_once(' ')
+            .ok_or_else(|| anyhow!("malformed line: {line}"))?;
+        let name = rest
+            .strip_prefix(' ')
+            .or_else(|| rest.strip_prefix('*'))
+            .ok_or_else(|| anyhow!("malformed line: {line}"))?;
+        (digest, name)
+    };
+    let check_sum = hex::decode(digest).map_err(|e| anyhow!("bad digest {digest}: {e}"))?;
+    let name = if escaped {
+        unescape(name)?
+    } else {
+        name.to_string()
+    };
+    if name.is_empty() {
+        return Err(anyhow!("missing file name: {line}"));
+    }
+    Ok(Some((check_sum, name)))
+}
+
+fn unescape(name: &str) -> Result<String, Error> {
+    let mut unescaped = String::with_capacity(name.len());
+    let mut chars = name.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => unescaped.push('\\'),
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            other => return Err(anyhow!("bad escape \\{} in {name}", other.unwrap_or(' '))),
+        }
+    }
+    Ok(unescaped)
+}
+
+/// A manifest of every entry in `snapshot`, sorted by path, with names relative to its root.
+pub fn to_manifest(snapshot: &Snapshot) -> Result<String, Error> {
+    let file_hashes = snapshot
+        .file_hashes
+        .lock()
+        .map_err(|_| anyhow!("unable to lock snapshot"))?;
+    let root = snapshot.root_path.trim_end_matches('/');
+    let mut entries: Vec<&FileMetadata> = file_hashes.values().collect();
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let mut manifest = String::new();
+    for entry in entries {
+        let name = match entry.path.strip_prefix(root) {
+            Some(rest) if rest.starts_with('/') => &rest[1..],
+            _ => entry.path.as_str(),
+        };
+        manifest.push_str(&manifest_line(&entry.check_sum, name));
+        manifest.push('\n');
+    }
+    Ok(manifest)
+}
+
+/// A baseline snapshot of `root_path` from manifest contents. Names are resolved against
+/// `root_path`, and absolute names or names that climb out of it are refused; size and times
+/// are unknown and left at zero, so only digests are compared.
+pub fn from_manifest(
+    contents: &str,
+    root_path: &Path,
+    hash_type: HashType,
+) -> Result<Snapshot, Error> {
+    let root = root_path
+        .to_str()
+        .ok_or_else(|| anyhow!("cannot parse path"))?
+        .trim_end_matches('/');
+    let mut entries = vec![];
+    for (number, line) in contents.lines().enumerate() {
+        let (check_sum, name) = match parse_manifest_line(line) {
+            Ok(Some(parsed)) => parsed,
+            Ok(None) => continue,
+            Err(e) => return Err(anyhow!("line {}: {e}", number + 1)),
+        };
+        if check_sum.len() != digest_len(hash_type) {
+            return Err(anyhow!(
+                "line {}: {} byte digest does not match {:?}",
+                number + 1,
+                check_sum.len(),
+                hash_type
+            ));
+        }
+        if name.starts_with('/') {
+            return Err(anyhow!("line {}: {name} is not relative", number + 1));
+        }
+        let name = normalize(&name).map_err(|e| anyhow!("line {}: {e}", number + 1))?;
+        entries.push(FileMetadata {
+            path: format!("{root}/{name}"),
+            check_sum,
+            ..Default::default()
+        });
+    }
+    let root = if root.is_empty() { "/" } else { root };
+    Ok(Snapshot::from_entries(root, hash_type, entries))
+}
+
+/// Write the manifest of `snapshot` to `path` atomically, the way snapshots are exported.
+pub fn export_manifest(snapshot: &Snapshot, path: &Path, overwrite: bool) -> Result<(), Error> {
+    if path.exists() && !overwrite {
+        return Err(SnapshotError::AlreadyExists(path.display().to_string()).into());
+    }
+    let options = ExportOptions {
+        overwrite,
+        ..Default::default()
+    };
+    write_to_file(path, to_manifest(snapshot)?.as_bytes(), &options)
+}
+
+/// Read a manifest file as a baseline of `root_path`, guessing the hash type from the file
+/// name when `hash_type` is `None`.
+pub fn import_manifest(
+    path: &Path,
+    root_path: &Path,
+    hash_type: Option<HashType>,
+) -> Result<Snapshot, Error> {
+    let hash_type = hash_type
+        .or_else(|| manifest_hash_type(path))
+        .ok_or_else(|| anyhow!("cannot tell the hash type of {}", path.display()))?;
+    from_manifest(&fs::read_to_string(path)?, root_path, hash_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::compare_hashes;
+    use crate::verify::{verify, VerifyOptions};
+
+    #[test]
+    fn escaped_names() {
+        assert_eq!(manifest_line(&[0xab], "a b"), "ab  a b");
+        assert_eq!(manifest_line(&[0xab], "a\\b\nc"), "\\ab  a\\\\b\\nc");
+        assert_eq!(
+            parse_manifest_line("\\ab  a\\\\b\\nc").unwrap(),
+            Some((vec![0xab], "a\\b\nc".to_string()))
+        );
+        assert_eq!(
+            parse_manifest_line("ab *bin/x").unwrap(),
+            Some((vec![0xab], "bin/x".to_string()))
+        );
+        assert_eq!(
+            parse_manifest_line("ab  name with  spaces ").unwrap(),
+            Some((vec![0xab], "name with  spaces ".to_string()))
+        );
+        assert_eq!(
+            parse_manifest_line("SHA256 (dir/a (1).txt) = ab").unwrap(),
+            Some((vec![0xab], "dir/a (1).txt".to_string()))
+        );
+        assert_eq!(
+            parse_manifest_line("\\BLAKE3 (x\\ny) = ab").unwrap(),
+            Some((vec![0xab], "x\ny".to_string()))
+        );
+        assert_eq!(
+            parse_manifest_line("ab  a (1).txt").unwrap(),
+            Some((vec![0xab], "a (1).txt".to_string()))
+        );
+        assert_eq!(parse_manifest_line("").unwrap(), None);
+        assert!(parse_manifest_line("zz  file").is_err());
+        assert!(parse_manifest_line("ab file").is_err());
+        assert!(parse_manifest_line("\\ab  bad\\t").is_err());
+        assert_eq!(
+            manifest_hash_type(Path::new("/dl/SHA256SUMS")),
+            Some(HashType::SHA256)
+        );
+        assert_eq!(
+            manifest_hash_type(Path::new("image.iso.md5")),
+            Some(HashType::MD5)
+        );
+        assert_eq!(
+            manifest_hash_type(Path::new("release.b3")),
+            Some(HashType::BLAKE3)
+        );
+        assert_eq!(manifest_hash_type(Path::new("notes.txt")), None);
+        assert_eq!(manifest_hash_type(Path::new("md5-notes.txt")), None);
+        assert_eq!(manifest_hash_type(Path::new("sha256sums.pdf")), None);
+    }
+
+    #[test]
+    fn round_trip_and_verify() {
+        let root = Path::new("./target/build/test_manifest");
+        assert!(!root.exists());
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("abc"), "abc").unwrap();
+        fs::write(root.join("sub/back\\slash"), "x").unwrap();
+
+        let snapshot = Snapshot::new(root, HashType::SHA256, vec![], false).unwrap();
+        let manifest = to_manifest(&snapshot).unwrap();
+        assert_eq!(
+            manifest.lines().next().unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad  abc"
+        );
+        assert!(manifest.contains("\n\\2d711642b726b04401627ca9fbac32f5c8530fb1903cc4db02258717921a4881  sub/back\\\\slash\n"));
+
+        let manifest_path = root.join("SHA256SUMS");
+        export_manifest(&snapshot, &manifest_path, false).unwrap();
+        assert!(export_manifest(&snapshot, &manifest_path, false).is_err());
+        export_manifest(&snapshot, &manifest_path, true).unwrap();
+        assert!(!fs::read_dir(root).unwrap().any(|entry| entry
+            .unwrap()
+            .file_name()
+            .to_string_lossy()
+            .ends_with(".tmp")));
+        let baseline = import_manifest(&manifest_path, root, None).unwrap();
+        assert_eq!(baseline.hash_type, HashType::SHA256);
+        let (_, result) = compare_hashes(baseline.clone(), snapshot, false).unwrap();
+        assert!(result.created.is_empty() && result.deleted.is_empty());
+        assert!(result.changed.is_empty());
+
+        fs::write(root.join("abc"), "abd").unwrap();
+        let mut changed = vec![];
+        let report = verify(&baseline, &VerifyOptions::default(), false, |event| {
+            changed.push(event.path().to_string())
+        })
+        .unwrap();
+        assert_eq!(report.changed, 1);
+        // the manifest itself is not listed in the manifest
+        assert_eq!(report.created, 1);
+        assert!(changed.iter().any(|p| p.ends_with("/abc")));
+
+        assert!(from_manifest("ab  abc\n", root, HashType::SHA256).is_err());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn names_stay_under_the_root() {
+        let digest = "ab".repeat(32);
+        let baseline = from_manifest(
+            &format!("{digest}  ./sub//a\n"),
+            Path::new("/srv/"),
+            HashType::SHA256,
+        )
+        .unwrap();
+        assert!(baseline
+            .file_hashes
+            .lock()
+            .unwrap()
+            .contains_key("/srv/sub/a"));
+        for name in ["../../etc/shadow", "/etc/shadow", "sub/../../x"] {
+            assert!(from_manifest(
+                &format!("{digest}  {name}\n"),
+                Path::new("/srv"),
+                HashType::SHA256
+            )
+            .is_err());
+        }
+    }
+}
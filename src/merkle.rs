@@ -0,0 +1,273 @@
+use crate::snapshot::{FileMetadata, Snapshot};
+use std::collections::{BTreeMap, HashMap};
+
+/// Merkle tree over the files of a snapshot, with one node per directory.
+///
+/// Leaves cover each file's path and checksum (plus size), so two trees have the same root
+/// exactly when `compare_hashes` would report no differences. Timestamps and inode numbers are
+/// not part of the tree.
+#[derive(Debug, Clone, Default)]
+pub struct MerkleTree {
+    pub root: String,
+    /// Nodes keyed by directory path relative to the snapshot root, `""` being the root itself.
+    pub directories: BTreeMap<String, MerkleNode>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MerkleNode {
+    /// Digest of the whole subtree below this directory.
+    pub digest: [u8; 32],
+    /// Digest of the files directly inside this directory.
+    pub files_digest: [u8; 32],
+    /// Relative paths of the child directories.
+    pub children: Vec<String>,
+    /// Snapshot keys of the files directly inside this directory.
+    pub files: Vec<String>,
+}
+
+#[derive(Default)]
+struct Node<'a> {
+    files: BTreeMap<String, (&'a str, [u8; 32])>,
+    dirs: BTreeMap<String, Node<'a>>,
+}
+
+/// Top level directory holding the keys that lie outside the root, named so that it cannot
+/// collide with a real directory.
+const OUTSIDE_ROOT: &str = "/";
+
+/// Path of `key` relative to `root_path`, or `key` itself, still absolute, when it lies outside
+/// the root.
+pub fn relative_path<'a>(root_path: &str, key: &'a str) -> &'a str {
+    let root = root_path.trim_end_matches('/');
+    match key.strip_prefix(root) {
+        Some(rel) if root.is_empty() || rel.is_empty() || rel.starts_with('/') => {
+            rel.trim_start_matches('/')
+        }
+        _ => key,
+    }
+}
+
+fn leaf_digest(name: &str, entry: &FileMetadata) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(b"F");
+    hasher.update(&(name.len() as u64).to_le_bytes());
+    hasher.update(name.as_bytes());
+    hasher.update(&(entry.check_sum.len() as u64).to_le_bytes());
+    hasher.update(&entry.check_sum);
+    hasher.update(&entry.size.to_le_bytes());
+    *hasher.finalize().as_bytes()
+}
+
+/// Build the Merkle tree for `file_hashes` as recorded under `root_path`.
+pub fn build(root_path: &str, file_hashes: &HashMap<String, FileMetadata>) -> MerkleTree {
+    let mut top = Node::default();
+    for (key, entry) in file_hashes.iter() {
+        let rel = relative_path(root_path, key);
+        let mut parts: Vec<&str> = rel.split('/').filter(|p| !p.is_empty()).collect();
+        if rel.starts_with('/') {
+            parts.insert(0, OUTSIDE_ROOT);
+        }
+        let name = parts.pop().unwrap_or_default();
+        let mut node = &mut top;
+        for part in parts {
+            node = node.dirs.entry(part.to_string()).or_default();
+        }
+        node.files
+            .insert(name.to_string(), (key.as_str(), leaf_digest(name, entry)));
+    }
+
+    let mut directories = BTreeMap::new();
+    let root = digest_node("", &top, &mut directories);
+    MerkleTree {
+        root: hex::encode(root),
+        directories,
+    }
+}
+
+fn digest_node(
+    path: &str,
+    node: &Node,
+    directories: &mut BTreeMap<String, MerkleNode>,
+) -> [u8; 32] {
+    let mut files_hasher = blake3::Hasher::new();
+    files_hasher.update(b"L");
+    for (name, (_, digest)) in node.files.iter() {
+        files_hasher.update(&(name.len() as u64).to_le_bytes());
+        files_hasher.update(name.as_bytes());
+        files_hasher.update(digest);
+    }
+    let files_digest = *files_hasher.finalize().as_bytes();
+
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(b"D");
+    hasher.update(&files_digest);
+    let mut children = vec![];
+    for (name, child) in node.dirs.iter() {
+        let child_path = match path.is_empty() {
+            true => name.to_string(),
+            false => format!("{path}/{name}"),
+        };
+        let child_digest = digest_node(&child_path, child, directories);
+        hasher.update(&(name.len() as u64).to_le_bytes());
+        hasher.update(name.as_bytes());
+        hasher.update(&child_digest);
+        children.push(child_path);
+    }
+    let digest = *hasher.finalize().as_bytes();
+
+    directories.insert(
+        path.to_string(),
+        MerkleNode {
+            digest,
+            files_digest,
+            children,
+            files: node
+                .files
+                .values()
+                .map(|(key, _)| key.to_string())
+                .collect(),
+        },
+    );
+    digest
+}
+
+/// Root digest of the snapshot's current entries.
+pub fn root_digest(snapshot: &Snapshot) -> String {
+    match snapshot.file_hashes.lock() {
+        Ok(file_hashes) => build(&snapshot.root_path, &file_hashes).root,
+        Err(_) => String::new(),
+    }
+}
+
+/// Relative paths of the directories whose own files differ between the two trees, found by
+/// descending only into subtrees whose digests differ.
+pub fn diff_directories(left: &MerkleTree, right: &MerkleTree) -> Vec<String> {
+    let mut changed = vec![];
+    if left.root != right.root {
+        descend("", left, right, &mut changed);
+    }
+    changed
+}
+
+fn descend(path: &str, left: &MerkleTree, right: &MerkleTree, changed: &mut Vec<String>) {
+    let (l, r) = (left.directories.get(path), right.directories.get(path));
+    if let (Some(l), Some(r)) = (l, r) {
+        if l.digest == r.digest {
+            return;
+        }
+    }
+    let files_differ = match (l, r) {
+        (Some(l), Some(r)) => l.files_digest != r.files_digest,
+        (Some(n), None) | (None, Some(n)) => !n.files.is_empty(),
+        (None, None) => false,
+    };
+    if files_differ {
+        changed.push(path.to_string());
+    }
+
+    let mut children: Vec<&String> = l
+        .iter()
+        .chain(r.iter())
+        .flat_map(|n| n.children.iter())
+        .collect();
+    children.sort();
+    children.dedup();
+    for child in children {
+        descend(child, left, right, changed);
+    }
+}
+
+/// Directories, joined onto the root path, in which files were created, deleted or changed
+/// between `left` and `right`; e.g. `["/etc/nginx"]`.
+pub fn changed_directories(left: &Snapshot, right: &Snapshot) -> Vec<String> {
+    let left_tree = match left.file_hashes.lock() {
+        Ok(file_hashes) => build(&left.root_path, &file_hashes),
+        Err(_) => return vec![],
+    };
+    let right_tree = match right.file_hashes.lock() {
+        Ok(file_hashes) => build(&right.root_path, &file_hashes),
+        Err(_) => return vec![],
+    };
+    let root = left.root_path.trim_end_matches('/');
+    diff_directories(&left_tree, &right_tree)
+        .into_iter()
+        .map(|dir| match dir.strip_prefix(OUTSIDE_ROOT) {
+            Some("") => "/".to_string(),
+            Some(outside) => outside.to_string(),
+            None if dir.is_empty() => left.root_path.clone(),
+            None => format!("{root}/{dir}"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::test_snapshot;
+
+    fn entry(path: &str, check_sum: u8) -> (String, FileMetadata) {
+        (
+            path.to_string(),
+            FileMetadata {
+                path: path.to_string(),
+                check_sum: vec![check_sum],
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn root_is_order_independent_and_ignores_timestamps() {
+        let left: HashMap<String, FileMetadata> = vec![
+            entry("/etc/hosts", 1),
+            entry("/etc/nginx/nginx.conf", 2),
+            entry("/etc/nginx/sites/default", 3),
+        ]
+        .into_iter()
+        .collect();
+        let mut right = left.clone();
+        right.get_mut("/etc/hosts").unwrap().mtime = 42;
+        assert_eq!(build("/etc", &left).root, build("/etc/", &right).root);
+    }
+
+    #[test]
+    fn localizes_changes() {
+        let left: HashMap<String, FileMetadata> = vec![
+            entry("/etc/hosts", 1),
+            entry("/etc/nginx/nginx.conf", 2),
+            entry("/etc/nginx/sites/default", 3),
+            entry("/etc/ssh/sshd_config", 4),
+        ]
+        .into_iter()
+        .collect();
+        let mut right = left.clone();
+        right.get_mut("/etc/nginx/sites/default").unwrap().check_sum = vec![9];
+        right.extend(vec![entry("/etc/cron.d/job", 5)]);
+
+        let (left_tree, right_tree) = (build("/etc", &left), build("/etc", &right));
+        assert_ne!(left_tree.root, right_tree.root);
+        assert_eq!(
+            diff_directories(&left_tree, &right_tree),
+            vec!["cron.d".to_string(), "nginx/sites".to_string()]
+        );
+        assert_eq!(
+            left_tree.directories["nginx"].files,
+            vec!["/etc/nginx/nginx.conf".to_string()]
+        );
+    }
+
+    #[test]
+    fn keys_outside_the_root_do_not_collide() {
+        let left = test_snapshot("/etc", "1", 0, &[("/etc/hosts", 1), ("/hosts", 1)]);
+        let right = test_snapshot("/etc", "2", 0, &[("/etc/hosts", 1), ("/hosts", 2)]);
+        let left_tree = build("/etc", &left.file_hashes.lock().unwrap());
+        let right_tree = build("/etc", &right.file_hashes.lock().unwrap());
+        assert_ne!(left_tree.root, right_tree.root);
+        assert_eq!(
+            left_tree.directories[""].files,
+            vec!["/etc/hosts".to_string()]
+        );
+        assert_eq!(left_tree.directories["/"].files, vec!["/hosts".to_string()]);
+        assert_eq!(changed_directories(&left, &right), vec!["/".to_string()]);
+    }
+}
@@ -0,0 +1,698 @@
+use crate::hasher::HashType;
+use crate::manifest::digest_len;
+use crate::snapshot::{
+    write_to_file, ExportOptions, FileAttributes, FileMetadata, Snapshot, SnapshotError,
+};
+use crate::util::normalize;
+use anyhow::{anyhow, Error};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write;
+use std::fs;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Keywords [`to_mtree`] can write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MtreeKeyword {
+    Type,
+    Mode,
+    Uid,
+    Gid,
+    Size,
+    Time,
+    Link,
+    /// The snapshot's own digest: `md5digest`, `sha256digest`, `sha3_256digest` or
+    /// `blake3digest`. The last two are not understood by other mtree implementations.
+    Digest,
+}
+
+impl FromStr for MtreeKeyword {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "type" => Ok(MtreeKeyword::Type),
+            "mode" => Ok(MtreeKeyword::Mode),
+            "uid" => Ok(MtreeKeyword::Uid),
+            "gid" => Ok(MtreeKeyword::Gid),
+            "size" => Ok(MtreeKeyword::Size),
+            "time" => Ok(MtreeKeyword::Time),
+            "link" => Ok(MtreeKeyword::Link),
+            "digest" => Ok(MtreeKeyword::Digest),
+            _ => Err(anyhow!("unknown mtree keyword: {s}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MtreeOptions {
+    pub keywords: Vec<MtreeKeyword>,
+}
+
+impl Default for MtreeOptions {
+    fn default() -> Self {
+        MtreeOptions {
+            keywords: vec![
+                MtreeKeyword::Type,
+                MtreeKeyword::Mode,
+                MtreeKeyword::Uid,
+                MtreeKeyword::Gid,
+                MtreeKeyword::Size,
+                MtreeKeyword::Time,
+                MtreeKeyword::Link,
+                MtreeKeyword::Digest,
+            ],
+        }
+    }
+}
+
+/// The digest keyword for `hash_type`.
+pub fn digest_keyword(hash_type: HashType) -> &'static str {
+    match hash_type {
+        HashType::MD5 => "md5digest",
+        HashType::SHA256 => "sha256digest",
+        HashType::SHA3 => "sha3_256digest",
+        HashType::BLAKE3 => "blake3digest",
+        HashType::GITSHA1 => "gitsha1digest",
+    }
+}
+
+/// Map a digest keyword, or one of its short aliases, back to a hash type.
+fn keyword_hash_type(keyword: &str) -> Option<HashType> {
+    match keyword {
+        "md5" | "md5digest" => Some(HashType::MD5),
+        "sha256" | "sha256digest" => Some(HashType::SHA256),
+        "sha3_256digest" => Some(HashType::SHA3),
+        "blake3digest" => Some(HashType::BLAKE3),
+        "gitsha1digest" => Some(HashType::GITSHA1),
+        _ => None,
+    }
+}
+
+/// Encode a file name the way mtree does: whitespace, `\`, glob characters, `#` and anything
+/// not printable ASCII become a backslash and three octal digits.
+pub fn escape_name(name: &str) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        if byte.is_ascii_graphic() && !b"\\#*?[".contains(&byte) {
+            escaped.push(byte as char);
+        } else {
+            let _ = write!(escaped, "\\{byte:03o}");
+        }
+    }
+    escaped
+}
+
+pub fn unescape_name(name: &str) -> Result<String, Error> {
+    let bytes = name.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'\\' {
+            unescaped.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        let octal = bytes
+            .get(i + 1..i + 4)
+            .filter(|digits| digits.iter().all(|d| (b'0'..=b'7').contains(d)));
+        if let Some(digits) = octal {
+            let value = digits
+                .iter()
+                .fold(0u32, |acc, d| acc * 8 + (d - b'0') as u32);
+            unescaped.push(u8::try_from(value).map_err(|_| anyhow!("bad escape in {name}"))?);
+            i += 4;
+            continue;
+        }
+        let c = match bytes.get(i + 1) {
+            Some(b's') => b' ',
+            Some(b't') => b'\t',
+            Some(b'n') => b'\n',
+            Some(b'r') => b'\r',
+            Some(b'\\') => b'\\',
+            Some(b'#') => b'#',
+            _ => return Err(anyhow!("bad escape in {name}")),
+        };
+        unescaped.push(c);
+        i += 2;
+    }
+    String::from_utf8(unescaped).map_err(|_| anyhow!("file name is not utf-8: {name}"))
+}
+
+/// Attributes of a file as written to a spec, either recorded in the snapshot or read from disk.
+struct Attributes {
+    kind: &'static str,
+    mode: u32,
+    uid: u64,
+    gid: u64,
+    link: Option<String>,
+}
+
+fn recorded_attributes(attributes: &FileAttributes) -> Attributes {
+    let kind = match attributes.mode & libc::S_IFMT {
+        libc::S_IFLNK => "link",
+        libc::S_IFDIR => "dir",
+        libc::S_IFBLK => "block",
+        libc::S_IFCHR => "char",
+        libc::S_IFIFO => "fifo",
+        libc::S_IFSOCK => "socket",
+        _ => "file",
+    };
+    Attributes {
+        kind,
+        mode: attributes.mode & 0o7777,
+        uid: attributes.uid,
+        gid: attributes.gid,
+        // archives record hard link targets too, which mtree has no keyword for
+        link: attributes.link.clone().filter(|_| kind == "link"),
+    }
+}
+
+fn attributes(path: &str) -> Option<Attributes> {
+    let metadata = fs::symlink_metadata(path).ok()?;
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_symlink() {
+        "link"
+    } else if file_type.is_dir() {
+        "dir"
+    } else if file_type.is_block_device() {
+        "block"
+    } else if file_type.is_char_device() {
+        "char"
+    } else if file_type.is_fifo() {
+        "fifo"
+    } else if file_type.is_socket() {
+        "socket"
+    } else {
+        "file"
+    };
+    let link = match kind {
+        "link" => fs::read_link(path)
+            .ok()
+            .map(|target| target.to_string_lossy().to_string()),
+        _ => None,
+    };
+    Some(Attributes {
+        kind,
+        mode: metadata.mode() & 0o7777,
+        uid: metadata.uid() as u64,
+        gid: metadata.gid() as u64,
+        link,
+    })
+}
+
+/// The most common value of `values`, ties going to the smallest.
+fn most_common<T: Ord + Copy>(values: impl Iterator<Item = T>) -> Option<T> {
+    let mut counts: BTreeMap<T, usize> = BTreeMap::new();
+    for value in values {
+        *counts.entry(value).or_default() += 1;
+    }
+    let max = counts.values().copied().max()?;
+    counts
+        .into_iter()
+        .find(|(_, count)| *count == max)
+        .map(|(value, _)| value)
+}
+
+/// Write `snapshot` as an mtree spec with one full path per line, relative to its root.
+///
+/// Everything comes from the snapshot where it is recorded. Type, mode, owner and link target
+/// are only recorded for some snapshots, such as those of archives or git trees; otherwise, and
+/// for the directories above the entries, they are read from the files on disk and left out for
+/// files that are gone. The most common uid, gid and file mode go into a `/set` line.
+pub fn to_mtree(snapshot: &Snapshot, options: &MtreeOptions) -> Result<String, Error> {
+    let file_hashes = snapshot
+        .file_hashes
+        .lock()
+        .map_err(|_| anyhow!("unable to lock snapshot"))?;
+    let root = snapshot.root_path.trim_end_matches('/');
+    let has = |keyword| options.keywords.contains(&keyword);
+    let relative = |path: &str| -> String {
+        match path.strip_prefix(root) {
+            Some("") => ".".to_string(),
+            Some(rest) if rest.starts_with('/') => format!(".{rest}"),
+            _ => format!("./{}", path.trim_start_matches('/')),
+        }
+    };
+
+    let mut entries: Vec<&FileMetadata> = file_hashes.values().collect();
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    let mut directories = BTreeSet::new();
+    for entry in &entries {
+        for dir in Path::new(&entry.path).ancestors().skip(1) {
+            let dir = dir.to_string_lossy().to_string();
+            if dir.len() < root.len() || !directories.insert(dir) {
+                break;
+            }
+        }
+    }
+    let stats: HashMap<&str, Attributes> = directories
+        .iter()
+        .filter_map(|dir| attributes(dir).map(|attributes| (dir.as_str(), attributes)))
+        .chain(entries.iter().filter_map(|entry| {
+            let attributes = match &entry.attributes {
+                Some(recorded) => Some(recorded_attributes(recorded)),
+                None => attributes(&entry.path),
+            };
+            attributes.map(|attributes| (entry.path.as_str(), attributes))
+        }))
+        .collect();
+
+    let files = || {
+        entries
+            .iter()
+            .filter_map(|entry| stats.get(entry.path.as_str()))
+    };
+    let mut defaults: Vec<(&str, String)> = vec![];
+    if has(MtreeKeyword::Type) {
+        defaults.push(("type", "file".to_string()));
+    }
+    if has(MtreeKeyword::Uid) {
+        if let Some(uid) = most_common(files().map(|a| a.uid)) {
+            defaults.push(("uid", uid.to_string()));
+        }
+    }
+    if has(MtreeKeyword::Gid) {
+        if let Some(gid) = most_common(files().map(|a| a.gid)) {
+            defaults.push(("gid", gid.to_string()));
+        }
+    }
+    if has(MtreeKeyword::Mode) {
+        if let Some(mode) = most_common(files().filter(|a| a.kind == "file").map(|a| a.mode)) {
+            defaults.push(("mode", format!("{mode:04o}")));
+        }
+    }
+
+    let mut out = String::from("#mtree\n");
+    if !defaults.is_empty() {
+        let set: Vec<String> = defaults.iter().map(|(k, v)| format!("{k}={v}")).collect();
+        let _ = writeln!(out, "/set {}", set.join(" "));
+    }
+    let write_line = |out: &mut String, path: &str, mut keywords: Vec<(&str, String)>| {
+        keywords.retain(|(key, value)| !defaults.contains(&(*key, value.clone())));
+        let _ = write!(out, "{}", escape_name(&relative(path)));
+        for (key, value) in keywords {
+            let _ = write!(out, " {key}={value}");
+        }
+        out.push('\n');
+    };
+    let attribute_keywords = |attributes: Option<&Attributes>, kind: &'static str| {
+        let mut keywords = vec![];
+        let Some(attributes) = attributes else {
+            if has(MtreeKeyword::Type) {
+                keywords.push(("type", kind.to_string()));
+            }
+            return keywords;
+        };
+        if has(MtreeKeyword::Type) {
+            keywords.push(("type", attributes.kind.to_string()));
+        }
+        if has(MtreeKeyword::Uid) {
+            keywords.push(("uid", attributes.uid.to_string()));
+        }
+        if has(MtreeKeyword::Gid) {
+            keywords.push(("gid", attributes.gid.to_string()));
+        }
+        if has(MtreeKeyword::Mode) {
+            keywords.push(("mode", format!("{:04o}", attributes.mode)));
+        }
+        if has(MtreeKeyword::Link) {
+            if let Some(link) = &attributes.link {
+                keywords.push(("link", escape_name(link)));
+            }
+        }
+        keywords
+    };
+
+    // directories before the files they hold, in path order; without a type they would read
+    // as files, so they are left out
+    let mut lines: Vec<(&str, bool)> = directories
+        .iter()
+        .filter(|_| has(MtreeKeyword::Type))
+        .map(|dir| (dir.as_str(), true))
+        .chain(entries.iter().map(|entry| (entry.path.as_str(), false)))
+        .collect();
+    lines.sort_by_key(|(path, _)| relative(path));
+    for (path, is_dir) in lines {
+        if is_dir {
+            write_line(&mut out, path, attribute_keywords(stats.get(path), "dir"));
+            continue;
+        }
+        let entry = &file_hashes[path];
+        let mut keywords = attribute_keywords(stats.get(path), "file");
+        if has(MtreeKeyword::Size) {
+            keywords.push(("size", entry.size.to_string()));
+        }
+        if has(MtreeKeyword::Time) {
+            keywords.push(("time", format!("{}.000000000", entry.mtime)));
+        }
+        if has(MtreeKeyword::Digest) {
+            keywords.push((
+                digest_keyword(snapshot.hash_type),
+                hex::encode(&entry.check_sum),
+            ));
+        }
+        write_line(&mut out, path, keywords);
+    }
+    Ok(out)
+}
+
+/// Write the mtree spec of `snapshot` to `path` atomically, the way snapshots are exported.
+pub fn export_mtree(
+    snapshot: &Snapshot,
+    path: &Path,
+    options: &MtreeOptions,
+    overwrite: bool,
+) -> Result<(), Error> {
+    if path.exists() && !overwrite {
+        return Err(SnapshotError::AlreadyExists(path.display().to_string()).into());
+    }
+    let export_options = ExportOptions {
+        overwrite,
+        ..Default::default()
+    };
+    write_to_file(
+        path,
+        to_mtree(snapshot, options)?.as_bytes(),
+        &export_options,
+    )
+}
+
+/// One entry of an mtree spec with its keywords, `/set` defaults applied.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MtreeEntry {
+    /// Path relative to the spec's root, without the leading `./`; empty for the root itself.
+    pub path: String,
+    pub keywords: BTreeMap<String, String>,
+}
+
+impl MtreeEntry {
+    pub fn get(&self, keyword: &str) -> Option<&str> {
+        self.keywords.get(keyword).map(String::as_str)
+    }
+
+    /// The entry type, `file` when the spec leaves it out.
+    pub fn kind(&self) -> &str {
+        self.get("type").unwrap_or("file")
+    }
+
+    /// Seconds of the `time` keyword.
+    pub fn mtime(&self) -> Option<i64> {
+        self.get("time")?.split('.').next()?.parse().ok()
+    }
+}
+
+fn parse_keywords(words: &[&str], line: usize) -> Result<Vec<(String, String)>, Error> {
+    words
+        .iter()
+        .map(|word| match word.split_once('=') {
+            Some((key, value)) => Ok((key.to_string(), value.to_string())),
+            // flag keywords such as `ignore`, `optional` or `nochange`
+            None if !word.is_empty() => Ok((word.to_string(), String::new())),
+            None => Err(anyhow!("line {line}: empty keyword")),
+        })
+        .collect()
+}
+
+/// Parse an mtree spec in either the full path form written by libarchive and [`to_mtree`] or
+/// the nested form written by `mtree -c`, where names without a `/` are relative to the last
+/// directory and `..` climbs back out of it.
+pub fn parse_mtree(contents: &str) -> Result<Vec<MtreeEntry>, Error> {
+    let mut entries = vec![];
+    let mut defaults: BTreeMap<String, String> = BTreeMap::new();
+    let mut cwd: Vec<String> = vec![];
+    let mut pending = String::new();
+    let mut start = 0;
+
+    for (number, raw) in contents.lines().enumerate() {
+        if pending.is_empty() {
+            start = number + 1;
+        }
+        let raw = raw.strip_suffix('\r').unwrap_or(raw);
+        if let Some(continued) = raw.strip_suffix('\\') {
+            pending.push_str(continued);
+            pending.push(' ');
+            continue;
+        }
+        pending.push_str(raw);
+        let line = std::mem::take(&mut pending);
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+
+        match words[0] {
+            "/set" => defaults.extend(parse_keywords(&words[1..], start)?),
+            "/unset" => {
+                for keyword in &words[1..] {
+                    if *keyword == "all" {
+                        defaults.clear();
+                    } else {
+                        defaults.remove(*keyword);
+                    }
+                }
+            }
+            ".." => {
+                cwd.pop();
+            }
+            name if name.starts_with('/') => {
+                return Err(anyhow!("line {start}: unknown command {name}"))
+            }
+            name => {
+                let name = unescape_name(name)?;
+                let mut keywords = defaults.clone();
+                keywords.extend(parse_keywords(&words[1..], start)?);
+                let nested = !name.contains('/');
+                let path = if nested {
+                    let mut parts = cwd.clone();
+                    parts.push(name);
+                    normalize(&parts.join("/"))
+                } else {
+                    normalize(&name)
+                }
+                .map_err(|e| anyhow!("line {start}: {e}"))?;
+                let entry = MtreeEntry { path, keywords };
+                if nested && entry.kind() == "dir" {
+                    cwd = entry.path.split('/').map(str::to_string).collect();
+                    cwd.retain(|part| !part.is_empty());
+                }
+                entries.push(entry);
+            }
+        }
+    }
+    if !pending.is_empty() {
+        return Err(anyhow!("line {start}: unterminated continuation"));
+    }
+    Ok(entries)
+}
+
+/// A baseline snapshot of `root_path` from an mtree spec. Only entries of type `file` are kept.
+/// `hash_type` picks the digest keyword to use; when `None` the first one found is used,
+/// preferring SHA-256. Every file needs that digest, and sizes and times are taken over when
+/// present.
+pub fn from_mtree(
+    contents: &str,
+    root_path: &Path,
+    hash_type: Option<HashType>,
+) -> Result<Snapshot, Error> {
+    let entries = parse_mtree(contents)?;
+    let files: Vec<&MtreeEntry> = entries.iter().filter(|e| e.kind() == "file").collect();
+    let hash_type = match hash_type {
+        Some(hash_type) => hash_type,
+        None => {
+            let found: BTreeSet<&str> = files
+                .iter()
+                .flat_map(|entry| entry.keywords.keys())
+                .map(String::as_str)
+                .filter(|keyword| keyword_hash_type(keyword).is_some())
+                .collect();
+            found
+                .iter()
+                .filter_map(|keyword| keyword_hash_type(keyword))
+                .max_by_key(|hash_type| *hash_type == HashType::SHA256)
+                .ok_or_else(|| anyhow!("spec has no digest keyword this crate can check"))?
+        }
+    };
+
+    let root = root_path
+        .to_str()
+        .ok_or_else(|| anyhow!("cannot parse path"))?
+        .trim_end_matches('/');
+    let mut metadata = vec![];
+    for entry in files {
+        let digest = entry
+            .keywords
+            .iter()
+            .find(|(keyword, _)| keyword_hash_type(keyword) == Some(hash_type))
+            .map(|(_, digest)| digest)
+            .ok_or_else(|| anyhow!("{} has no {}", entry.path, digest_keyword(hash_type)))?;
+        let check_sum = hex::decode(digest).map_err(|e| anyhow!("{}: {e}", entry.path))?;
+        if check_sum.len() != digest_len(hash_type) {
+            return Err(anyhow!(
+                "{}: digest does not match {:?}",
+                entry.path,
+                hash_type
+            ));
+        }
+        metadata.push(FileMetadata {
+            path: format!("{root}/{}", entry.path),
+            check_sum,
+            size: entry.get("size").and_then(|s| s.parse().ok()).unwrap_or(0),
+            mtime: entry.mtime().unwrap_or(0),
+            ..Default::default()
+        });
+    }
+    let root = if root.is_empty() { "/" } else { root };
+    Ok(Snapshot::from_entries(root, hash_type, metadata))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verify::{verify, VerifyOptions};
+
+    #[test]
+    fn parses_nested_specs() {
+        let spec = format!(
+            "\
+#	   user: root
+/set type=file uid=0 gid=0 mode=0644 nlink=1
+.               type=dir mode=0755 nlink=3
+    motd        size=12 time=1700000000.000000000 \\
+                sha256digest={ab}
+/set uid=1000
+    bin         type=dir mode=0755
+        tool    mode=0755 md5digest=cd sha256digest={ab}
+/unset uid
+        a\\040b\\134c  sha256digest={one} ignore
+    ..
+..
+./sub/x sha256digest={ab}
+",
+            ab = "ab".repeat(32),
+            one = "01".repeat(32)
+        );
+        let entries = parse_mtree(&spec).unwrap();
+        let paths: Vec<&str> = entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            ["", "motd", "bin", "bin/tool", "bin/a b\\c", "sub/x"]
+        );
+        assert_eq!(entries[1].get("uid"), Some("0"));
+        assert_eq!(entries[1].mtime(), Some(1700000000));
+        assert_eq!(
+            entries[1].get("sha256digest"),
+            Some("ab".repeat(32).as_str())
+        );
+        assert_eq!(entries[3].get("uid"), Some("1000"));
+        assert_eq!(entries[3].get("mode"), Some("0755"));
+        assert_eq!(entries[4].get("uid"), None);
+        assert_eq!(entries[4].get("ignore"), Some(""));
+        assert_eq!(entries[4].kind(), "file");
+
+        let snapshot = from_mtree(&spec, Path::new("/etc"), None).unwrap();
+        assert_eq!(snapshot.hash_type, HashType::SHA256);
+        let file_hashes = snapshot.file_hashes.lock().unwrap();
+        assert_eq!(file_hashes.len(), 4);
+        assert_eq!(file_hashes["/etc/motd"].size, 12);
+        assert_eq!(file_hashes["/etc/bin/a b\\c"].check_sum, vec![1; 32]);
+        drop(file_hashes);
+        assert!(from_mtree(&spec, Path::new("/etc"), Some(HashType::MD5)).is_err());
+
+        assert_eq!(escape_name("./a b\\c#"), "./a\\040b\\134c\\043");
+        assert_eq!(unescape_name("a\\sb\\303\\251").unwrap(), "a b\u{e9}");
+        assert!(parse_mtree("/bogus x=1\n").is_err());
+        assert!(parse_mtree("./a/../../etc/shadow type=file\n").is_err());
+        assert!(parse_mtree("a type=dir\n./a/.. type=file\n").is_err());
+    }
+
+    #[test]
+    fn round_trip_and_verify() {
+        let root = Path::new("./target/build/test_mtree");
+        assert!(!root.exists());
+        fs::create_dir_all(root.join("sub dir")).unwrap();
+        fs::write(root.join("a"), "a").unwrap();
+        fs::write(root.join("sub dir/b"), "b").unwrap();
+
+        let snapshot = Snapshot::new(root, HashType::SHA256, vec![], false).unwrap();
+        let spec = to_mtree(&snapshot, &MtreeOptions::default()).unwrap();
+        let lines: Vec<&str> = spec.lines().collect();
+        assert_eq!(lines[0], "#mtree");
+        assert!(lines[1].starts_with("/set type=file uid="));
+        assert!(lines[2].starts_with(". type=dir "));
+        assert!(lines[3].starts_with("./a size=1 time="));
+        assert!(lines[3].ends_with(
+            " sha256digest=ca978112ca1bbdcafac231b39a23dc4da786eff8147c4e72b9807785afee48bb"
+        ));
+        assert!(lines[4].starts_with("./sub\\040dir type=dir "));
+        assert!(lines[5].starts_with("./sub\\040dir/b size=1 "));
+
+        let spec_path = Path::new("./target/build/test_mtree.spec");
+        export_mtree(&snapshot, spec_path, &MtreeOptions::default(), false).unwrap();
+        assert!(export_mtree(&snapshot, spec_path, &MtreeOptions::default(), false).is_err());
+        assert_eq!(fs::read_to_string(spec_path).unwrap(), spec);
+        fs::remove_file(spec_path).unwrap();
+
+        let baseline = from_mtree(&spec, root, None).unwrap();
+        fs::write(root.join("sub dir/b"), "c").unwrap();
+        let mut changed = vec![];
+        let report = verify(&baseline, &VerifyOptions::default(), false, |event| {
+            changed.push(event.path().to_string())
+        })
+        .unwrap();
+        assert_eq!(report.checked, 2);
+        assert_eq!(report.changed, 1);
+        assert!(changed[0].ends_with("sub dir/b"));
+
+        let options = MtreeOptions {
+            keywords: vec![MtreeKeyword::Size, MtreeKeyword::Digest],
+        };
+        let spec = to_mtree(&snapshot, &options).unwrap();
+        assert!(!spec.contains("/set"));
+        assert!(!spec.contains("mode="));
+        let subset = from_mtree(&spec, root, None).unwrap();
+        assert_eq!(subset.file_hashes.lock().unwrap().len(), 2);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn recorded_attributes_win_over_the_disk() {
+        let snapshot = Snapshot::from_entries(
+            "/nonexistent",
+            HashType::SHA256,
+            vec![
+                FileMetadata {
+                    path: "/nonexistent/bin/run".to_string(),
+                    check_sum: vec![0xab; 32],
+                    size: 3,
+                    attributes: Some(FileAttributes {
+                        mode: libc::S_IFREG | 0o755,
+                        uid: 7,
+                        gid: 8,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                FileMetadata {
+                    path: "/nonexistent/bin/start".to_string(),
+                    check_sum: vec![0xab; 32],
+                    size: 3,
+                    attributes: Some(FileAttributes {
+                        mode: libc::S_IFLNK | 0o777,
+                        uid: 7,
+                        gid: 8,
+                        link: Some("run".to_string()),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            ],
+        );
+        let spec = to_mtree(&snapshot, &MtreeOptions::default()).unwrap();
+        assert!(spec.contains("/set type=file uid=7 gid=8 mode=0755\n"));
+        assert!(spec.contains("\n./bin/start type=link mode=0777 link=run size=3 "));
+    }
+}
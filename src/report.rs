@@ -0,0 +1,393 @@
+use crate::hasher::HashType;
+use crate::snapshot::{
+    change_records, ChangeRecord, FileMetadata, Snapshot, SnapshotCompareResult,
+};
+use anyhow::{anyhow, Error};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    Json,
+    Csv,
+    Markdown,
+    Html,
+}
+
+impl FromStr for ReportFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(ReportFormat::Json),
+            "csv" => Ok(ReportFormat::Csv),
+            "markdown" | "md" => Ok(ReportFormat::Markdown),
+            "html" => Ok(ReportFormat::Html),
+            _ => Err(anyhow!("unknown report format: {s}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ChangeCounts {
+    pub created: usize,
+    pub deleted: usize,
+    pub changed: usize,
+}
+
+/// A compare result together with the snapshots it came from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CompareReport {
+    pub root_path: String,
+    pub left_uuid: String,
+    pub right_uuid: String,
+    pub left_date_created: i64,
+    pub right_date_created: i64,
+    pub summary: ChangeCounts,
+    pub changes: Vec<ChangeRecord>,
+}
+
+impl CompareReport {
+    pub fn new(
+        result: &SnapshotCompareResult,
+        left: &Snapshot,
+        right: &Snapshot,
+    ) -> Result<CompareReport, Error> {
+        Ok(CompareReport {
+            root_path: right.root_path.clone(),
+            left_uuid: left.uuid.clone(),
+            right_uuid: right.uuid.clone(),
+            left_date_created: left.date_created,
+            right_date_created: right.date_created,
+            summary: ChangeCounts {
+                created: result.created.len(),
+                deleted: result.deleted.len(),
+                changed: result.changed.len(),
+            },
+            changes: change_records(result, left, right)?,
+        })
+    }
+}
+
+/// A single snapshot, entries sorted by path.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SnapshotReport {
+    pub root_path: String,
+    pub uuid: String,
+    pub date_created: i64,
+    pub hash_type: HashType,
+    pub merkle_root: String,
+    pub files: usize,
+    pub bytes: u64,
+    pub entries: Vec<SnapshotReportEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SnapshotReportEntry {
+    pub path: String,
+    pub check_sum: String,
+    pub size: u64,
+    pub mtime: i64,
+}
+
+impl SnapshotReport {
+    pub fn new(snapshot: &Snapshot) -> Result<SnapshotReport, Error> {
+        let file_hashes = snapshot
+            .file_hashes
+            .lock()
+            .map_err(|_| anyhow!("unable to lock snapshot"))?;
+        let mut entries: Vec<&FileMetadata> = file_hashes.values().collect();
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(SnapshotReport {
+            root_path: snapshot.root_path.clone(),
+            uuid: snapshot.uuid.clone(),
+            date_created: snapshot.date_created,
+            hash_type: snapshot.hash_type,
+            merkle_root: snapshot.merkle_root.clone(),
+            files: entries.len(),
+            bytes: entries.iter().map(|entry| entry.size).sum(),
+            entries: entries
+                .into_iter()
+                .map(|entry| SnapshotReportEntry {
+                    path: entry.path.clone(),
+                    check_sum: hex::encode(&entry.check_sum),
+                    size: entry.size,
+                    mtime: entry.mtime,
+                })
+                .collect(),
+        })
+    }
+}
+
+pub fn render_compare(report: &CompareReport, format: ReportFormat) -> Result<String, Error> {
+    let rows: Vec<Vec<String>> = report
+        .changes
+        .iter()
+        .map(|record| {
+            vec![
+                record.change.to_string(),
+                record.path.clone(),
+                record.old_digest.clone().unwrap_or_default(),
+                record.new_digest.clone().unwrap_or_default(),
+            ]
+        })
+        .collect();
+    let header = ["change", "path", "old_digest", "new_digest"];
+    let summary = [
+        ("Root", report.root_path.clone()),
+        ("Left", report.left_uuid.clone()),
+        ("Right", report.right_uuid.clone()),
+        ("Created", report.summary.created.to_string()),
+        ("Deleted", report.summary.deleted.to_string()),
+        ("Changed", report.summary.changed.to_string()),
+    ];
+    Ok(match format {
+        ReportFormat::Json => serde_json::to_string_pretty(report)?,
+        ReportFormat::Csv => csv(&header, &rows),
+        ReportFormat::Markdown => markdown("Snapshot comparison", &summary, &header, &rows),
+        ReportFormat::Html => html("Snapshot comparison", &summary, &header, &rows, 1, Some(0)),
+    })
+}
+
+pub fn render_snapshot(report: &SnapshotReport, format: ReportFormat) -> Result<String, Error> {
+    let rows: Vec<Vec<String>> = report
+        .entries
+        .iter()
+        .map(|entry| {
+            vec![
+                entry.path.clone(),
+                entry.check_sum.clone(),
+                entry.size.to_string(),
+                entry.mtime.to_string(),
+            ]
+        })
+        .collect();
+    let header = ["path", "check_sum", "size", "mtime"];
+    let summary = [
+        ("Root", report.root_path.clone()),
+        ("Snapshot", report.uuid.clone()),
+        ("Hash type", format!("{:?}", report.hash_type)),
+        ("Merkle root", report.merkle_root.clone()),
+        ("Files", report.files.to_string()),
+        ("Bytes", report.bytes.to_string()),
+    ];
+    Ok(match format {
+        ReportFormat::Json => serde_json::to_string_pretty(report)?,
+        ReportFormat::Csv => csv(&header, &rows),
+        ReportFormat::Markdown => markdown("Snapshot", &summary, &header, &rows),
+        ReportFormat::Html => html("Snapshot", &summary, &header, &rows, 0, None),
+    })
+}
+
+/// Quote a CSV field when it contains a comma, quote or line break, doubling inner quotes.
+pub fn escape_csv(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Escape characters that Markdown would interpret inside a table cell.
+pub fn escape_markdown(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' | '|' | '*' | '_' | '`' | '[' | ']' | '<' | '>' | '#' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\n' | '\r' => escaped.push(' '),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn csv(header: &[&str], rows: &[Vec<String>]) -> String {
+    let mut out = header.join(",");
+    out.push_str("\r\n");
+    for row in rows {
+        let fields: Vec<String> = row.iter().map(|field| escape_csv(field)).collect();
+        out.push_str(&fields.join(","));
+        out.push_str("\r\n");
+    }
+    out
+}
+
+fn markdown(
+    title: &str,
+    summary: &[(&str, String)],
+    header: &[&str],
+    rows: &[Vec<String>],
+) -> String {
+    let mut out = format!("# {title}\n\n| | |\n|---|---|\n");
+    for (name, value) in summary {
+        let _ = writeln!(out, "| {name} | {} |", escape_markdown(value));
+    }
+    let _ = write!(out, "\n| {} |\n|", header.join(" | "));
+    out.push_str(&"---|".repeat(header.len()));
+    out.push('\n');
+    for row in rows {
+        let cells: Vec<String> = row.iter().map(|cell| escape_markdown(cell)).collect();
+        let _ = writeln!(out, "| {} |", cells.join(" | "));
+    }
+    out
+}
+
+const STYLE: &str = "body{font-family:sans-serif;margin:2em}\
+table{border-collapse:collapse;margin:.5em 0}\
+td,th{border:1px solid #ccc;padding:.2em .6em;text-align:left;font-family:monospace}\
+summary{cursor:pointer;font-weight:bold;margin:.3em 0}\
+.created{color:#060}.deleted{color:#a00}.changed{color:#a60}";
+
+/// A self-contained HTML page: summary counts, then the rows grouped by the parent directory of
+/// the path in column `path_column`, one collapsible section per directory. Column
+/// `class_column`, when given, is also the css class of each row.
+fn html(
+    title: &str,
+    summary: &[(&str, String)],
+    header: &[&str],
+    rows: &[Vec<String>],
+    path_column: usize,
+    class_column: Option<usize>,
+) -> String {
+    let mut groups: BTreeMap<String, Vec<&Vec<String>>> = BTreeMap::new();
+    for row in rows {
+        let dir = Path::new(&row[path_column])
+            .parent()
+            .map(|p| p.to_string_lossy().to_string())
+            .unwrap_or_default();
+        groups.entry(dir).or_default().push(row);
+    }
+
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n\
+         <style>{STYLE}</style>\n</head>\n<body>\n<h1>{title}</h1>\n<table>\n"
+    );
+    for (name, value) in summary {
+        let _ = writeln!(
+            out,
+            "<tr><th>{name}</th><td>{}</td></tr>",
+            escape_html(value)
+        );
+    }
+    out.push_str("</table>\n");
+    for (dir, rows) in groups {
+        let _ = writeln!(
+            out,
+            "<details open>\n<summary>{} ({})</summary>\n<table>",
+            escape_html(&dir),
+            rows.len()
+        );
+        out.push_str("<tr>");
+        for name in header {
+            let _ = write!(out, "<th>{name}</th>");
+        }
+        out.push_str("</tr>\n");
+        for row in rows {
+            match class_column {
+                Some(column) => {
+                    let _ = write!(out, "<tr class=\"{}\">", escape_html(&row[column]));
+                }
+                None => out.push_str("<tr>"),
+            }
+            for cell in row {
+                let _ = write!(out, "<td>{}</td>", escape_html(cell));
+            }
+            out.push_str("</tr>\n");
+        }
+        out.push_str("</table>\n</details>\n");
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::test_snapshot;
+
+    #[test]
+    fn compare_reports() {
+        let left = test_snapshot(
+            "/etc",
+            "a",
+            0,
+            &[("/etc/passwd", 1), ("/etc/ssh/sshd_config", 2)],
+        );
+        let right = test_snapshot("/etc", "b", 0, &[("/etc/passwd", 3), ("/etc/a,\"b\"", 4)]);
+        let result = SnapshotCompareResult {
+            created: vec!["/etc/a,\"b\"".to_string()],
+            deleted: vec!["/etc/ssh/sshd_config".to_string()],
+            changed: vec!["/etc/passwd".to_string()],
+        };
+        let report = CompareReport::new(&result, &left, &right).unwrap();
+
+        let json: serde_json::Value =
+            serde_json::from_str(&render_compare(&report, ReportFormat::Json).unwrap()).unwrap();
+        assert_eq!(json["summary"]["changed"], 1);
+        assert_eq!(json["changes"][2]["old_digest"], "01");
+
+        let csv = render_compare(&report, ReportFormat::Csv).unwrap();
+        assert_eq!(
+            csv,
+            "change,path,old_digest,new_digest\r\n\
+             created,\"/etc/a,\"\"b\"\"\",,04\r\n\
+             deleted,/etc/ssh/sshd_config,02,\r\n\
+             changed,/etc/passwd,01,03\r\n"
+        );
+
+        let markdown = render_compare(&report, ReportFormat::Markdown).unwrap();
+        assert!(markdown.contains("| Created | 1 |"));
+        assert!(markdown.contains("| deleted | /etc/ssh/sshd\\_config | 02 |  |"));
+
+        let html = render_compare(&report, ReportFormat::Html).unwrap();
+        assert!(html.contains("<summary>/etc (2)</summary>"));
+        assert!(html.contains("<summary>/etc/ssh (1)</summary>"));
+        assert!(html.contains("<td>/etc/a,&quot;b&quot;</td>"));
+        assert!(html.contains("<tr class=\"changed\">"));
+
+        let snapshot_report = SnapshotReport::new(&right).unwrap();
+        assert_eq!(snapshot_report.bytes, 2);
+        let csv = render_snapshot(&snapshot_report, ReportFormat::Csv).unwrap();
+        assert!(csv.starts_with("path,check_sum,size,mtime\r\n\"/etc/a,\"\"b\"\"\",04,1,0\r\n"));
+        let html = render_snapshot(&snapshot_report, ReportFormat::Html).unwrap();
+        assert!(html.contains("<summary>/etc (2)</summary>"));
+        assert!(!html.contains("<tr class="));
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(escape_csv("plain"), "plain");
+        assert_eq!(escape_csv("line\nbreak"), "\"line\nbreak\"");
+        assert_eq!(escape_markdown("a|b_c\nd"), "a\\|b\\_c d");
+        assert_eq!(
+            escape_html("<a href='x'>&</a>"),
+            "&lt;a href=&#39;x&#39;&gt;&amp;&lt;/a&gt;"
+        );
+        assert_eq!(
+            "MD".parse::<ReportFormat>().unwrap(),
+            ReportFormat::Markdown
+        );
+        assert!("pdf".parse::<ReportFormat>().is_err());
+    }
+}
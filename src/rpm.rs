@@ -0,0 +1,705 @@
+use crate::hasher::{hash_path, HashType};
+use crate::snapshot::{FileMetadata, Snapshot};
+use crate::util::owner_name;
+use anyhow::{anyhow, Error};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+const LEAD_SIZE: usize = 96;
+const HEADER_MAGIC: [u8; 3] = [0x8e, 0xad, 0xe8];
+
+const TAG_NAME: u32 = 1000;
+const TAG_VERSION: u32 = 1001;
+const TAG_RELEASE: u32 = 1002;
+const TAG_EPOCH: u32 = 1003;
+const TAG_ARCH: u32 = 1022;
+const TAG_OLDFILENAMES: u32 = 1027;
+const TAG_FILESIZES: u32 = 1028;
+const TAG_FILEMODES: u32 = 1030;
+const TAG_FILEMTIMES: u32 = 1034;
+const TAG_FILEDIGESTS: u32 = 1035;
+const TAG_FILELINKTOS: u32 = 1036;
+const TAG_FILEFLAGS: u32 = 1037;
+const TAG_FILEUSERNAME: u32 = 1039;
+const TAG_FILEGROUPNAME: u32 = 1040;
+const TAG_DIRINDEXES: u32 = 1116;
+const TAG_BASENAMES: u32 = 1117;
+const TAG_DIRNAMES: u32 = 1118;
+const TAG_LONGFILESIZES: u32 = 5008;
+const TAG_FILEDIGESTALGO: u32 = 5011;
+
+const RPMFILE_CONFIG: u32 = 1 << 0;
+const RPMFILE_GHOST: u32 = 1 << 6;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+/// One file of a package as recorded in its header.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RpmFile {
+    pub path: String,
+    pub size: u64,
+    pub mtime: i64,
+    /// Empty for directories, symlinks and other files without contents.
+    pub digest: Vec<u8>,
+    /// Full `st_mode`, file type included.
+    pub mode: u32,
+    pub user: String,
+    pub group: String,
+    pub config: bool,
+    /// `%ghost` files are owned but not shipped, so they are not checked.
+    pub ghost: bool,
+    pub link: String,
+}
+
+impl RpmFile {
+    pub fn is_regular(&self) -> bool {
+        self.mode & S_IFMT == S_IFREG
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RpmPackage {
+    /// `name-[epoch:]version-release.arch`, or whatever header line a dump used.
+    pub name: String,
+    /// The digest algorithm of `files`, `None` when no file has a digest.
+    pub hash_type: Option<HashType>,
+    pub files: Vec<RpmFile>,
+}
+
+/// RPM digest algorithm ids from `FILEDIGESTALGO`; packages without the tag use MD5.
+fn digest_algo(id: u32) -> Result<HashType, Error> {
+    match id {
+        1 => Ok(HashType::MD5),
+        8 => Ok(HashType::SHA256),
+        _ => Err(anyhow!("unsupported rpm digest algorithm {id}")),
+    }
+}
+
+/// Guess the algorithm from the length of a hex digest.
+fn digest_hash_type(digest: &[u8]) -> Option<HashType> {
+    match digest.len() {
+        16 => Some(HashType::MD5),
+        32 => Some(HashType::SHA256),
+        _ => None,
+    }
+}
+
+enum TagValue {
+    Ints(Vec<u64>),
+    Strings(Vec<String>),
+}
+
+/// The index of an rpm header structure, tag to value.
+struct Header {
+    tags: HashMap<u32, TagValue>,
+}
+
+impl Header {
+    /// Parse the header starting at `bytes[0]`, returning it and its length in bytes.
+    fn parse(bytes: &[u8]) -> Result<(Header, usize), Error> {
+        let be32 = |at: usize| -> Result<u32, Error> {
+            let slice = bytes
+                .get(at..at + 4)
+                .ok_or_else(|| anyhow!("rpm header is truncated"))?;
+            Ok(u32::from_be_bytes(slice.try_into()?))
+        };
+        if bytes.get(..3) != Some(&HEADER_MAGIC[..]) {
+            return Err(anyhow!("bad rpm header magic"));
+        }
+        let count = be32(8)? as usize;
+        let data_len = be32(12)? as usize;
+        let data_start = 16 + count * 16;
+        let data = bytes
+            .get(data_start..data_start + data_len)
+            .ok_or_else(|| anyhow!("rpm header is truncated"))?;
+
+        let mut tags = HashMap::new();
+        for index in 0..count {
+            let entry = 16 + index * 16;
+            let (tag, kind) = (be32(entry)?, be32(entry + 4)?);
+            let (offset, n) = (be32(entry + 8)? as usize, be32(entry + 12)? as usize);
+            let field = data
+                .get(offset..)
+                .ok_or_else(|| anyhow!("rpm tag {tag} points outside the header"))?;
+            let ints = |width: usize| -> Result<TagValue, Error> {
+                let raw = field
+                    .get(..n * width)
+                    .ok_or_else(|| anyhow!("rpm tag {tag} is truncated"))?;
+                Ok(TagValue::Ints(
+                    raw.chunks_exact(width)
+                        .map(|c| c.iter().fold(0u64, |acc, b| acc << 8 | *b as u64))
+                        .collect(),
+                ))
+            };
+            let value = match kind {
+                // char, int8, int16, int32, int64
+                1 | 2 => ints(1)?,
+                3 => ints(2)?,
+                4 => ints(4)?,
+                5 => ints(8)?,
+                // string, string array, i18n string
+                6 | 8 | 9 => {
+                    let n = if kind == 6 { 1 } else { n };
+                    let strings: Vec<String> = field
+                        .split(|b| *b == 0)
+                        .take(n)
+                        .map(|s| String::from_utf8_lossy(s).to_string())
+                        .collect();
+                    if strings.len() != n {
+                        return Err(anyhow!("rpm tag {tag} is truncated"));
+                    }
+                    TagValue::Strings(strings)
+                }
+                _ => continue,
+            };
+            tags.insert(tag, value);
+        }
+        Ok((Header { tags }, data_start + data_len))
+    }
+
+    fn ints(&self, tag: u32) -> &[u64] {
+        match self.tags.get(&tag) {
+            Some(TagValue::Ints(values)) => values,
+            _ => &[],
+        }
+    }
+
+    fn strings(&self, tag: u32) -> &[String] {
+        match self.tags.get(&tag) {
+            Some(TagValue::Strings(values)) => values,
+            _ => &[],
+        }
+    }
+
+    fn string(&self, tag: u32) -> Option<&str> {
+        self.strings(tag).first().map(String::as_str)
+    }
+}
+
+/// Read the file list of a package from the main header of an `.rpm` file. The payload is not
+/// touched.
+pub fn parse_rpm(bytes: &[u8]) -> Result<RpmPackage, Error> {
+    if bytes.get(..4) != Some(&[0xed, 0xab, 0xee, 0xdb][..]) {
+        return Err(anyhow!("not an rpm file"));
+    }
+    let (_, signature_len) = Header::parse(bytes.get(LEAD_SIZE..).unwrap_or_default())?;
+    // the signature header is padded to a multiple of 8 bytes
+    let start = LEAD_SIZE + signature_len.div_ceil(8) * 8;
+    let (header, _) = Header::parse(bytes.get(start..).unwrap_or_default())?;
+
+    let mut name = format!(
+        "{}-{}{}-{}",
+        header.string(TAG_NAME).unwrap_or_default(),
+        header
+            .ints(TAG_EPOCH)
+            .first()
+            .map(|epoch| format!("{epoch}:"))
+            .unwrap_or_default(),
+        header.string(TAG_VERSION).unwrap_or_default(),
+        header.string(TAG_RELEASE).unwrap_or_default(),
+    );
+    if let Some(arch) = header.string(TAG_ARCH) {
+        name = format!("{name}.{arch}");
+    }
+
+    let paths: Vec<String> = if header.strings(TAG_BASENAMES).is_empty() {
+        header.strings(TAG_OLDFILENAMES).to_vec()
+    } else {
+        let dirs = header.strings(TAG_DIRNAMES);
+        header
+            .strings(TAG_BASENAMES)
+            .iter()
+            .zip(header.ints(TAG_DIRINDEXES))
+            .map(|(base, dir)| {
+                let dir = dirs.get(*dir as usize).map(String::as_str).unwrap_or("/");
+                format!("{dir}{base}")
+            })
+            .collect()
+    };
+    let hash_type = match header.ints(TAG_FILEDIGESTALGO).first() {
+        Some(id) => digest_algo(*id as u32)?,
+        None => HashType::MD5,
+    };
+    let sizes = match header.ints(TAG_LONGFILESIZES) {
+        [] => header.ints(TAG_FILESIZES),
+        long => long,
+    };
+    let at = |values: &[u64], index: usize| values.get(index).copied().unwrap_or(0);
+    let string_at =
+        |values: &[String], index: usize| values.get(index).cloned().unwrap_or_default();
+
+    let mut files = vec![];
+    for (index, path) in paths.into_iter().enumerate() {
+        let digest = header
+            .strings(TAG_FILEDIGESTS)
+            .get(index)
+            .filter(|digest| !digest.is_empty())
+            .map(hex::decode)
+            .transpose()
+            .map_err(|e| anyhow!("{path}: {e}"))?
+            .unwrap_or_default();
+        let flags = at(header.ints(TAG_FILEFLAGS), index) as u32;
+        files.push(RpmFile {
+            path,
+            size: at(sizes, index),
+            mtime: at(header.ints(TAG_FILEMTIMES), index) as i64,
+            digest,
+            mode: at(header.ints(TAG_FILEMODES), index) as u32,
+            user: string_at(header.strings(TAG_FILEUSERNAME), index),
+            group: string_at(header.strings(TAG_FILEGROUPNAME), index),
+            config: flags & RPMFILE_CONFIG != 0,
+            ghost: flags & RPMFILE_GHOST != 0,
+            link: string_at(header.strings(TAG_FILELINKTOS), index),
+        });
+    }
+    let has_digests = files.iter().any(|file| !file.digest.is_empty());
+    Ok(RpmPackage {
+        name,
+        hash_type: has_digests.then_some(hash_type),
+        files,
+    })
+}
+
+pub fn read_rpm(path: &Path) -> Result<RpmPackage, Error> {
+    parse_rpm(&fs::read(path)?).map_err(|e| anyhow!("{}: {e}", path.display()))
+}
+
+/// Parse `rpm -q --dump` output. Each file line reads
+/// `path size mtime digest mode owner group isconfig isdoc rdev symlink`; any other line names
+/// the package of the lines that follow, as printed by
+/// `rpm -qa --queryformat '%{NEVRA}\n' --dump`. Plain `rpm -qa --dump` output, which has no
+/// package lines, ends up in a single package with an empty name.
+pub fn parse_dump(contents: &str) -> Result<Vec<RpmPackage>, Error> {
+    let mut packages: Vec<RpmPackage> = vec![];
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim_end();
+        if line.is_empty() {
+            continue;
+        }
+        if !line.starts_with('/') {
+            packages.push(RpmPackage {
+                name: line.trim().to_string(),
+                ..Default::default()
+            });
+            continue;
+        }
+        let fields: Vec<&str> = line.split(' ').collect();
+        if fields.len() < 11 {
+            return Err(anyhow!("line {}: expected 11 fields: {line}", number + 1));
+        }
+        // only the path may hold spaces
+        let extra = fields.len() - 11;
+        let path = fields[..=extra].join(" ");
+        let field = |index: usize| fields[extra + index];
+        let parse_error = |what: &str| anyhow!("line {}: bad {what}: {line}", number + 1);
+
+        let digest = hex::decode(field(3)).map_err(|_| parse_error("digest"))?;
+        let digest = match digest.iter().all(|b| *b == 0) {
+            true => vec![],
+            false => digest,
+        };
+        let file = RpmFile {
+            path,
+            size: field(1).parse().map_err(|_| parse_error("size"))?,
+            mtime: field(2).parse().map_err(|_| parse_error("mtime"))?,
+            mode: u32::from_str_radix(field(4), 8).map_err(|_| parse_error("mode"))?,
+            user: field(5).to_string(),
+            group: field(6).to_string(),
+            config: field(7) == "1",
+            ghost: false,
+            link: match field(10) {
+                "X" => String::new(),
+                link => link.to_string(),
+            },
+            digest,
+        };
+        if packages.is_empty() {
+            packages.push(RpmPackage::default());
+        }
+        let package = packages.last_mut().expect("a package was just pushed");
+        if let Some(hash_type) = digest_hash_type(&file.digest) {
+            match package.hash_type {
+                Some(existing) if existing != hash_type => {
+                    return Err(anyhow!(
+                        "line {}: {} mixes digest algorithms",
+                        number + 1,
+                        package.name
+                    ))
+                }
+                _ => package.hash_type = Some(hash_type),
+            }
+        } else if !file.digest.is_empty() {
+            return Err(parse_error("digest"));
+        }
+        package.files.push(file);
+    }
+    Ok(packages)
+}
+
+fn under_root(root: &Path, path: &str) -> PathBuf {
+    root.join(path.trim_start_matches('/'))
+}
+
+/// A baseline snapshot of the regular files of `packages`, for use with
+/// [`crate::verify::verify_paths`] or [`crate::snapshot::compare_hashes`]. Every package with
+/// digests has to use the same algorithm.
+pub fn rpm_baseline(packages: &[RpmPackage], root: &Path) -> Result<Snapshot, Error> {
+    let root_path = root.to_str().ok_or_else(|| anyhow!("cannot parse path"))?;
+    let mut hash_types = packages.iter().filter_map(|package| package.hash_type);
+    let hash_type = hash_types.next().unwrap_or(HashType::SHA256);
+    if hash_types.any(|other| other != hash_type) {
+        return Err(anyhow!("packages use different digest algorithms"));
+    }
+    let mut entries = vec![];
+    for package in packages {
+        for file in &package.files {
+            if !file.is_regular() || file.ghost || file.digest.is_empty() {
+                continue;
+            }
+            let path = under_root(root, &file.path);
+            entries.push(FileMetadata {
+                path: path
+                    .to_str()
+                    .ok_or_else(|| anyhow!("cannot parse path"))?
+                    .to_string(),
+                check_sum: file.digest.clone(),
+                size: file.size,
+                mtime: file.mtime,
+                ..Default::default()
+            });
+        }
+    }
+    Ok(Snapshot::from_entries(root_path, hash_type, entries))
+}
+
+/// A file that differs from its package, described like `rpm -V` does.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RpmFinding {
+    pub path: String,
+    pub config: bool,
+    pub missing: bool,
+    /// `SM5DLUGTP`, each letter a differing size, mode, digest, device, link target, user,
+    /// group, mtime or capabilities, `.` where they match. Device numbers and capabilities are
+    /// not recorded here and always match.
+    pub flags: String,
+}
+
+impl RpmFinding {
+    /// The line `rpm -V` would print.
+    pub fn line(&self) -> String {
+        let kind = if self.config { 'c' } else { ' ' };
+        match self.missing {
+            true => format!("missing   {kind} {}", self.path),
+            false => format!("{}  {kind} {}", self.flags, self.path),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RpmPackageReport {
+    pub package: String,
+    pub checked: u64,
+    pub findings: Vec<RpmFinding>,
+    /// `(path, message)` for files that could not be read.
+    pub errors: Vec<(String, String)>,
+}
+
+impl RpmPackageReport {
+    /// Whether every file other than config files matches.
+    pub fn is_clean(&self) -> bool {
+        self.findings.iter().all(|finding| finding.config)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RpmReport {
+    pub packages_checked: u64,
+    pub files_checked: u64,
+    /// Only packages with findings or unreadable files.
+    pub packages: Vec<RpmPackageReport>,
+}
+
+impl RpmReport {
+    pub fn is_clean(&self) -> bool {
+        self.packages.iter().all(RpmPackageReport::is_clean)
+    }
+}
+
+/// Check the files of `packages` installed under `root` the way `rpm -V` does: digest, size,
+/// mode, owner, group, mtime and link target. User and group names are resolved with the
+/// `etc/passwd` and `etc/group` files below `root`.
+pub fn verify_rpm_packages(
+    packages: &[RpmPackage],
+    root: &Path,
+    verbose: bool,
+) -> Result<RpmReport, Error> {
+    let passwd = fs::read_to_string(root.join("etc/passwd")).unwrap_or_default();
+    let group = fs::read_to_string(root.join("etc/group")).unwrap_or_default();
+    let mut report = RpmReport {
+        packages_checked: packages.len() as u64,
+        ..Default::default()
+    };
+    for package in packages {
+        let mut package_report = RpmPackageReport {
+            package: package.name.clone(),
+            ..Default::default()
+        };
+        for file in package.files.iter().filter(|file| !file.ghost) {
+            package_report.checked += 1;
+            let full_path = under_root(root, &file.path);
+            let Ok(metadata) = fs::symlink_metadata(&full_path) else {
+                package_report.findings.push(RpmFinding {
+                    path: file.path.clone(),
+                    config: file.config,
+                    missing: true,
+                    flags: String::new(),
+                });
+                continue;
+            };
+            let regular = file.is_regular();
+            let digest_differs = match (regular, hash_type_of(package, file)) {
+                (true, Some(hash_type)) => match hash_path(&full_path, hash_type, verbose) {
+                    Ok(actual) => actual.check_sum != file.digest,
+                    Err(e) => {
+                        package_report
+                            .errors
+                            .push((file.path.clone(), e.to_string()));
+                        continue;
+                    }
+                },
+                _ => false,
+            };
+            let link_differs = file.mode & S_IFMT == S_IFLNK
+                && fs::read_link(&full_path)
+                    .map(|target| target.to_string_lossy() != file.link)
+                    .unwrap_or(true);
+            let user = owner_name(&passwd, metadata.uid()).unwrap_or(metadata.uid().to_string());
+            let group = owner_name(&group, metadata.gid()).unwrap_or(metadata.gid().to_string());
+            let flags: String = [
+                ('S', regular && metadata.size() != file.size),
+                ('M', metadata.mode() != file.mode),
+                ('5', digest_differs),
+                ('D', false),
+                ('L', link_differs),
+                ('U', !file.user.is_empty() && user != file.user),
+                ('G', !file.group.is_empty() && group != file.group),
+                ('T', regular && metadata.mtime() != file.mtime),
+                ('P', false),
+            ]
+            .iter()
+            .map(|(flag, differs)| if *differs { *flag } else { '.' })
+            .collect();
+            if flags.chars().any(|c| c != '.') {
+                package_report.findings.push(RpmFinding {
+                    path: file.path.clone(),
+                    config: file.config,
+                    missing: false,
+                    flags,
+                });
+            }
+        }
+        report.files_checked += package_report.checked;
+        if !package_report.findings.is_empty() || !package_report.errors.is_empty() {
+            report.packages.push(package_report);
+        }
+    }
+    Ok(report)
+}
+
+fn hash_type_of(package: &RpmPackage, file: &RpmFile) -> Option<HashType> {
+    if file.digest.is_empty() {
+        return None;
+    }
+    package.hash_type.or_else(|| digest_hash_type(&file.digest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verify::{verify, VerifyOptions};
+    use std::os::unix::fs::PermissionsExt;
+
+    /// An rpm header holding `tags`, each `(tag, type, count, data)`.
+    fn header(tags: &[(u32, u32, u32, Vec<u8>)]) -> Vec<u8> {
+        let mut index = vec![];
+        let mut data = vec![];
+        for (tag, kind, count, value) in tags {
+            // int32 values are aligned to 4 bytes
+            while *kind == 4 && !data.len().is_multiple_of(4) {
+                data.push(0);
+            }
+            for field in [*tag, *kind, data.len() as u32, *count] {
+                index.extend(field.to_be_bytes());
+            }
+            data.extend(value);
+        }
+        let mut bytes = vec![0x8e, 0xad, 0xe8, 0x01, 0, 0, 0, 0];
+        bytes.extend((tags.len() as u32).to_be_bytes());
+        bytes.extend((data.len() as u32).to_be_bytes());
+        bytes.extend(index);
+        bytes.extend(data);
+        bytes
+    }
+
+    fn strings(values: &[&str]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|s| [s.as_bytes(), &[0]].concat())
+            .collect()
+    }
+
+    fn int32s(values: &[u32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_be_bytes()).collect()
+    }
+
+    fn sha256(contents: &str) -> String {
+        hex::encode(<sha2::Sha256 as sha2::Digest>::digest(contents))
+    }
+
+    #[test]
+    fn parses_rpm_headers() {
+        let mut rpm = vec![0u8; LEAD_SIZE];
+        rpm[..4].copy_from_slice(&[0xed, 0xab, 0xee, 0xdb]);
+        // a 5 byte signature store leaves the signature header unaligned
+        rpm.extend(header(&[(1000, 7, 5, vec![1, 2, 3, 4, 5])]));
+        while !rpm.len().is_multiple_of(8) {
+            rpm.push(0);
+        }
+        rpm.extend(header(&[
+            (TAG_NAME, 6, 1, strings(&["hello"])),
+            (TAG_VERSION, 6, 1, strings(&["2.12"])),
+            (TAG_RELEASE, 6, 1, strings(&["1.el9"])),
+            (TAG_ARCH, 6, 1, strings(&["x86_64"])),
+            (TAG_DIRNAMES, 8, 2, strings(&["/usr/bin/", "/etc/"])),
+            (TAG_BASENAMES, 8, 2, strings(&["hello", "hello.conf"])),
+            (TAG_DIRINDEXES, 4, 2, int32s(&[0, 1])),
+            (TAG_FILESIZES, 4, 2, int32s(&[5, 3])),
+            (
+                TAG_FILEMODES,
+                3,
+                2,
+                [0o100755u16, 0o100644]
+                    .iter()
+                    .flat_map(|m| m.to_be_bytes())
+                    .collect(),
+            ),
+            (TAG_FILEMTIMES, 4, 2, int32s(&[1700000000, 1700000001])),
+            (
+                TAG_FILEDIGESTS,
+                8,
+                2,
+                strings(&[&sha256("hello"), &sha256("a=1")]),
+            ),
+            (TAG_FILEFLAGS, 4, 2, int32s(&[0, RPMFILE_CONFIG])),
+            (TAG_FILEUSERNAME, 8, 2, strings(&["root", "root"])),
+            (TAG_FILEGROUPNAME, 8, 2, strings(&["root", "wheel"])),
+            (TAG_FILEDIGESTALGO, 4, 1, int32s(&[8])),
+        ]));
+
+        let package = parse_rpm(&rpm).unwrap();
+        assert_eq!(package.name, "hello-2.12-1.el9.x86_64");
+        assert_eq!(package.hash_type, Some(HashType::SHA256));
+        assert_eq!(package.files.len(), 2);
+        let conf = &package.files[1];
+        assert_eq!(conf.path, "/etc/hello.conf");
+        assert_eq!(conf.size, 3);
+        assert_eq!(conf.mode, 0o100644);
+        assert_eq!(conf.mtime, 1700000001);
+        assert_eq!(conf.group, "wheel");
+        assert!(conf.config);
+        assert_eq!(hex::encode(&package.files[0].digest), sha256("hello"));
+
+        assert!(parse_rpm(&rpm[..200]).is_err());
+        assert!(parse_rpm(b"not an rpm").is_err());
+    }
+
+    #[test]
+    fn verifies_dumped_packages() {
+        let root = Path::new("./target/build/test_rpm");
+        assert!(!root.exists());
+        fs::create_dir_all(root.join("usr/bin")).unwrap();
+        fs::create_dir_all(root.join("etc")).unwrap();
+        fs::write(root.join("usr/bin/hello"), "hello").unwrap();
+        fs::write(root.join("etc/hello.conf"), "a=2").unwrap();
+        fs::write(root.join("usr/bin/tool"), "tool").unwrap();
+        std::os::unix::fs::symlink("hello", root.join("usr/bin/hi")).unwrap();
+        fs::set_permissions(
+            root.join("usr/bin/hello"),
+            fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+        fs::set_permissions(root.join("usr/bin/tool"), fs::Permissions::from_mode(0o755)).unwrap();
+        let meta = |path: &str| fs::metadata(root.join(path)).unwrap();
+        let (uid, gid) = (meta("usr/bin/hello").uid(), meta("usr/bin/hello").gid());
+        let passwd = format!("root:x:0:0:root:/root:/bin/sh\nbuilder:x:{uid}:{gid}::/:/bin/sh\n");
+        let groups = format!("root:x:0:\nbuilder:x:{gid}:\n");
+        fs::write(root.join("etc/passwd"), &passwd).unwrap();
+        fs::write(root.join("etc/group"), &groups).unwrap();
+        let user = owner_name(&passwd, uid).unwrap();
+        let group = owner_name(&groups, gid).unwrap();
+
+        let dump = format!(
+            "hello-2.12-1.el9.x86_64\n\
+             /usr/bin/hello 5 {} {} 0100755 {user} {group} 0 0 0 X\n\
+             /etc/hello.conf 3 {} {} 0100644 {user} {group} 1 0 0 X\n\
+             /usr/bin/hi 5 {} {} 0120777 {user} {group} 0 0 0 hello\n\
+             /usr/share/doc/hello 4096 0 {} 040755 {user} {group} 0 0 0 X\n\
+             tools-1.0-1.noarch\n\
+             /usr/bin/tool 4 {} {} 0100644 {user} {group} 0 0 0 X\n",
+            meta("usr/bin/hello").mtime(),
+            sha256("hello"),
+            meta("etc/hello.conf").mtime(),
+            sha256("a=1"),
+            meta("usr/bin/hello").mtime(),
+            "0".repeat(64),
+            "0".repeat(64),
+            meta("usr/bin/tool").mtime(),
+            sha256("tool"),
+        );
+        let packages = parse_dump(&dump).unwrap();
+        assert_eq!(packages.len(), 2);
+        assert_eq!(packages[0].name, "hello-2.12-1.el9.x86_64");
+        assert_eq!(packages[0].files.len(), 4);
+        assert_eq!(packages[0].files[2].link, "hello");
+        assert!(packages[0].files[3].digest.is_empty());
+
+        let report = verify_rpm_packages(&packages, root, false).unwrap();
+        assert_eq!(report.files_checked, 5);
+        assert_eq!(report.packages.len(), 2);
+        let hello = &report.packages[0];
+        let lines: Vec<String> = hello.findings.iter().map(RpmFinding::line).collect();
+        assert_eq!(
+            lines,
+            [
+                "..5......  c /etc/hello.conf",
+                "missing     /usr/share/doc/hello"
+            ]
+        );
+        assert!(!hello.is_clean());
+        assert_eq!(
+            report.packages[1].findings[0].line(),
+            ".M.......    /usr/bin/tool"
+        );
+
+        let baseline = rpm_baseline(&packages, root).unwrap();
+        assert_eq!(baseline.hash_type, HashType::SHA256);
+        assert_eq!(baseline.file_hashes.lock().unwrap().len(), 3);
+        let mut changed = vec![];
+        verify(&baseline, &VerifyOptions::default(), false, |event| {
+            if let crate::verify::VerifyEvent::Changed { path, .. } = event {
+                changed.push(path.clone())
+            }
+        })
+        .unwrap();
+        assert_eq!(changed.len(), 1);
+        assert!(changed[0].ends_with("etc/hello.conf"));
+
+        assert!(parse_dump("/usr/bin/x 1 2\n").is_err());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,429 @@
+use crate::hasher::HashType;
+use crate::snapshot::{FileMetadata, Snapshot, SnapshotCompareResult};
+use crate::syslog::hostname;
+use crate::util::owner_name;
+use anyhow::{anyhow, Error};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::{json, Map, Value};
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::str::FromStr;
+
+pub const ECS_VERSION: &str = "8.11.0";
+const CEF_VENDOR: &str = "fshash";
+const CEF_PRODUCT: &str = "filesystem-hashing";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SiemFormat {
+    Ecs,
+    Cef,
+}
+
+impl FromStr for SiemFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ecs" => Ok(SiemFormat::Ecs),
+            "cef" => Ok(SiemFormat::Cef),
+            _ => Err(anyhow!("unknown siem format: {s}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SiemOptions {
+    pub hostname: String,
+    /// Time the events are stamped with.
+    pub timestamp: DateTime<Utc>,
+}
+
+impl Default for SiemOptions {
+    fn default() -> Self {
+        SiemOptions {
+            hostname: hostname(),
+            timestamp: Utc::now(),
+        }
+    }
+}
+
+/// One change of a compare result with the file's attributes. Deleted files are described by
+/// the left snapshot, everything else by the right one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEvent {
+    /// The ECS `event.action`: `creation`, `deletion` or `change`.
+    pub action: &'static str,
+    pub root_path: String,
+    pub path: String,
+    pub hash_type: HashType,
+    pub digest: String,
+    pub size: u64,
+    pub mtime: i64,
+    /// Digest in the left snapshot of a changed file.
+    pub old_digest: Option<String>,
+    /// Owner recorded in the entry's attributes or, for an entry taken from this disk, of the file
+    /// on disk. `None` when neither is known.
+    pub uid: Option<u32>,
+    pub owner: Option<String>,
+}
+
+/// Created files first, then deleted, then changed, each sorted by path.
+pub fn file_events(
+    result: &SnapshotCompareResult,
+    left: &Snapshot,
+    right: &Snapshot,
+) -> Result<Vec<FileEvent>, Error> {
+    let left_hashes = left
+        .file_hashes
+        .lock()
+        .map_err(|_| anyhow!("unable to lock snapshot"))?;
+    let right_hashes = right
+        .file_hashes
+        .lock()
+        .map_err(|_| anyhow!("unable to lock snapshot"))?;
+    let passwd = fs::read_to_string("/etc/passwd").unwrap_or_default();
+
+    let mut events = vec![];
+    for (action, paths) in [
+        ("creation", &result.created),
+        ("deletion", &result.deleted),
+        ("change", &result.changed),
+    ] {
+        let mut paths = paths.clone();
+        paths.sort();
+        for path in paths {
+            let (snapshot, entry) = match action {
+                "deletion" => (left, left_hashes.get(&path)),
+                _ => (right, right_hashes.get(&path)),
+            };
+            let entry = entry.cloned().unwrap_or_else(|| FileMetadata {
+                path: path.clone(),
+                ..Default::default()
+            });
+            let old_digest = match action {
+                "change" => left_hashes
+                    .get(&path)
+                    .map(|entry| hex::encode(&entry.check_sum)),
+                _ => None,
+            };
+            let uid = match (&entry.attributes, action) {
+                (Some(attributes), _) => u32::try_from(attributes.uid).ok(),
+                (None, "deletion") => None,
+                (None, _) => live_uid(&entry),
+            };
+            events.push(FileEvent {
+                action,
+                root_path: right.root_path.clone(),
+                path,
+                hash_type: snapshot.hash_type,
+                digest: hex::encode(&entry.check_sum),
+                size: entry.size,
+                mtime: entry.mtime,
+                old_digest,
+                uid,
+                owner: uid.and_then(|uid| owner_name(&passwd, uid)),
+            });
+        }
+    }
+    Ok(events)
+}
+
+/// Owner of the file on disk, provided it is still the inode `entry` was hashed from. Entries
+/// imported from archives, manifests or package databases have no inode and are never looked up.
+fn live_uid(entry: &FileMetadata) -> Option<u32> {
+    let metadata = fs::symlink_metadata(&entry.path).ok()?;
+    (entry.ino != 0 && metadata.ino() == entry.ino).then(|| metadata.uid())
+}
+
+/// ECS has no field for SHA3, BLAKE3 or git blob digests, so those go into custom
+/// `file.hash.*` keys.
+fn ecs_hash_field(hash_type: HashType) -> &'static str {
+    match hash_type {
+        HashType::MD5 => "md5",
+        HashType::SHA3 => "sha3_256",
+        HashType::BLAKE3 => "blake3",
+        HashType::SHA256 => "sha256",
+        HashType::GITSHA1 => "git_sha1",
+    }
+}
+
+fn rfc3339(timestamp: i64) -> Option<String> {
+    DateTime::from_timestamp(timestamp, 0)
+        .map(|time| time.to_rfc3339_opts(SecondsFormat::Secs, true))
+}
+
+/// An Elastic Common Schema document for one change.
+pub fn ecs_document(event: &FileEvent, options: &SiemOptions) -> Value {
+    let path = Path::new(&event.path);
+    let mut file = Map::new();
+    file.insert("path".to_string(), json!(event.path));
+    if let Some(name) = path.file_name() {
+        file.insert("name".to_string(), json!(name.to_string_lossy()));
+    }
+    if let Some(directory) = path.parent() {
+        file.insert("directory".to_string(), json!(directory.to_string_lossy()));
+    }
+    file.insert("size".to_string(), json!(event.size));
+    if let Some(mtime) = rfc3339(event.mtime).filter(|_| event.mtime != 0) {
+        file.insert("mtime".to_string(), json!(mtime));
+    }
+    if !event.digest.is_empty() {
+        file.insert(
+            "hash".to_string(),
+            json!({ ecs_hash_field(event.hash_type): event.digest }),
+        );
+    }
+    if let Some(uid) = event.uid {
+        file.insert("uid".to_string(), json!(uid.to_string()));
+    }
+    if let Some(owner) = &event.owner {
+        file.insert("owner".to_string(), json!(owner));
+    }
+    file.insert("type".to_string(), json!("file"));
+
+    let mut fshash = json!({ "root": event.root_path });
+    if let Some(old_digest) = &event.old_digest {
+        fshash["old_digest"] = json!(old_digest);
+    }
+    json!({
+        "@timestamp": options.timestamp.to_rfc3339_opts(SecondsFormat::Millis, true),
+        "ecs": { "version": ECS_VERSION },
+        "event": {
+            "kind": "event",
+            "category": ["file"],
+            "type": [event.action],
+            "action": event.action,
+            "module": "fshash",
+            "dataset": "fshash.changes",
+        },
+        "file": file,
+        "host": { "hostname": options.hostname },
+        "message": format!("file {} {}", event.action, event.path),
+        "fshash": fshash,
+    })
+}
+
+/// Escape a CEF header field: `\` and `|` get a backslash.
+pub fn escape_cef_header(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' | '|' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\n' | '\r' => escaped.push(' '),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Escape a CEF extension value: `\` and `=` get a backslash, line breaks become `\n` and `\r`.
+pub fn escape_cef_extension(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' | '=' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// An ArcSight CEF line for one change.
+pub fn cef_line(event: &FileEvent, options: &SiemOptions) -> String {
+    let (name, severity) = match event.action {
+        "creation" => ("File created", 5),
+        "deletion" => ("File deleted", 8),
+        _ => ("File changed", 7),
+    };
+    let path = Path::new(&event.path);
+    let mut extension = vec![
+        ("rt", options.timestamp.timestamp_millis().to_string()),
+        ("dvchost", options.hostname.clone()),
+        ("act", event.action.to_string()),
+        ("filePath", event.path.clone()),
+    ];
+    if let Some(name) = path.file_name() {
+        extension.push(("fname", name.to_string_lossy().to_string()));
+    }
+    if !event.digest.is_empty() {
+        extension.push(("fileHash", event.digest.clone()));
+        extension.push(("cs1Label", "hashType".to_string()));
+        extension.push(("cs1", ecs_hash_field(event.hash_type).to_string()));
+    }
+    if let Some(old_digest) = &event.old_digest {
+        extension.push(("oldFileHash", old_digest.clone()));
+    }
+    extension.push(("fsize", event.size.to_string()));
+    if event.mtime != 0 {
+        extension.push(("fileModificationTime", (event.mtime * 1000).to_string()));
+    }
+    if let Some(owner) = &event.owner {
+        extension.push(("suser", owner.clone()));
+    }
+    extension.push(("cs2Label", "root".to_string()));
+    extension.push(("cs2", event.root_path.clone()));
+
+    let extension: Vec<String> = extension
+        .into_iter()
+        .map(|(key, value)| format!("{key}={}", escape_cef_extension(&value)))
+        .collect();
+    format!(
+        "CEF:0|{}|{}|{}|{}|{}|{severity}|{}",
+        escape_cef_header(CEF_VENDOR),
+        escape_cef_header(CEF_PRODUCT),
+        escape_cef_header(env!("CARGO_PKG_VERSION")),
+        escape_cef_header(event.action),
+        escape_cef_header(name),
+        extension.join(" ")
+    )
+}
+
+/// One line per change: an ECS JSON document or a CEF event.
+pub fn siem_lines(events: &[FileEvent], format: SiemFormat, options: &SiemOptions) -> Vec<String> {
+    events
+        .iter()
+        .map(|event| match format {
+            SiemFormat::Ecs => ecs_document(event, options).to_string(),
+            SiemFormat::Cef => cef_line(event, options),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::FileAttributes;
+
+    fn event(action: &'static str, path: &str) -> FileEvent {
+        FileEvent {
+            action,
+            root_path: "/etc".to_string(),
+            path: path.to_string(),
+            hash_type: HashType::MD5,
+            digest: "d41d8cd98f00b204e9800998ecf8427e".to_string(),
+            size: 12,
+            mtime: 1700000000,
+            old_digest: None,
+            uid: Some(0),
+            owner: Some("root".to_string()),
+        }
+    }
+
+    fn options() -> SiemOptions {
+        SiemOptions {
+            hostname: "web1".to_string(),
+            timestamp: DateTime::from_timestamp(1700000100, 0).unwrap(),
+        }
+    }
+
+    #[test]
+    fn ecs_documents() {
+        let document = ecs_document(&event("creation", "/etc/cron.d/job"), &options());
+        assert_eq!(document["@timestamp"], "2023-11-14T22:15:00.000Z");
+        assert_eq!(document["event"]["action"], "creation");
+        assert_eq!(document["event"]["type"][0], "creation");
+        assert_eq!(document["event"]["category"][0], "file");
+        assert_eq!(document["file"]["path"], "/etc/cron.d/job");
+        assert_eq!(document["file"]["name"], "job");
+        assert_eq!(document["file"]["directory"], "/etc/cron.d");
+        assert_eq!(document["file"]["size"], 12);
+        assert_eq!(document["file"]["mtime"], "2023-11-14T22:13:20Z");
+        assert_eq!(
+            document["file"]["hash"]["md5"],
+            "d41d8cd98f00b204e9800998ecf8427e"
+        );
+        assert_eq!(document["file"]["owner"], "root");
+        assert_eq!(document["host"]["hostname"], "web1");
+
+        let mut changed = event("change", "/etc/passwd");
+        changed.hash_type = HashType::SHA3;
+        changed.old_digest = Some("00".to_string());
+        let document = ecs_document(&changed, &options());
+        assert_eq!(document["event"]["type"][0], "change");
+        assert!(document["file"]["hash"]["sha3_256"].is_string());
+        assert_eq!(document["fshash"]["old_digest"], "00");
+    }
+
+    #[test]
+    fn cef_lines() {
+        let line = cef_line(&event("deletion", "/etc/a=b\\c\nd"), &options());
+        assert!(line.starts_with(&format!(
+            "CEF:0|fshash|filesystem-hashing|{}|deletion|File deleted|8|rt=1700000100000 dvchost=web1 act=deletion ",
+            env!("CARGO_PKG_VERSION")
+        )));
+        assert!(line.contains(" filePath=/etc/a\\=b\\\\c\\nd fname=a\\=b\\\\c\\nd "));
+        assert!(
+            line.contains(" fileHash=d41d8cd98f00b204e9800998ecf8427e cs1Label=hashType cs1=md5 ")
+        );
+        assert!(
+            line.ends_with(" fileModificationTime=1700000000000 suser=root cs2Label=root cs2=/etc")
+        );
+
+        assert_eq!(escape_cef_header("a|b\\c"), "a\\|b\\\\c");
+        assert_eq!(escape_cef_header("pipe|and=equals"), "pipe\\|and=equals");
+        assert_eq!(escape_cef_extension("a|b=c"), "a|b\\=c");
+        assert_eq!(escape_cef_extension("x\r\ny"), "x\\r\\ny");
+
+        let lines = siem_lines(
+            &[event("creation", "/etc/x"), event("change", "/etc/y")],
+            SiemFormat::Ecs,
+            &options(),
+        );
+        assert_eq!(lines.len(), 2);
+        assert!(!lines[0].contains('\n'));
+    }
+
+    #[test]
+    fn owners_come_from_attributes_or_the_live_file() {
+        assert!(!Path::new("./target/build/test_siem/").exists());
+        fs::create_dir_all(Path::new("./target/build/test_siem/")).unwrap();
+        fs::write("./target/build/test_siem/live", "live").unwrap();
+        fs::write("./target/build/test_siem/imported", "imported").unwrap();
+        let live = Snapshot::new(
+            Path::new("./target/build/test_siem"),
+            HashType::BLAKE3,
+            vec![],
+            false,
+        )
+        .unwrap();
+        let mut entries: Vec<FileMetadata> =
+            live.file_hashes.lock().unwrap().values().cloned().collect();
+        entries.push(FileMetadata {
+            path: "./target/build/test_siem/archived".to_string(),
+            attributes: Some(FileAttributes {
+                uid: 4242,
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        for entry in entries.iter_mut() {
+            if entry.path.ends_with("imported") {
+                entry.ino = 0;
+            }
+        }
+        let right = Snapshot::from_entries("./target/build/test_siem", HashType::BLAKE3, entries);
+        let result = SnapshotCompareResult {
+            created: vec![
+                "./target/build/test_siem/archived".to_string(),
+                "./target/build/test_siem/imported".to_string(),
+                "./target/build/test_siem/live".to_string(),
+            ],
+            ..Default::default()
+        };
+        let events = file_events(&result, &Snapshot::default(), &right).unwrap();
+        let disk_uid = fs::metadata("./target/build/test_siem/live").unwrap().uid();
+        assert_eq!(events[0].uid, Some(4242));
+        assert_eq!(events[1].uid, None);
+        assert_eq!(events[2].uid, Some(disk_uid));
+        fs::remove_dir_all(Path::new("./target/build/test_siem/")).unwrap();
+    }
+}
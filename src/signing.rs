@@ -0,0 +1,133 @@
+use anyhow::{anyhow, Error};
+use ed25519_dalek::pkcs8::spki::der::pem::LineEnding;
+use ed25519_dalek::pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePrivateKey, EncodePublicKey};
+use ed25519_dalek::{Signature, Signer, Verifier};
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
+
+/// How a signature is attached to an exported snapshot.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SignatureMode {
+    /// The signature is stored alongside the snapshot inside the exported file.
+    #[default]
+    Embedded,
+    /// The signature is written to a separate `<path>.sig` file.
+    Detached,
+}
+
+/// Result of checking a snapshot file against a trusted public key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureStatus {
+    Valid,
+    Missing,
+    Invalid,
+}
+
+pub fn generate_signing_key() -> SigningKey {
+    SigningKey::from_bytes(&rand::random::<[u8; 32]>())
+}
+
+/// Load an Ed25519 private key from a PKCS#8 PEM file, as written by
+/// `openssl genpkey -algorithm ed25519`.
+pub fn load_signing_key(path: &Path) -> Result<SigningKey, Error> {
+    let pem = fs::read_to_string(path)?;
+    SigningKey::from_pkcs8_pem(&pem)
+        .map_err(|e| anyhow!("unable to parse signing key {:?}: {}", path, e))
+}
+
+/// Load an Ed25519 public key from a SubjectPublicKeyInfo PEM file, as written by
+/// `openssl pkey -pubout`.
+pub fn load_verifying_key(path: &Path) -> Result<VerifyingKey, Error> {
+    let pem = fs::read_to_string(path)?;
+    VerifyingKey::from_public_key_pem(&pem)
+        .map_err(|e| anyhow!("unable to parse verifying key {:?}: {}", path, e))
+}
+
+/// Write an Ed25519 private key as a PKCS#8 PEM file readable only by its owner. An existing
+/// file is never overwritten.
+pub fn save_signing_key(key: &SigningKey, path: &Path) -> Result<(), Error> {
+    let pem = key
+        .to_pkcs8_pem(LineEnding::LF)
+        .map_err(|e| anyhow!("unable to encode signing key: {}", e))?;
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .map_err(|e| anyhow!("unable to create signing key {:?}: {}", path, e))?;
+    file.write_all(pem.as_bytes())?;
+    Ok(file.sync_all()?)
+}
+
+pub fn save_verifying_key(key: &VerifyingKey, path: &Path) -> Result<(), Error> {
+    let pem = key
+        .to_public_key_pem(LineEnding::LF)
+        .map_err(|e| anyhow!("unable to encode verifying key: {}", e))?;
+    Ok(fs::write(path, pem)?)
+}
+
+/// Sign `bytes`, returning the hex encoded signature.
+pub fn sign(key: &SigningKey, bytes: &[u8]) -> String {
+    hex::encode(key.sign(bytes).to_bytes())
+}
+
+/// Check a hex encoded signature over `bytes`.
+pub fn verify(key: &VerifyingKey, bytes: &[u8], signature: &str) -> bool {
+    let signature = match hex::decode(signature.trim()) {
+        Ok(s) => s,
+        Err(_) => return false,
+    };
+    match Signature::from_slice(&signature) {
+        Ok(signature) => key.verify(bytes, &signature).is_ok(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn sign_and_verify() {
+        let key = generate_signing_key();
+        let signature = sign(&key, b"snapshot");
+        assert!(verify(&key.verifying_key(), b"snapshot", &signature));
+        assert!(!verify(&key.verifying_key(), b"snapshot!", &signature));
+        assert!(!verify(
+            &generate_signing_key().verifying_key(),
+            b"snapshot",
+            &signature
+        ));
+        assert!(!verify(
+            &key.verifying_key(),
+            b"snapshot",
+            "not a signature"
+        ));
+    }
+
+    #[test]
+    fn key_files_round_trip() {
+        assert!(!Path::new("./target/build/test_signing_keys/").exists());
+        fs::create_dir_all(Path::new("./target/build/test_signing_keys/")).unwrap();
+        let key = generate_signing_key();
+        let private = Path::new("./target/build/test_signing_keys/key.pem");
+        let public = Path::new("./target/build/test_signing_keys/key.pub.pem");
+        save_signing_key(&key, private).unwrap();
+        assert_eq!(fs::metadata(private).unwrap().mode() & 0o777, 0o600);
+        assert!(save_signing_key(&generate_signing_key(), private).is_err());
+        save_verifying_key(&key.verifying_key(), public).unwrap();
+        assert_eq!(
+            load_signing_key(private).unwrap().to_bytes(),
+            key.to_bytes()
+        );
+        assert_eq!(load_verifying_key(public).unwrap(), key.verifying_key());
+        assert!(load_verifying_key(private).is_err());
+        fs::remove_dir_all(Path::new("./target/build/test_signing_keys/")).unwrap();
+    }
+}
@@ -1,16 +1,21 @@
-use crate::hasher::{hash_file, HashType};
+use crate::encryption::{self, EncryptionKey};
+use crate::hasher::{hash_path, HashType};
+use crate::mac::{self, MacKey};
+use crate::merkle;
+use crate::signing::{self, SignatureMode, SignatureStatus, SigningKey, VerifyingKey};
 use anyhow::{anyhow, Error};
-use bytes::BytesMut;
 use chrono::Utc;
-use rand::{thread_rng, Rng};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
 use std::collections::HashMap;
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
-use std::{env, fs, thread};
+use std::{env, fs, process, thread};
+use thiserror::Error;
 use walkdir::DirEntry;
 
 #[derive(Debug, Clone)]
@@ -21,8 +26,11 @@ pub struct Snapshot {
     pub hash_type: HashType,
     pub uuid: String,
     pub date_created: i64,
+    /// Hex encoded Merkle root over `file_hashes`, see [`merkle::build`]. Call
+    /// [`Snapshot::update_merkle_root`] after modifying `file_hashes` by hand.
+    pub merkle_root: String,
 }
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct FileMetadata {
     pub path: String,
     pub check_sum: Vec<u8>,
@@ -30,6 +38,30 @@ pub struct FileMetadata {
     pub ino: u64,
     pub ctime: i64,
     pub mtime: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac: Option<Vec<u8>>,
+    /// Recorded by sources that carry ownership details, such as archives; `None` for
+    /// directory walks.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attributes: Option<FileAttributes>,
+}
+
+/// File type, permissions and ownership of an entry as stored in its source.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct FileAttributes {
+    /// Full `st_mode`, file type included.
+    pub mode: u32,
+    pub uid: u64,
+    pub gid: u64,
+    /// Target of a symbolic or hard link.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<String>,
+    /// CRC32 stored for a zip member.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crc32: Option<u32>,
+    /// Zip compression method id, `0` for stored and `8` for deflated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<u16>,
 }
 
 impl Default for FileMetadata {
@@ -41,26 +73,40 @@ impl Default for FileMetadata {
             ino: 0,
             ctime: 0,
             mtime: 0,
+            mac: None,
+            attributes: None,
         }
     }
 }
 
+/// Files hashed concurrently by [`Snapshot::new`].
+pub const DEFAULT_THREADS: usize = 4;
+
 impl Snapshot {
     pub fn new(
         path: &Path,
         hash_type: HashType,
         black_list: Vec<String>,
         verbose: bool,
+    ) -> Result<Snapshot, Error> {
+        Snapshot::with_threads(path, hash_type, black_list, DEFAULT_THREADS, verbose)
+    }
+
+    /// Like [`Snapshot::new`], hashing up to `threads` files at a time.
+    pub fn with_threads(
+        path: &Path,
+        hash_type: HashType,
+        black_list: Vec<String>,
+        threads: usize,
+        verbose: bool,
     ) -> Result<Snapshot, Error> {
         let root_path = match path.to_str() {
             None => "".to_string(),
             Some(p) => p.to_string(),
         };
-        let mut rand = thread_rng();
-        let uuid_int: u128 = rand.gen();
-        let uuid = uuid_int.to_string();
+        let uuid = new_uuid();
         if verbose {
-            println!("Walking Directory: {}", path.to_str().unwrap());
+            eprintln!("Walking Directory: {}", path.to_str().unwrap());
         }
 
         let file_paths = walkdir::WalkDir::new(path).sort_by_file_name();
@@ -75,9 +121,9 @@ impl Snapshot {
             .for_each(|a| paths.push(Option::from(a)));
 
         if verbose {
-            println!("Skipping (Blacklisted): {:?}", black_list);
+            eprintln!("Skipping (Blacklisted): {:?}", black_list);
         }
-        
+
         while !paths.is_empty() {
             #[allow(clippy::collapsible_match)]
             if let Some(p) = paths.pop() {
@@ -93,18 +139,18 @@ impl Snapshot {
 
                     if p.path().is_file() && !blacklisted {
                         let bind = file_hashes.clone();
-                        let handle = thread::spawn(move || {
-                            let mut binding = bind.lock();
-                            let ht = binding.as_mut().expect("binding error");
-                            if let Err(e) = hash_file(p.path(), ht, hash_type, verbose) {
-                                println!("Warning: {e}")
-                            }
-                        });
+                        let handle =
+                            thread::spawn(move || match hash_path(p.path(), hash_type, verbose) {
+                                Ok(metadata) => {
+                                    let mut binding = bind.lock();
+                                    let ht = binding.as_mut().expect("binding error");
+                                    ht.insert(metadata.path.clone(), metadata);
+                                }
+                                Err(e) => eprintln!("Warning: {e}"),
+                            });
                         pool.push(handle);
-                        if pool.len() > 4 {
-                            if let Some(handle) = pool.pop() {
-                                handle.join().expect("could not join handle")
-                            }
+                        if pool.len() >= threads.max(1) {
+                            pool.remove(0).join().expect("could not join handle")
                         }
                     }
                 }
@@ -115,17 +161,75 @@ impl Snapshot {
             handle.join().expect("could not join handle")
         }
 
-        Ok(Snapshot {
+        let mut snapshot = Snapshot {
             file_hashes,
             black_list,
             root_path,
             hash_type,
             uuid,
             date_created: Utc::now().timestamp(),
-        })
+            merkle_root: String::new(),
+        };
+        snapshot.update_merkle_root();
+        Ok(snapshot)
+    }
+
+    /// A snapshot of `root_path` made from entries that were hashed elsewhere, such as a
+    /// checksum manifest or a package database.
+    pub fn from_entries(
+        root_path: &str,
+        hash_type: HashType,
+        entries: impl IntoIterator<Item = FileMetadata>,
+    ) -> Snapshot {
+        let file_hashes: HashMap<String, FileMetadata> = entries
+            .into_iter()
+            .map(|entry| (entry.path.clone(), entry))
+            .collect();
+        let mut snapshot = Snapshot {
+            file_hashes: Arc::new(Mutex::new(file_hashes)),
+            black_list: vec![],
+            root_path: root_path.to_string(),
+            hash_type,
+            uuid: new_uuid(),
+            date_created: Utc::now().timestamp(),
+            merkle_root: String::new(),
+        };
+        snapshot.update_merkle_root();
+        snapshot
+    }
+
+    pub fn update_merkle_root(&mut self) {
+        self.merkle_root = merkle::root_digest(self);
     }
 }
 
+/// Snapshot of `root_path` whose entries are one-byte checksums, shared by the unit tests.
+#[cfg(test)]
+pub(crate) fn test_snapshot(
+    root_path: &str,
+    uuid: &str,
+    date_created: i64,
+    files: &[(&str, u8)],
+) -> Snapshot {
+    let entries = files.iter().map(|(path, check_sum)| FileMetadata {
+        path: path.to_string(),
+        check_sum: vec![*check_sum],
+        size: 1,
+        ..Default::default()
+    });
+    Snapshot {
+        uuid: uuid.to_string(),
+        date_created,
+        ..Snapshot::from_entries(root_path, HashType::BLAKE3, entries)
+    }
+}
+
+fn new_uuid() -> String {
+    let mut rand = rand::rng();
+    let uuid_int: u128 = rand.random();
+    uuid_int.to_string()
+}
+
 impl Default for Snapshot {
     fn default() -> Self {
         let black_list: Vec<String> = vec![];
@@ -136,11 +240,12 @@ impl Default for Snapshot {
             hash_type: HashType::BLAKE3,
             uuid: "".to_string(),
             date_created: 0,
+            merkle_root: "".to_string(),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum SnapshotChangeType {
     None,
     Created,
@@ -148,13 +253,78 @@ pub enum SnapshotChangeType {
     Changed,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct SnapshotCompareResult {
     pub created: Vec<String>,
     pub deleted: Vec<String>,
     pub changed: Vec<String>,
 }
 
+/// One created, deleted or changed file of a compare result, with its digests.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ChangeRecord {
+    pub path: String,
+    pub change: &'static str,
+    /// Hex digest in the left snapshot, `None` for created files.
+    pub old_digest: Option<String>,
+    /// Hex digest in the right snapshot, `None` for deleted files.
+    pub new_digest: Option<String>,
+}
+
+pub fn change_name(change: &SnapshotChangeType) -> &'static str {
+    match change {
+        SnapshotChangeType::None => "none",
+        SnapshotChangeType::Created => "created",
+        SnapshotChangeType::Deleted => "deleted",
+        SnapshotChangeType::Changed => "changed",
+    }
+}
+
+/// Pair each path of `result` with its digests in the snapshots it was computed from; created
+/// files first, then deleted, then changed, each sorted by path.
+pub fn change_records(
+    result: &SnapshotCompareResult,
+    left: &Snapshot,
+    right: &Snapshot,
+) -> Result<Vec<ChangeRecord>, Error> {
+    let left = left
+        .file_hashes
+        .lock()
+        .map_err(|_| anyhow!("unable to lock snapshot"))?;
+    let right = right
+        .file_hashes
+        .lock()
+        .map_err(|_| anyhow!("unable to lock snapshot"))?;
+    let digest = |entry: Option<&FileMetadata>| entry.map(|entry| hex::encode(&entry.check_sum));
+
+    let mut records = vec![];
+    for (change, paths) in [
+        (SnapshotChangeType::Created, &result.created),
+        (SnapshotChangeType::Deleted, &result.deleted),
+        (SnapshotChangeType::Changed, &result.changed),
+    ] {
+        let mut paths = paths.clone();
+        paths.sort();
+        for path in paths {
+            let old_digest = match change {
+                SnapshotChangeType::Created => None,
+                _ => digest(left.get(&path)),
+            };
+            let new_digest = match change {
+                SnapshotChangeType::Deleted => None,
+                _ => digest(right.get(&path)),
+            };
+            records.push(ChangeRecord {
+                path,
+                change: change_name(&change),
+                old_digest,
+                new_digest,
+            });
+        }
+    }
+    Ok(records)
+}
+
 pub fn compare_hashes(
     left: Snapshot,
     right: Snapshot,
@@ -166,33 +336,68 @@ pub fn compare_hashes(
     let mut deleted: Vec<String> = vec![];
     let mut changed: Vec<String> = vec![];
 
-    if let Ok(left_lock) = left.file_hashes.lock() {
-        // for each entry in the hash list
-        for left_entry in left_lock.iter() {
-            if let Ok(curr_lock) = right.file_hashes.lock() {
-                match curr_lock.get(left_entry.0) {
-                    // check for mis-matching checksum between L and R
+    // Merkle roots cover root-relative paths only, so they are only comparable under the same
+    // root. They are rebuilt here rather than trusting `merkle_root`, which may be stale.
+    if left.root_path == right.root_path && !Arc::ptr_eq(&left.file_hashes, &right.file_hashes) {
+        let left_lock = left.file_hashes.lock().ok()?;
+        let right_lock = right.file_hashes.lock().ok()?;
+        let left_tree = merkle::build(&left.root_path, &left_lock);
+        let right_tree = merkle::build(&right.root_path, &right_lock);
+        // only directories whose files digest differs can hold created, deleted or changed
+        // files; identical roots yield none
+        for dir in merkle::diff_directories(&left_tree, &right_tree) {
+            let left_files = left_tree.directories.get(&dir).map(|n| n.files.as_slice());
+            let right_files = right_tree.directories.get(&dir).map(|n| n.files.as_slice());
+            for key in left_files.unwrap_or_default() {
+                match right_lock.get(key) {
                     Some(right_entry) => {
-                        if !right_entry.check_sum.eq(&left_entry.1.check_sum) {
+                        if !right_entry.check_sum.eq(&left_lock[key].check_sum) {
                             changed.push(right_entry.path.to_string());
                         }
                     }
-                    // check for deletion == files that exist in L and missing from R
-                    None => {
-                        deleted.push(left_entry.0.to_string());
+                    None => deleted.push(key.to_string()),
+                }
+            }
+            for key in right_files.unwrap_or_default() {
+                if !left_lock.contains_key(key) {
+                    created.push(key.to_string());
+                }
+            }
+        }
+    } else {
+        if let Ok(left_lock) = left.file_hashes.lock() {
+            // for each entry in the hash list
+            for left_entry in left_lock.iter() {
+                if let Ok(curr_lock) = right.file_hashes.lock() {
+                    match curr_lock.get(left_entry.0) {
+                        // check for mis-matching checksum between L and R
+                        Some(right_entry) => {
+                            if !right_entry.check_sum.eq(&left_entry.1.check_sum) {
+                                changed.push(right_entry.path.to_string());
+                            }
+                        }
+                        // check for deletion == files that exist in L and missing from R
+                        None => {
+                            deleted.push(left_entry.0.to_string());
+                        }
                     }
                 }
             }
         }
-    }
-    // check for creation == check for files that exist in R but do not exist in L
-    if let Ok(e) = right.file_hashes.lock() {
-        for right_entry in e.iter() {
-            if left.file_hashes.lock().ok()?.get(right_entry.0).is_none() {
-                created.push(right_entry.0.to_string());
+        // check for creation == check for files that exist in R but do not exist in L
+        if let Ok(e) = right.file_hashes.lock() {
+            for right_entry in e.iter() {
+                if left.file_hashes.lock().ok()?.get(right_entry.0).is_none() {
+                    created.push(right_entry.0.to_string());
+                }
             }
         }
     }
+    if verbose {
+        created.iter().for_each(|p| eprintln!("Created: {p}"));
+        deleted.iter().for_each(|p| eprintln!("Deleted: {p}"));
+        changed.iter().for_each(|p| eprintln!("Changed: {p}"));
+    }
 
     let mut return_type = SnapshotChangeType::None;
     if !created.is_empty() {
@@ -289,9 +494,40 @@ struct SerializableSnapshot {
     pub hash_type: HashType,
     pub uuid: String,
     pub date_created: i64,
+    #[serde(default)]
+    pub merkle_root: String,
+}
+
+/// Wrapper written around the serialized snapshot when it carries an embedded signature or mac.
+#[derive(Debug, Serialize, Deserialize)]
+struct SnapshotEnvelope<'a> {
+    #[serde(borrow)]
+    snapshot: &'a RawValue,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signature: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mac: Option<String>,
+}
+
+/// Split an exported file into the serialized snapshot and its embedded signature and mac.
+fn unwrap_envelope(bytes: &[u8]) -> (&[u8], Option<String>, Option<String>) {
+    match serde_json::from_slice::<SnapshotEnvelope>(bytes) {
+        Ok(envelope) => (
+            envelope.snapshot.get().as_bytes(),
+            envelope.signature,
+            envelope.mac,
+        ),
+        Err(_) => (bytes, None, None),
+    }
+}
+
+fn signature_path(full_path: &Path) -> PathBuf {
+    let mut sig = full_path.as_os_str().to_os_string();
+    sig.push(".sig");
+    PathBuf::from(sig)
 }
 
-fn path_resolve(path: String) -> String {
+pub(crate) fn path_resolve(path: String) -> String {
     #[allow(unused)]
     let mut full_path = String::new();
     if path.starts_with("./") {
@@ -310,8 +546,73 @@ fn path_resolve(path: String) -> String {
     full_path
 }
 
+#[derive(Debug, Error)]
+pub enum SnapshotError {
+    #[error("snapshot already exists: {0}")]
+    AlreadyExists(String),
+    #[error("snapshot is not signed: {0}")]
+    SignatureMissing(String),
+    #[error("snapshot signature does not match: {0}")]
+    SignatureInvalid(String),
+    #[error("snapshot is not authenticated: {0}")]
+    MacMissing(String),
+    #[error("snapshot mac does not match: {0}")]
+    MacInvalid(String),
+    #[error("snapshot is encrypted: {0}")]
+    Encrypted(String),
+    #[error("unable to decrypt snapshot: {0}")]
+    DecryptionFailed(String),
+}
+
+/// Options controlling how a snapshot is written by [`export_with_options`].
+#[derive(Debug, Clone, Default)]
+pub struct ExportOptions {
+    /// Replace an existing snapshot at the destination path.
+    pub overwrite: bool,
+    /// Keep the replaced snapshot next to the new one as `<path>.bak`.
+    pub keep_backup: bool,
+    /// Sign the serialized snapshot with this Ed25519 key.
+    pub signing_key: Option<SigningKey>,
+    /// Where the signature is stored when `signing_key` is set.
+    pub signature_mode: SignatureMode,
+    /// Authenticate every entry and the whole snapshot with this secret.
+    pub mac_key: Option<MacKey>,
+    /// Encrypt the exported file with this key.
+    pub encryption_key: Option<EncryptionKey>,
+}
+
+/// Options controlling how a snapshot is read by [`import_with_options`].
+#[derive(Debug, Clone, Default)]
+pub struct ImportOptions {
+    /// Refuse the snapshot unless it carries a valid signature from this key.
+    pub verifying_key: Option<VerifyingKey>,
+    /// Refuse the snapshot unless it and all of its entries carry a valid mac for this secret.
+    pub mac_key: Option<MacKey>,
+    /// Decrypt the snapshot with this key when it was exported encrypted.
+    pub encryption_key: Option<EncryptionKey>,
+}
+
 pub fn export(snapshot: Snapshot, path: String, overwrite: bool, verbose: bool) -> Result<(), Error> {
-    let full_path = path_resolve(path);
+    let options = ExportOptions {
+        overwrite,
+        ..Default::default()
+    };
+    export_with_options(snapshot, path, &options, verbose)
+}
+
+/// Serialize `snapshot` and write it to `path` atomically.
+///
+/// The snapshot is written to a temporary file in the destination directory, synced to disk and
+/// then renamed over the destination, so an interrupted export never leaves a truncated snapshot
+/// behind. Returns [`SnapshotError::AlreadyExists`] when the destination exists and
+/// `options.overwrite` is not set.
+pub fn export_with_options(
+    snapshot: Snapshot,
+    path: String,
+    options: &ExportOptions,
+    verbose: bool,
+) -> Result<(), Error> {
+    let full_path = PathBuf::from(path_resolve(path));
 
     let mut fh: Vec<FileMetadata> = vec![];
 
@@ -320,75 +621,321 @@ pub fn export(snapshot: Snapshot, path: String, overwrite: bool, verbose: bool)
             fh.push(entry.1.clone())
         }
     }
+    fh.sort_by(|a, b| a.path.cmp(&b.path));
+    if let Some(key) = &options.mac_key {
+        for entry in fh.iter_mut() {
+            entry.mac = Some(mac::entry_mac(key, entry));
+        }
+    }
 
+    let merkle_root = merkle::root_digest(&snapshot);
     let serializable = SerializableSnapshot {
         file_hashes: fh,
         root_path: snapshot.root_path,
         hash_type: snapshot.hash_type,
         uuid: snapshot.uuid,
         date_created: snapshot.date_created,
+        merkle_root,
     };
 
-    let serialized = serde_json::to_string(&serializable)?;
-    // println!("{:#?}", serialized);
-    let filename = full_path
-        .split('/')
-        .last()
-        .expect("unable to get full path");
-    let path_only = full_path.replace(filename, "");
-    // println!("{}", full_path);
-    // println!("{}", path_only);
-
-    if Path::new(&full_path).exists() && overwrite {
-        fs::remove_file(&full_path)?;
-        write_to_file(path_only, full_path, serialized)?
-    } else if !Path::new(&full_path).exists() {
-        write_to_file(path_only, full_path, serialized)?
+    let payload = serde_json::to_vec(&serializable)?;
+    write_payload(&full_path, payload, options, verbose)
+}
+
+/// Sign, authenticate and encrypt `payload` as configured by `options` and write it atomically.
+pub(crate) fn write_payload(
+    full_path: &Path,
+    payload: Vec<u8>,
+    options: &ExportOptions,
+    verbose: bool,
+) -> Result<(), Error> {
+    let signature = options
+        .signing_key
+        .as_ref()
+        .map(|key| signing::sign(key, &payload));
+    let (embedded_signature, detached_signature) = match options.signature_mode {
+        SignatureMode::Embedded => (signature, None),
+        SignatureMode::Detached => (None, signature),
     };
+    let snapshot_mac = options
+        .mac_key
+        .as_ref()
+        .map(|key| mac::snapshot_mac(key, &payload));
+
+    let mut serialized = if embedded_signature.is_some() || snapshot_mac.is_some() {
+        serde_json::to_vec(&SnapshotEnvelope {
+            snapshot: serde_json::from_slice::<&RawValue>(&payload)?,
+            signature: embedded_signature,
+            mac: snapshot_mac,
+        })?
+    } else {
+        payload
+    };
+    if let Some(key) = &options.encryption_key {
+        serialized = encryption::encrypt(key, &serialized)?;
+    }
+
+    if full_path.exists() && !options.overwrite {
+        return Err(SnapshotError::AlreadyExists(full_path.to_string_lossy().to_string()).into());
+    }
+
+    write_to_file(full_path, &serialized, options)?;
+    if let Some(signature) = detached_signature {
+        let sig_options = ExportOptions {
+            overwrite: true,
+            keep_backup: options.keep_backup,
+            ..Default::default()
+        };
+        write_to_file(
+            &signature_path(full_path),
+            signature.as_bytes(),
+            &sig_options,
+        )?;
+    } else {
+        // a signature left over from an earlier detached-signed export no longer matches
+        match fs::remove_file(signature_path(full_path)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    if verbose {
+        eprintln!("Exported: {}", full_path.to_string_lossy());
+    }
+    Ok(())
+}
+
+/// Write `serialized` to `full_path` through a synced temporary file that is then renamed, or
+/// hard linked when `options.overwrite` is not set, over the destination.
+pub(crate) fn write_to_file(
+    full_path: &Path,
+    serialized: &[u8],
+    options: &ExportOptions,
+) -> Result<(), Error> {
+    let path_only = match full_path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let filename = match full_path.file_name() {
+        Some(f) => f.to_string_lossy().to_string(),
+        None => return Err(anyhow!("Unable to write to path: {:?}", full_path)),
+    };
+    if fs::create_dir_all(&path_only).is_err() {
+        return Err(anyhow!("Unable to write to path: {:?}", path_only));
+    }
+
+    let tmp_path = path_only.join(format!(".{}.{}.tmp", filename, process::id()));
+    let result = write_and_replace(full_path, &tmp_path, serialized, options);
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result?;
+
+    // persist the rename itself
+    if let Ok(dir) = File::open(&path_only) {
+        dir.sync_all()?;
+    }
     Ok(())
 }
 
-fn write_to_file(path_only: String, full_path: String, serialized: String) -> Result<(), Error> {
-    if fs::create_dir_all(&path_only).is_ok() {
-        if let Ok(mut file_handle) = File::create(full_path) {
-            Ok(file_handle.write_all(serialized.as_bytes())?)
-        } else {
-            Err(anyhow!("Unable to write to path: {}", path_only.clone()))
+fn write_and_replace(
+    full_path: &Path,
+    tmp_path: &Path,
+    serialized: &[u8],
+    options: &ExportOptions,
+) -> Result<(), Error> {
+    let mut file_handle = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(tmp_path)?;
+    file_handle.write_all(serialized)?;
+    file_handle.sync_all()?;
+    drop(file_handle);
+
+    if !options.overwrite {
+        // hard_link fails if the destination appeared since the existence check
+        return match fs::hard_link(tmp_path, full_path) {
+            Ok(_) => Ok(fs::remove_file(tmp_path)?),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                Err(SnapshotError::AlreadyExists(full_path.to_string_lossy().to_string()).into())
+            }
+            Err(e) => Err(e.into()),
+        };
+    }
+
+    if options.keep_backup && full_path.exists() {
+        let mut backup = full_path.as_os_str().to_os_string();
+        backup.push(".bak");
+        let backup = PathBuf::from(backup);
+        if backup.exists() {
+            fs::remove_file(&backup)?;
+        }
+        if fs::hard_link(full_path, &backup).is_err() {
+            fs::copy(full_path, &backup)?;
         }
-    } else {
-        Err(anyhow!("Unable to write to path: {}", path_only.clone()))
     }
+
+    fs::rename(tmp_path, full_path)?;
+    Ok(())
 }
 
 pub fn import(path: String, verbose: bool) -> Result<Snapshot, Error> {
-    #[allow(unused)]
-    let buffer = BytesMut::new();
-    let full_path = path_resolve(path);
-    if let Ok(bytes) = fs::read(full_path) {
-        let snapshot = serde_json::from_slice::<SerializableSnapshot>(&bytes)?;
+    import_with_options(path, &ImportOptions::default(), verbose)
+}
 
-        let mut fh: HashMap<String, FileMetadata> = HashMap::new();
+/// Read a snapshot exported by [`export_with_options`].
+///
+/// When `options.verifying_key` is set the snapshot must carry a signature, embedded or in a
+/// `<path>.sig` file, that verifies against it; otherwise [`SnapshotError::SignatureMissing`] or
+/// [`SnapshotError::SignatureInvalid`] is returned and nothing is imported. `options.mac_key`
+/// likewise requires a valid mac over the snapshot and over each of its entries. Encrypted
+/// snapshots are decrypted with `options.encryption_key`, and refused with
+/// [`SnapshotError::Encrypted`] when no key is given.
+pub fn import_with_options(
+    path: String,
+    options: &ImportOptions,
+    verbose: bool,
+) -> Result<Snapshot, Error> {
+    let full_path = PathBuf::from(path_resolve(path));
+    let bytes = match fs::read(&full_path) {
+        Ok(bytes) => bytes,
+        Err(e) if options.verifying_key.is_some() || options.mac_key.is_some() => {
+            return Err(e.into())
+        }
+        Err(_) => return Ok(Snapshot::default()),
+    };
+    let payload = open_payload(&full_path, bytes, options, verbose)?;
+
+    let snapshot = serde_json::from_slice::<SerializableSnapshot>(&payload)?;
+    if let Some(key) = &options.mac_key {
+        if let Some(entry) = snapshot
+            .file_hashes
+            .iter()
+            .find(|entry| !mac::verify_entry(key, entry))
+        {
+            return Err(SnapshotError::MacInvalid(entry.path.clone()).into());
+        }
+    }
 
+    let mut fh: HashMap<String, FileMetadata> = HashMap::new();
+    for entry in snapshot.file_hashes {
         if verbose {
-            for entry in snapshot.file_hashes {
-                if let Some(_res) = fh.insert(entry.path.clone(), entry.clone()) {
-                    println!("successfully imported: {}", entry.path);
+            eprintln!("successfully imported: {}", entry.path);
+        }
+        fh.insert(entry.path.clone(), entry);
+    }
+
+    let black_list: Vec<String> = vec![];
+    let merkle_root = merkle::build(&snapshot.root_path, &fh).root;
+    Ok(Snapshot {
+        file_hashes: Arc::new(Mutex::new(fh)),
+        black_list,
+        root_path: snapshot.root_path,
+        hash_type: snapshot.hash_type,
+        uuid: snapshot.uuid,
+        date_created: snapshot.date_created,
+        merkle_root,
+    })
+}
+
+/// Decrypt an exported file and check its signature and mac as required by `options`, returning
+/// the serialized payload.
+pub(crate) fn open_payload(
+    full_path: &Path,
+    bytes: Vec<u8>,
+    options: &ImportOptions,
+    verbose: bool,
+) -> Result<Vec<u8>, Error> {
+    let bytes = decrypt_if_needed(full_path, bytes, options.encryption_key.as_ref())?;
+
+    let (payload, embedded_signature, snapshot_mac) = unwrap_envelope(&bytes);
+    if let Some(key) = &options.verifying_key {
+        match check_signature(full_path, key, payload, embedded_signature) {
+            SignatureStatus::Valid => {
+                if verbose {
+                    eprintln!("Verified signature: {}", full_path.to_string_lossy());
                 }
             }
+            SignatureStatus::Missing => {
+                return Err(SnapshotError::SignatureMissing(
+                    full_path.to_string_lossy().to_string(),
+                )
+                .into())
+            }
+            SignatureStatus::Invalid => {
+                return Err(SnapshotError::SignatureInvalid(
+                    full_path.to_string_lossy().to_string(),
+                )
+                .into())
+            }
         }
+    }
 
+    if let Some(key) = &options.mac_key {
+        match snapshot_mac {
+            None => {
+                return Err(
+                    SnapshotError::MacMissing(full_path.to_string_lossy().to_string()).into(),
+                )
+            }
+            Some(snapshot_mac) if !mac::verify_snapshot(key, payload, &snapshot_mac) => {
+                return Err(
+                    SnapshotError::MacInvalid(full_path.to_string_lossy().to_string()).into(),
+                )
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(payload.to_vec())
+}
 
-        let black_list: Vec<String> = vec![];
-        Ok(Snapshot {
-            file_hashes: Arc::new(Mutex::new(fh)),
-            black_list,
-            root_path: snapshot.root_path,
-            hash_type: snapshot.hash_type,
-            uuid: snapshot.uuid,
-            date_created: snapshot.date_created,
-        })
+/// Check the signature of an exported snapshot without importing it, for callers that want to
+/// flag rather than refuse unsigned or tampered baselines.
+pub fn signature_status(path: String, key: &VerifyingKey) -> Result<SignatureStatus, Error> {
+    let full_path = PathBuf::from(path_resolve(path));
+    let bytes = decrypt_if_needed(&full_path, fs::read(&full_path)?, None)?;
+    let (payload, embedded_signature, _) = unwrap_envelope(&bytes);
+    Ok(check_signature(
+        &full_path,
+        key,
+        payload,
+        embedded_signature,
+    ))
+}
+
+fn decrypt_if_needed(
+    full_path: &Path,
+    bytes: Vec<u8>,
+    key: Option<&EncryptionKey>,
+) -> Result<Vec<u8>, Error> {
+    if !encryption::is_encrypted(&bytes) {
+        return Ok(bytes);
+    }
+    match key {
+        None => Err(SnapshotError::Encrypted(full_path.to_string_lossy().to_string()).into()),
+        Some(key) => encryption::decrypt(key, &bytes).map_err(|_| {
+            SnapshotError::DecryptionFailed(full_path.to_string_lossy().to_string()).into()
+        }),
+    }
+}
+
+fn check_signature(
+    full_path: &Path,
+    key: &VerifyingKey,
+    payload: &[u8],
+    embedded_signature: Option<String>,
+) -> SignatureStatus {
+    let signature = match embedded_signature {
+        Some(signature) => signature,
+        None => match fs::read_to_string(signature_path(full_path)) {
+            Ok(signature) => signature,
+            Err(_) => return SignatureStatus::Missing,
+        },
+    };
+    if signing::verify(key, payload, &signature) {
+        SignatureStatus::Valid
     } else {
-        Ok(Snapshot::default())
+        SignatureStatus::Invalid
     }
 }
 
@@ -402,7 +949,7 @@ mod tests {
 
     #[test]
     fn dangerous() {
-        let mut snap = Snapshot::new(
+        let snap = Snapshot::new(
             Path::new("/proc"),
             HashType::BLAKE3,
             vec![
@@ -415,7 +962,7 @@ mod tests {
         );
         assert!(snap.is_ok());
 
-        let mut snap = Snapshot::new(
+        let snap = Snapshot::new(
             Path::new("/dev"),
             HashType::BLAKE3,
             vec![
@@ -428,7 +975,7 @@ mod tests {
         );
         assert!(snap.is_ok());
 
-        let mut snap = Snapshot::new(
+        let snap = Snapshot::new(
             Path::new("/tmp"),
             HashType::BLAKE3,
             vec![
@@ -458,17 +1005,27 @@ mod tests {
     #[test]
     fn create_snapshot_blake3() {
         let test_snap_b3 = Snapshot::new(Path::new("/etc"), HashType::BLAKE3, vec![], false);
-        assert!(test_snap_b3.unwrap().file_hashes.lock().unwrap().len() > 0);
+        assert!(!test_snap_b3.unwrap().file_hashes.lock().unwrap().is_empty());
     }
     #[test]
     fn create_snapshot_md5() {
         let test_snap_md5 = Snapshot::new(Path::new("/etc"), HashType::MD5, vec![], true);
-        assert!(test_snap_md5.unwrap().file_hashes.lock().unwrap().len() > 0);
+        assert!(!test_snap_md5
+            .unwrap()
+            .file_hashes
+            .lock()
+            .unwrap()
+            .is_empty());
     }
     #[test]
     fn create_snapshot_sha3() {
         let test_snap_sha3 = Snapshot::new(Path::new("/etc"), HashType::SHA3, vec![], true);
-        assert!(test_snap_sha3.unwrap().file_hashes.lock().unwrap().len() > 0);
+        assert!(!test_snap_sha3
+            .unwrap()
+            .file_hashes
+            .lock()
+            .unwrap()
+            .is_empty());
     }
 
     #[test]
@@ -495,10 +1052,317 @@ mod tests {
             true,
         );
         let snapshot = import("./target/build/in.snapshot".to_string(), true);
-        assert!(snapshot.unwrap().file_hashes.lock().unwrap().len() > 0);
+        assert!(!snapshot.unwrap().file_hashes.lock().unwrap().is_empty());
         fs::remove_file(Path::new("./target/build/in.snapshot")).unwrap();
     }
 
+    #[test]
+    fn export_snapshot_already_exists() {
+        assert!(!Path::new("./target/build/test_export_exists/").exists());
+        fs::create_dir_all(Path::new("./target/build/test_export_exists/")).unwrap();
+        let snapshot_path = "./target/build/test_export_exists/out.snapshot".to_string();
+        export(Snapshot::default(), snapshot_path.clone(), false, true).unwrap();
+        let result = export(Snapshot::default(), snapshot_path.clone(), false, true);
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<SnapshotError>(),
+            Some(SnapshotError::AlreadyExists(_))
+        ));
+        assert!(export(Snapshot::default(), snapshot_path, true, true).is_ok());
+        fs::remove_dir_all(Path::new("./target/build/test_export_exists/")).unwrap();
+    }
+
+    #[test]
+    fn export_snapshot_keep_backup() {
+        assert!(!Path::new("./target/build/test_export_backup/").exists());
+        fs::create_dir_all(Path::new("./target/build/test_export_backup/")).unwrap();
+        let snapshot_path = "./target/build/test_export_backup/out.snapshot".to_string();
+        let first = Snapshot {
+            uuid: "first".to_string(),
+            ..Default::default()
+        };
+        let second = Snapshot {
+            uuid: "second".to_string(),
+            ..Default::default()
+        };
+        let options = ExportOptions {
+            overwrite: true,
+            keep_backup: true,
+            ..Default::default()
+        };
+        export_with_options(first, snapshot_path.clone(), &options, true).unwrap();
+        export_with_options(second, snapshot_path.clone(), &options, true).unwrap();
+        assert_eq!(import(snapshot_path.clone(), true).unwrap().uuid, "second");
+        assert_eq!(
+            import(format!("{snapshot_path}.bak"), true).unwrap().uuid,
+            "first"
+        );
+        let leftovers = fs::read_dir("./target/build/test_export_backup/")
+            .unwrap()
+            .flatten()
+            .filter(|e| e.file_name().to_string_lossy().ends_with(".tmp"))
+            .count();
+        assert_eq!(leftovers, 0);
+        fs::remove_dir_all(Path::new("./target/build/test_export_backup/")).unwrap();
+    }
+
+    #[test]
+    fn signed_snapshot_embedded() {
+        assert!(!Path::new("./target/build/test_signed_embedded/").exists());
+        fs::create_dir_all(Path::new("./target/build/test_signed_embedded/")).unwrap();
+        let snapshot_path = "./target/build/test_signed_embedded/out.snapshot".to_string();
+        let key = signing::generate_signing_key();
+        let snapshot = Snapshot {
+            root_path: "/etc".to_string(),
+            ..Default::default()
+        };
+        let export_options = ExportOptions {
+            overwrite: true,
+            signing_key: Some(key.clone()),
+            ..Default::default()
+        };
+        export_with_options(snapshot, snapshot_path.clone(), &export_options, true).unwrap();
+
+        let import_options = ImportOptions {
+            verifying_key: Some(key.verifying_key()),
+            ..Default::default()
+        };
+        let imported = import_with_options(snapshot_path.clone(), &import_options, true);
+        assert_eq!(imported.unwrap().root_path, "/etc");
+        // signed snapshots still import without a key
+        assert_eq!(
+            import(snapshot_path.clone(), true).unwrap().root_path,
+            "/etc"
+        );
+
+        let tampered = fs::read_to_string(&snapshot_path)
+            .unwrap()
+            .replace("/etc", "/tmp");
+        fs::write(&snapshot_path, tampered).unwrap();
+        let result = import_with_options(snapshot_path.clone(), &import_options, true);
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<SnapshotError>(),
+            Some(SnapshotError::SignatureInvalid(_))
+        ));
+        assert_eq!(
+            signature_status(snapshot_path, &key.verifying_key()).unwrap(),
+            SignatureStatus::Invalid
+        );
+        fs::remove_dir_all(Path::new("./target/build/test_signed_embedded/")).unwrap();
+    }
+
+    #[test]
+    fn signed_snapshot_detached() {
+        assert!(!Path::new("./target/build/test_signed_detached/").exists());
+        fs::create_dir_all(Path::new("./target/build/test_signed_detached/")).unwrap();
+        let snapshot_path = "./target/build/test_signed_detached/out.snapshot".to_string();
+        let key = signing::generate_signing_key();
+        let import_options = ImportOptions {
+            verifying_key: Some(key.verifying_key()),
+            ..Default::default()
+        };
+
+        export(Snapshot::default(), snapshot_path.clone(), false, true).unwrap();
+        let result = import_with_options(snapshot_path.clone(), &import_options, true);
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<SnapshotError>(),
+            Some(SnapshotError::SignatureMissing(_))
+        ));
+
+        let export_options = ExportOptions {
+            overwrite: true,
+            signing_key: Some(key.clone()),
+            signature_mode: SignatureMode::Detached,
+            ..Default::default()
+        };
+        export_with_options(
+            Snapshot::default(),
+            snapshot_path.clone(),
+            &export_options,
+            true,
+        )
+        .unwrap();
+        assert!(Path::new("./target/build/test_signed_detached/out.snapshot.sig").exists());
+        assert!(import_with_options(snapshot_path.clone(), &import_options, true).is_ok());
+
+        let other_key = signing::generate_signing_key();
+        assert_eq!(
+            signature_status(snapshot_path.clone(), &other_key.verifying_key()).unwrap(),
+            SignatureStatus::Invalid
+        );
+
+        export(Snapshot::default(), snapshot_path.clone(), true, true).unwrap();
+        assert!(!Path::new("./target/build/test_signed_detached/out.snapshot.sig").exists());
+        let result = import_with_options(snapshot_path, &import_options, true);
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<SnapshotError>(),
+            Some(SnapshotError::SignatureMissing(_))
+        ));
+        fs::remove_dir_all(Path::new("./target/build/test_signed_detached/")).unwrap();
+    }
+
+    #[test]
+    fn mac_snapshot() {
+        assert!(!Path::new("./target/build/test_mac/").exists());
+        fs::create_dir_all(Path::new("./target/build/test_mac/")).unwrap();
+        File::create(Path::new("./target/build/test_mac/test1")).unwrap();
+        let snapshot_path = "./target/build/test_mac/out.snapshot".to_string();
+        let snapshot = Snapshot::new(
+            Path::new("./target/build/test_mac/"),
+            HashType::BLAKE3,
+            vec![],
+            true,
+        )
+        .unwrap();
+        let key = MacKey::from_secret(b"correct horse battery staple");
+        let export_options = ExportOptions {
+            mac_key: Some(key.clone()),
+            ..Default::default()
+        };
+        export_with_options(snapshot, snapshot_path.clone(), &export_options, true).unwrap();
+
+        let import_options = ImportOptions {
+            mac_key: Some(key),
+            ..Default::default()
+        };
+        let imported = import_with_options(snapshot_path.clone(), &import_options, true).unwrap();
+        assert!(imported
+            .file_hashes
+            .lock()
+            .unwrap()
+            .values()
+            .all(|entry| entry.mac.is_some()));
+
+        let wrong_key = ImportOptions {
+            mac_key: Some(MacKey::from_secret(b"wrong")),
+            ..Default::default()
+        };
+        let result = import_with_options(snapshot_path.clone(), &wrong_key, true);
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<SnapshotError>(),
+            Some(SnapshotError::MacInvalid(_))
+        ));
+
+        export(Snapshot::default(), snapshot_path.clone(), true, true).unwrap();
+        let result = import_with_options(snapshot_path, &import_options, true);
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<SnapshotError>(),
+            Some(SnapshotError::MacMissing(_))
+        ));
+        fs::remove_dir_all(Path::new("./target/build/test_mac/")).unwrap();
+    }
+
+    #[test]
+    fn encrypted_snapshot() {
+        assert!(!Path::new("./target/build/test_encrypted/").exists());
+        fs::create_dir_all(Path::new("./target/build/test_encrypted/")).unwrap();
+        let snapshot_path = "./target/build/test_encrypted/out.snapshot".to_string();
+        let snapshot = Snapshot {
+            root_path: "/root/secrets".to_string(),
+            ..Default::default()
+        };
+        let key = EncryptionKey::Passphrase("hunter2".to_string());
+        let signing_key = signing::generate_signing_key();
+        let export_options = ExportOptions {
+            signing_key: Some(signing_key.clone()),
+            encryption_key: Some(key.clone()),
+            ..Default::default()
+        };
+        export_with_options(snapshot, snapshot_path.clone(), &export_options, true).unwrap();
+        let raw = fs::read(&snapshot_path).unwrap();
+        assert!(!raw.is_empty());
+        assert!(!raw
+            .windows(b"/root/secrets".len())
+            .any(|window| window == b"/root/secrets"));
+
+        let result = import(snapshot_path.clone(), true);
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<SnapshotError>(),
+            Some(SnapshotError::Encrypted(_))
+        ));
+
+        let wrong_key = ImportOptions {
+            encryption_key: Some(EncryptionKey::Passphrase("hunter3".to_string())),
+            ..Default::default()
+        };
+        let result = import_with_options(snapshot_path.clone(), &wrong_key, true);
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<SnapshotError>(),
+            Some(SnapshotError::DecryptionFailed(_))
+        ));
+
+        let import_options = ImportOptions {
+            verifying_key: Some(signing_key.verifying_key()),
+            encryption_key: Some(key),
+            ..Default::default()
+        };
+        let imported = import_with_options(snapshot_path, &import_options, true).unwrap();
+        assert_eq!(imported.root_path, "/root/secrets");
+        fs::remove_dir_all(Path::new("./target/build/test_encrypted/")).unwrap();
+    }
+
+    #[test]
+    fn merkle_root_localizes_changes() {
+        assert!(!Path::new("./target/build/test_merkle/").exists());
+        fs::create_dir_all(Path::new("./target/build/test_merkle/nginx/sites/")).unwrap();
+        fs::write("./target/build/test_merkle/hosts", "127.0.0.1").unwrap();
+        fs::write(
+            "./target/build/test_merkle/nginx/sites/default",
+            "listen 80",
+        )
+        .unwrap();
+        let root = Path::new("./target/build/test_merkle");
+        let left = Snapshot::new(root, HashType::BLAKE3, vec![], true).unwrap();
+        let unchanged = Snapshot::new(root, HashType::BLAKE3, vec![], true).unwrap();
+        assert!(!left.merkle_root.is_empty());
+        assert_eq!(left.merkle_root, unchanged.merkle_root);
+
+        fs::write(
+            "./target/build/test_merkle/nginx/sites/default",
+            "listen 443",
+        )
+        .unwrap();
+        let right = Snapshot::new(root, HashType::BLAKE3, vec![], true).unwrap();
+        assert_ne!(left.merkle_root, right.merkle_root);
+        assert_eq!(
+            merkle::changed_directories(&left, &right),
+            vec!["./target/build/test_merkle/nginx/sites".to_string()]
+        );
+        let (_, result) = compare_hashes(left, right, true).unwrap();
+        assert_eq!(
+            result.changed,
+            vec!["./target/build/test_merkle/nginx/sites/default".to_string()]
+        );
+        assert!(result.created.is_empty() && result.deleted.is_empty());
+
+        // equal roots under different root paths are different files
+        let mut moved = unchanged.clone();
+        moved.root_path = "/elsewhere".to_string();
+        moved.file_hashes = Arc::new(Mutex::new(
+            unchanged
+                .file_hashes
+                .lock()
+                .unwrap()
+                .values()
+                .map(|entry| {
+                    let path = entry.path.replace("./target/build/test_merkle", "/elsewhere");
+                    (path.clone(), FileMetadata { path, ..entry.clone() })
+                })
+                .collect(),
+        ));
+        moved.update_merkle_root();
+        assert_eq!(moved.merkle_root, unchanged.merkle_root);
+        let (_, result) = compare_hashes(unchanged.clone(), moved, false).unwrap();
+        assert_eq!(result.deleted.len(), 2);
+        assert_eq!(result.created.len(), 2);
+
+        // a stale stored root does not hide a change
+        let mut stale = Snapshot::new(root, HashType::BLAKE3, vec![], false).unwrap();
+        stale.merkle_root = unchanged.merkle_root.clone();
+        let (_, result) = compare_hashes(unchanged, stale, false).unwrap();
+        assert_eq!(result.changed.len(), 1);
+        fs::remove_dir_all(Path::new("./target/build/test_merkle/")).unwrap();
+    }
+
     #[test]
     fn creation_detection() {
         assert!(!Path::new("./target/build/test_creation/").exists());
@@ -605,9 +1469,9 @@ mod tests {
             vec![],
             true,
         );
-        let _ = fs::remove_file(Path::new("./target/build/test_change_modify/test1")).unwrap();
-        let _ = fs::remove_file(Path::new("./target/build/test_change_modify/test2")).unwrap();
-        let _ = fs::remove_file(Path::new("./target/build/test_change_modify/test3")).unwrap();
+        fs::remove_file(Path::new("./target/build/test_change_modify/test1")).unwrap();
+        fs::remove_file(Path::new("./target/build/test_change_modify/test2")).unwrap();
+        fs::remove_file(Path::new("./target/build/test_change_modify/test3")).unwrap();
 
         let _ = File::create(Path::new("./target/build/test_change_modify/test1")).unwrap();
         let _ = File::create(Path::new("./target/build/test_change_modify/test2")).unwrap();
@@ -0,0 +1,486 @@
+use crate::hasher::HashType;
+use crate::snapshot::{
+    export_with_options, import_with_options, ExportOptions, FileMetadata, ImportOptions, Snapshot,
+};
+use anyhow::{anyhow, Error};
+use rusqlite::types::Type;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS snapshots (
+    id INTEGER PRIMARY KEY,
+    uuid TEXT NOT NULL UNIQUE,
+    root_path TEXT NOT NULL,
+    hash_type TEXT NOT NULL,
+    date_created INTEGER NOT NULL,
+    merkle_root TEXT NOT NULL,
+    black_list TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS snapshots_root ON snapshots (root_path, date_created);
+CREATE TABLE IF NOT EXISTS file_versions (
+    id INTEGER PRIMARY KEY,
+    fingerprint BLOB NOT NULL UNIQUE,
+    path TEXT NOT NULL,
+    check_sum BLOB NOT NULL,
+    size INTEGER NOT NULL,
+    ino INTEGER NOT NULL,
+    ctime INTEGER NOT NULL,
+    mtime INTEGER NOT NULL,
+    mac BLOB,
+    attributes TEXT
+);
+CREATE INDEX IF NOT EXISTS file_versions_path ON file_versions (path);
+CREATE TABLE IF NOT EXISTS snapshot_entries (
+    snapshot_id INTEGER NOT NULL REFERENCES snapshots (id) ON DELETE CASCADE,
+    version_id INTEGER NOT NULL REFERENCES file_versions (id),
+    PRIMARY KEY (snapshot_id, version_id)
+);
+CREATE INDEX IF NOT EXISTS snapshot_entries_version ON snapshot_entries (version_id);
+";
+
+/// Snapshot history kept in a SQLite database.
+///
+/// Entries are stored once per distinct version of a file, so a file that is unchanged across
+/// many snapshots costs a single row plus one reference per snapshot.
+pub struct SqliteStore {
+    conn: Connection,
+}
+
+/// A stored snapshot, without its entries loaded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotSummary {
+    pub uuid: String,
+    pub root_path: String,
+    pub hash_type: HashType,
+    pub date_created: i64,
+    pub merkle_root: String,
+    pub entries: u64,
+}
+
+/// A path as recorded by one snapshot.
+#[derive(Debug, Clone)]
+pub struct PathVersion {
+    pub uuid: String,
+    pub date_created: i64,
+    pub metadata: FileMetadata,
+}
+
+fn fingerprint(entry: &FileMetadata) -> Result<Vec<u8>, Error> {
+    Ok(blake3::hash(&serde_json::to_vec(entry)?)
+        .as_bytes()
+        .to_vec())
+}
+
+fn metadata_from_row(row: &Row, offset: usize) -> rusqlite::Result<FileMetadata> {
+    Ok(FileMetadata {
+        path: row.get(offset)?,
+        check_sum: row.get(offset + 1)?,
+        size: row.get::<_, i64>(offset + 2)? as u64,
+        ino: row.get::<_, i64>(offset + 3)? as u64,
+        ctime: row.get(offset + 4)?,
+        mtime: row.get(offset + 5)?,
+        mac: row.get(offset + 6)?,
+        attributes: match row.get::<_, Option<String>>(offset + 7)? {
+            Some(attributes) => Some(serde_json::from_str(&attributes).map_err(|e| {
+                rusqlite::Error::FromSqlConversionFailure(offset + 7, Type::Text, Box::new(e))
+            })?),
+            None => None,
+        },
+    })
+}
+
+fn hash_type_from_row(row: &Row, index: usize) -> rusqlite::Result<HashType> {
+    serde_json::from_str(&row.get::<_, String>(index)?)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(index, Type::Text, Box::new(e)))
+}
+
+fn summary_from_row(row: &Row) -> rusqlite::Result<SnapshotSummary> {
+    Ok(SnapshotSummary {
+        uuid: row.get(0)?,
+        root_path: row.get(1)?,
+        hash_type: hash_type_from_row(row, 2)?,
+        date_created: row.get(3)?,
+        merkle_root: row.get(4)?,
+        entries: row.get::<_, i64>(5)? as u64,
+    })
+}
+
+const VERSION_COLUMNS: &str = "v.path, v.check_sum, v.size, v.ino, v.ctime, v.mtime, v.mac,
+                               v.attributes";
+
+impl SqliteStore {
+    pub fn open(path: &Path) -> Result<SqliteStore, Error> {
+        SqliteStore::init(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> Result<SqliteStore, Error> {
+        SqliteStore::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> Result<SqliteStore, Error> {
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(SqliteStore { conn })
+    }
+
+    /// Store `snapshot`, reusing the rows of entries already recorded by earlier snapshots.
+    pub fn insert(&mut self, snapshot: &Snapshot) -> Result<(), Error> {
+        let entries: Vec<FileMetadata> = match snapshot.file_hashes.lock() {
+            Ok(file_hashes) => file_hashes.values().cloned().collect(),
+            Err(_) => return Err(anyhow!("unable to lock snapshot")),
+        };
+
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "INSERT INTO snapshots (uuid, root_path, hash_type, date_created, merkle_root, black_list)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                snapshot.uuid,
+                snapshot.root_path,
+                serde_json::to_string(&snapshot.hash_type)?,
+                snapshot.date_created,
+                snapshot.merkle_root,
+                serde_json::to_string(&snapshot.black_list)?,
+            ],
+        )?;
+        let snapshot_id = tx.last_insert_rowid();
+        {
+            let mut insert_version = tx.prepare(
+                "INSERT INTO file_versions (fingerprint, path, check_sum, size, ino, ctime, mtime, mac,
+                                           attributes)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+                 ON CONFLICT (fingerprint) DO UPDATE SET fingerprint = excluded.fingerprint
+                 RETURNING id",
+            )?;
+            let mut insert_entry = tx.prepare(
+                "INSERT OR IGNORE INTO snapshot_entries (snapshot_id, version_id) VALUES (?1, ?2)",
+            )?;
+            for entry in entries.iter() {
+                let version_id: i64 = insert_version.query_row(
+                    params![
+                        fingerprint(entry)?,
+                        entry.path,
+                        entry.check_sum,
+                        entry.size as i64,
+                        entry.ino as i64,
+                        entry.ctime,
+                        entry.mtime,
+                        entry.mac,
+                        entry
+                            .attributes
+                            .as_ref()
+                            .map(serde_json::to_string)
+                            .transpose()?,
+                    ],
+                    |row| row.get(0),
+                )?;
+                insert_entry.execute(params![snapshot_id, version_id])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Stored snapshots, optionally only those of `root_path`, oldest first.
+    pub fn list(&self, root_path: Option<&str>) -> Result<Vec<SnapshotSummary>, Error> {
+        let mut stmt = self.conn.prepare(
+            "SELECT s.uuid, s.root_path, s.hash_type, s.date_created, s.merkle_root,
+                    (SELECT COUNT(*) FROM snapshot_entries e WHERE e.snapshot_id = s.id)
+             FROM snapshots s
+             WHERE ?1 IS NULL OR s.root_path = ?1
+             ORDER BY s.date_created, s.id",
+        )?;
+        let rows = stmt.query_map(params![root_path], summary_from_row)?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    pub fn load(&self, uuid: &str) -> Result<Option<Snapshot>, Error> {
+        let header = self
+            .conn
+            .query_row(
+                "SELECT id, root_path, hash_type, date_created, black_list
+                 FROM snapshots WHERE uuid = ?1",
+                params![uuid],
+                |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, String>(1)?,
+                        hash_type_from_row(row, 2)?,
+                        row.get::<_, i64>(3)?,
+                        row.get::<_, String>(4)?,
+                    ))
+                },
+            )
+            .optional()?;
+        let (id, root_path, hash_type, date_created, black_list) = match header {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {VERSION_COLUMNS} FROM snapshot_entries e
+             JOIN file_versions v ON v.id = e.version_id
+             WHERE e.snapshot_id = ?1"
+        ))?;
+        let mut file_hashes = HashMap::new();
+        for entry in stmt.query_map(params![id], |row| metadata_from_row(row, 0))? {
+            let entry = entry?;
+            file_hashes.insert(entry.path.clone(), entry);
+        }
+
+        // The root is recomputed from the loaded entries rather than trusting the stored column.
+        let mut snapshot = Snapshot {
+            file_hashes: Arc::new(Mutex::new(file_hashes)),
+            black_list: serde_json::from_str(&black_list)?,
+            root_path,
+            hash_type,
+            uuid: uuid.to_string(),
+            date_created,
+            merkle_root: String::new(),
+        };
+        snapshot.update_merkle_root();
+        Ok(Some(snapshot))
+    }
+
+    pub fn latest(&self, root_path: &str) -> Result<Option<Snapshot>, Error> {
+        match self.list(Some(root_path))?.last() {
+            Some(summary) => self.load(&summary.uuid),
+            None => Ok(None),
+        }
+    }
+
+    /// Remove a snapshot along with the file versions no other snapshot refers to.
+    pub fn delete(&mut self, uuid: &str) -> Result<bool, Error> {
+        let tx = self.conn.transaction()?;
+        let deleted = tx.execute("DELETE FROM snapshots WHERE uuid = ?1", params![uuid])?;
+        tx.execute(
+            "DELETE FROM file_versions
+             WHERE id NOT IN (SELECT version_id FROM snapshot_entries)",
+            [],
+        )?;
+        tx.commit()?;
+        Ok(deleted > 0)
+    }
+
+    /// Every snapshot that recorded `path`, oldest first.
+    pub fn path_history(&self, path: &str) -> Result<Vec<PathVersion>, Error> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT s.uuid, s.date_created, {VERSION_COLUMNS} FROM file_versions v
+             JOIN snapshot_entries e ON e.version_id = v.id
+             JOIN snapshots s ON s.id = e.snapshot_id
+             WHERE v.path = ?1
+             ORDER BY s.date_created, s.id"
+        ))?;
+        let rows = stmt.query_map(params![path], |row| {
+            Ok(PathVersion {
+                uuid: row.get(0)?,
+                date_created: row.get(1)?,
+                metadata: metadata_from_row(row, 2)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    /// `path` as recorded by the newest snapshot taken at or before `timestamp` that contains it.
+    pub fn path_at(&self, path: &str, timestamp: i64) -> Result<Option<PathVersion>, Error> {
+        Ok(self
+            .path_history(path)?
+            .into_iter()
+            .rfind(|version| version.date_created <= timestamp))
+    }
+
+    /// The snapshot in which the most recently recorded content of `path` first appeared.
+    pub fn last_changed(&self, path: &str) -> Result<Option<PathVersion>, Error> {
+        let history = self.path_history(path)?;
+        let current = match history.last() {
+            Some(current) => current.metadata.check_sum.clone(),
+            None => return Ok(None),
+        };
+        Ok(history
+            .into_iter()
+            .rev()
+            .take_while(|version| version.metadata.check_sum == current)
+            .last())
+    }
+
+    /// Read a JSON snapshot written by `export` and store it.
+    pub fn import_json(
+        &mut self,
+        path: String,
+        options: &ImportOptions,
+        verbose: bool,
+    ) -> Result<SnapshotSummary, Error> {
+        let snapshot = import_with_options(path, options, verbose)?;
+        self.insert(&snapshot)?;
+        self.list(Some(&snapshot.root_path))?
+            .into_iter()
+            .find(|summary| summary.uuid == snapshot.uuid)
+            .ok_or_else(|| anyhow!("snapshot was not stored: {}", snapshot.uuid))
+    }
+
+    /// Write a stored snapshot in the JSON format read by `import`.
+    pub fn export_json(
+        &self,
+        uuid: &str,
+        path: String,
+        options: &ExportOptions,
+        verbose: bool,
+    ) -> Result<(), Error> {
+        match self.load(uuid)? {
+            Some(snapshot) => export_with_options(snapshot, path, options, verbose),
+            None => Err(anyhow!("no such snapshot: {}", uuid)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::{export, test_snapshot, FileAttributes};
+    use std::fs;
+
+    fn count(store: &SqliteStore, table: &str) -> i64 {
+        store
+            .conn
+            .query_row(&format!("SELECT COUNT(*) FROM {table}"), [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn history_and_deduplication() {
+        let mut store = SqliteStore::open_in_memory().unwrap();
+        store
+            .insert(&test_snapshot(
+                "/etc",
+                "a",
+                100,
+                &[("/etc/sudoers", 1), ("/etc/hosts", 1)],
+            ))
+            .unwrap();
+        store
+            .insert(&test_snapshot(
+                "/etc",
+                "b",
+                200,
+                &[("/etc/sudoers", 2), ("/etc/hosts", 1)],
+            ))
+            .unwrap();
+        store
+            .insert(&test_snapshot(
+                "/etc",
+                "c",
+                300,
+                &[("/etc/sudoers", 2), ("/etc/hosts", 1)],
+            ))
+            .unwrap();
+        assert_eq!(count(&store, "file_versions"), 3);
+        assert_eq!(count(&store, "snapshot_entries"), 6);
+
+        let history = store.path_history("/etc/sudoers").unwrap();
+        assert_eq!(history.len(), 3);
+        assert_eq!(
+            store
+                .path_at("/etc/sudoers", 150)
+                .unwrap()
+                .unwrap()
+                .metadata
+                .check_sum,
+            vec![1]
+        );
+        assert!(store.path_at("/etc/sudoers", 50).unwrap().is_none());
+        assert_eq!(
+            store.last_changed("/etc/sudoers").unwrap().unwrap().uuid,
+            "b"
+        );
+        assert_eq!(store.last_changed("/etc/hosts").unwrap().unwrap().uuid, "a");
+
+        let loaded = store.load("b").unwrap().unwrap();
+        assert_eq!(loaded.file_hashes.lock().unwrap().len(), 2);
+        assert_eq!(store.latest("/etc").unwrap().unwrap().uuid, "c");
+        assert_eq!(store.list(None).unwrap().len(), 3);
+        assert_eq!(store.list(Some("/usr")).unwrap().len(), 0);
+
+        assert!(store.delete("a").unwrap());
+        assert!(!store.delete("a").unwrap());
+        assert_eq!(count(&store, "file_versions"), 2);
+        assert!(store.load("a").unwrap().is_none());
+    }
+
+    #[test]
+    fn attributes_and_merkle_root_round_trip() {
+        let mut store = SqliteStore::open_in_memory().unwrap();
+        let mut stored = test_snapshot("/etc", "a", 100, &[("/etc/hosts", 1), ("/etc/shadow", 2)]);
+        let attributes = FileAttributes {
+            mode: 0o100640,
+            uid: 0,
+            gid: 42,
+            ..Default::default()
+        };
+        stored
+            .file_hashes
+            .lock()
+            .unwrap()
+            .get_mut("/etc/shadow")
+            .unwrap()
+            .attributes = Some(attributes.clone());
+        stored.merkle_root = "stale".to_string();
+        store.insert(&stored).unwrap();
+
+        let loaded = store.load("a").unwrap().unwrap();
+        let file_hashes = loaded.file_hashes.lock().unwrap();
+        assert_eq!(file_hashes["/etc/shadow"].attributes, Some(attributes));
+        assert_eq!(file_hashes["/etc/hosts"].attributes, None);
+        drop(file_hashes);
+        stored.update_merkle_root();
+        assert_eq!(loaded.merkle_root, stored.merkle_root);
+    }
+
+    #[test]
+    fn json_round_trip() {
+        assert!(!Path::new("./target/build/test_sqlite_json/").exists());
+        fs::create_dir_all(Path::new("./target/build/test_sqlite_json/")).unwrap();
+        let mut store = SqliteStore::open(Path::new("./target/build/test_sqlite_json/db")).unwrap();
+        export(
+            test_snapshot("/etc", "a", 100, &[("/etc/hosts", 1)]),
+            "./target/build/test_sqlite_json/in.snapshot".to_string(),
+            false,
+            true,
+        )
+        .unwrap();
+        let summary = store
+            .import_json(
+                "./target/build/test_sqlite_json/in.snapshot".to_string(),
+                &ImportOptions::default(),
+                true,
+            )
+            .unwrap();
+        assert_eq!(summary.entries, 1);
+        assert_eq!(summary.hash_type, HashType::BLAKE3);
+
+        store
+            .export_json(
+                "a",
+                "./target/build/test_sqlite_json/out.snapshot".to_string(),
+                &ExportOptions::default(),
+                true,
+            )
+            .unwrap();
+        let exported = crate::snapshot::import(
+            "./target/build/test_sqlite_json/out.snapshot".to_string(),
+            true,
+        )
+        .unwrap();
+        assert_eq!(exported.uuid, "a");
+        assert!(exported
+            .file_hashes
+            .lock()
+            .unwrap()
+            .contains_key("/etc/hosts"));
+        fs::remove_dir_all(Path::new("./target/build/test_sqlite_json/")).unwrap();
+    }
+}
@@ -0,0 +1,395 @@
+use crate::snapshot::{
+    compare_hashes, export_with_options, import_with_options, ExportOptions, ImportOptions,
+    Snapshot, SnapshotChangeType, SnapshotCompareResult,
+};
+use anyhow::{anyhow, Error};
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const ROOT_FILE: &str = "ROOT";
+const EXTENSION: &str = "snapshot";
+
+/// Local directory of snapshots, grouped by root path and ordered by creation time.
+///
+/// Each root gets its own subdirectory holding a `ROOT` file with the original root path and one
+/// `<date_created>-<uuid>.snapshot` file per stored snapshot, so the directory listing is the
+/// index.
+#[derive(Debug, Clone)]
+pub struct SnapshotStore {
+    base: PathBuf,
+    export_options: ExportOptions,
+    import_options: ImportOptions,
+}
+
+/// A stored snapshot, without its entries loaded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotRecord {
+    pub root_path: String,
+    pub date_created: i64,
+    pub uuid: String,
+    pub path: PathBuf,
+}
+
+/// Which snapshots of a root survive [`SnapshotStore::apply_retention`]. A snapshot is kept when
+/// any rule keeps it; a policy without rules keeps everything.
+#[derive(Debug, Clone, Default)]
+pub struct RetentionPolicy {
+    /// Keep the newest `n` snapshots.
+    pub keep_last: Option<usize>,
+    /// Keep the newest snapshot of each of the last `n` days (UTC).
+    pub keep_daily: Option<u32>,
+}
+
+impl SnapshotStore {
+    pub fn open(base: &Path) -> Result<SnapshotStore, Error> {
+        SnapshotStore::with_options(base, ExportOptions::default(), ImportOptions::default())
+    }
+
+    /// Open a store whose snapshots are written and read with the given options, e.g. to sign or
+    /// encrypt everything it holds. `export_options.overwrite` is ignored.
+    pub fn with_options(
+        base: &Path,
+        export_options: ExportOptions,
+        import_options: ImportOptions,
+    ) -> Result<SnapshotStore, Error> {
+        fs::create_dir_all(base)?;
+        Ok(SnapshotStore {
+            base: base.to_path_buf(),
+            export_options: ExportOptions {
+                overwrite: false,
+                keep_backup: false,
+                ..export_options
+            },
+            import_options,
+        })
+    }
+
+    fn root_dir(&self, root_path: &str) -> PathBuf {
+        let key = blake3::hash(root_path.as_bytes()).to_hex();
+        self.base.join(&key.as_str()[..16])
+    }
+
+    /// Whether the directory of `root_path` belongs to it rather than to a root whose key
+    /// collides with it.
+    fn holds_root(dir: &Path, root_path: &str) -> bool {
+        fs::read_to_string(dir.join(ROOT_FILE)).is_ok_and(|stored| stored == root_path)
+    }
+
+    pub fn save(&self, snapshot: &Snapshot, verbose: bool) -> Result<SnapshotRecord, Error> {
+        // the uuid ends up in a file name, and imported snapshots can carry anything there
+        if snapshot.uuid.parse::<u128>().is_err() {
+            return Err(anyhow!("invalid snapshot uuid: {:?}", snapshot.uuid));
+        }
+        let dir = self.root_dir(&snapshot.root_path);
+        fs::create_dir_all(&dir)?;
+        let root_file = dir.join(ROOT_FILE);
+        if !root_file.exists() {
+            fs::write(&root_file, &snapshot.root_path)?;
+        } else if !SnapshotStore::holds_root(&dir, &snapshot.root_path) {
+            return Err(anyhow!("store directory {:?} belongs to another root", dir));
+        }
+
+        let path = dir.join(format!(
+            "{}-{}.{}",
+            snapshot.date_created, snapshot.uuid, EXTENSION
+        ));
+        export_with_options(
+            snapshot.clone(),
+            path.to_string_lossy().to_string(),
+            &self.export_options,
+            verbose,
+        )?;
+        Ok(SnapshotRecord {
+            root_path: snapshot.root_path.clone(),
+            date_created: snapshot.date_created,
+            uuid: snapshot.uuid.clone(),
+            path,
+        })
+    }
+
+    /// Root paths that have snapshots in the store.
+    pub fn roots(&self) -> Result<Vec<String>, Error> {
+        let mut roots = vec![];
+        for entry in fs::read_dir(&self.base)?.flatten() {
+            if let Ok(root_path) = fs::read_to_string(entry.path().join(ROOT_FILE)) {
+                roots.push(root_path);
+            }
+        }
+        roots.sort();
+        Ok(roots)
+    }
+
+    /// Snapshots stored for `root_path`, oldest first.
+    pub fn list(&self, root_path: &str) -> Result<Vec<SnapshotRecord>, Error> {
+        let dir = self.root_dir(root_path);
+        if !SnapshotStore::holds_root(&dir, root_path) {
+            return Ok(vec![]);
+        }
+        let mut records = vec![];
+        for entry in fs::read_dir(&dir)?.flatten() {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(EXTENSION) {
+                continue;
+            }
+            let stem = match path.file_stem().and_then(|s| s.to_str()) {
+                Some(stem) => stem.to_string(),
+                None => continue,
+            };
+            if let Some((date, uuid)) = stem.split_once('-') {
+                if let Ok(date_created) = date.parse::<i64>() {
+                    records.push(SnapshotRecord {
+                        root_path: root_path.to_string(),
+                        date_created,
+                        uuid: uuid.to_string(),
+                        path,
+                    });
+                }
+            }
+        }
+        records.sort_by(|a, b| {
+            a.date_created
+                .cmp(&b.date_created)
+                .then_with(|| a.uuid.cmp(&b.uuid))
+        });
+        Ok(records)
+    }
+
+    pub fn load(&self, record: &SnapshotRecord, verbose: bool) -> Result<Snapshot, Error> {
+        import_with_options(
+            record.path.to_string_lossy().to_string(),
+            &self.import_options,
+            verbose,
+        )
+    }
+
+    /// The snapshot of `root_path` taken at `date_created`.
+    pub fn get(
+        &self,
+        root_path: &str,
+        date_created: i64,
+        verbose: bool,
+    ) -> Result<Option<Snapshot>, Error> {
+        match self
+            .list(root_path)?
+            .into_iter()
+            .rfind(|r| r.date_created == date_created)
+        {
+            Some(record) => Ok(Some(self.load(&record, verbose)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn latest(&self, root_path: &str, verbose: bool) -> Result<Option<Snapshot>, Error> {
+        match self.list(root_path)?.last() {
+            Some(record) => Ok(Some(self.load(record, verbose)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn delete(&self, record: &SnapshotRecord) -> Result<(), Error> {
+        if !record.path.starts_with(&self.base) {
+            return Err(anyhow!(
+                "snapshot is not part of this store: {:?}",
+                record.path
+            ));
+        }
+        fs::remove_file(&record.path)?;
+        let mut sig = record.path.as_os_str().to_os_string();
+        sig.push(".sig");
+        let _ = fs::remove_file(sig);
+        Ok(())
+    }
+
+    /// Delete the snapshots of `root_path` that `policy` does not keep, returning them.
+    pub fn apply_retention(
+        &self,
+        root_path: &str,
+        policy: &RetentionPolicy,
+    ) -> Result<Vec<SnapshotRecord>, Error> {
+        let expired = expired_records(self.list(root_path)?, policy, Utc::now());
+        for record in expired.iter() {
+            self.delete(record)?;
+        }
+        Ok(expired)
+    }
+
+    /// Compare the two most recent snapshots of `root_path`, previous on the left. `None` when
+    /// fewer than two snapshots are stored.
+    pub fn compare_latest(
+        &self,
+        root_path: &str,
+        verbose: bool,
+    ) -> Result<Option<(SnapshotChangeType, SnapshotCompareResult)>, Error> {
+        let records = self.list(root_path)?;
+        if records.len() < 2 {
+            return Ok(None);
+        }
+        let previous = self.load(&records[records.len() - 2], verbose)?;
+        let latest = self.load(&records[records.len() - 1], verbose)?;
+        Ok(compare_hashes(previous, latest, verbose))
+    }
+}
+
+fn expired_records(
+    records: Vec<SnapshotRecord>,
+    policy: &RetentionPolicy,
+    now: DateTime<Utc>,
+) -> Vec<SnapshotRecord> {
+    if policy.keep_last.is_none() && policy.keep_daily.is_none() {
+        return vec![];
+    }
+    let mut keep: HashSet<usize> = HashSet::new();
+    if let Some(n) = policy.keep_last {
+        keep.extend(records.len().saturating_sub(n)..records.len());
+    }
+    if let Some(days) = policy.keep_daily {
+        let oldest = (now - Duration::days(days as i64)).date_naive();
+        let mut seen_days: HashSet<NaiveDate> = HashSet::new();
+        // newest first, so the first snapshot seen for a day is the one kept
+        for (i, record) in records.iter().enumerate().rev() {
+            if let Some(created) = DateTime::from_timestamp(record.date_created, 0) {
+                let day = created.date_naive();
+                if day > oldest && seen_days.insert(day) {
+                    keep.insert(i);
+                }
+            }
+        }
+    }
+    records
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| !keep.contains(i))
+        .map(|(_, r)| r)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::test_snapshot;
+
+    #[test]
+    fn save_list_latest_and_compare() {
+        assert!(!Path::new("./target/build/test_store/").exists());
+        let store = SnapshotStore::open(Path::new("./target/build/test_store/")).unwrap();
+        store
+            .save(
+                &test_snapshot("/etc", "1001", 100, &[("/etc/file", 1)]),
+                true,
+            )
+            .unwrap();
+        store
+            .save(
+                &test_snapshot("/etc", "2001", 200, &[("/etc/file", 1)]),
+                true,
+            )
+            .unwrap();
+        store
+            .save(
+                &test_snapshot("/usr/bin", "1501", 150, &[("/usr/bin/file", 1)]),
+                true,
+            )
+            .unwrap();
+
+        assert_eq!(store.roots().unwrap(), vec!["/etc", "/usr/bin"]);
+        let dates: Vec<i64> = store
+            .list("/etc")
+            .unwrap()
+            .iter()
+            .map(|r| r.date_created)
+            .collect();
+        assert_eq!(dates, vec![100, 200]);
+        assert_eq!(
+            store.latest("/etc", true).unwrap().unwrap().date_created,
+            200
+        );
+        assert!(store.get("/etc", 150, true).unwrap().is_none());
+        assert!(store.latest("/var", true).unwrap().is_none());
+
+        let (_, result) = store.compare_latest("/etc", true).unwrap().unwrap();
+        assert!(result.changed.is_empty());
+        store
+            .save(
+                &test_snapshot("/etc", "3002", 300, &[("/etc/file", 2)]),
+                true,
+            )
+            .unwrap();
+        let (_, result) = store.compare_latest("/etc", true).unwrap().unwrap();
+        assert_eq!(result.changed, vec!["/etc/file".to_string()]);
+        assert!(store.compare_latest("/usr/bin", true).unwrap().is_none());
+
+        let first = store.list("/etc").unwrap().remove(0);
+        store.delete(&first).unwrap();
+        assert_eq!(store.list("/etc").unwrap().len(), 2);
+        fs::remove_dir_all(Path::new("./target/build/test_store/")).unwrap();
+    }
+
+    #[test]
+    fn rejects_uuids_and_roots_that_do_not_belong() {
+        assert!(!Path::new("./target/build/test_store_uuid/").exists());
+        let store = SnapshotStore::open(Path::new("./target/build/test_store_uuid/")).unwrap();
+        for uuid in ["../../escape", "a/b", ""] {
+            assert!(store
+                .save(&test_snapshot("/etc", uuid, 100, &[("/etc/file", 1)]), true)
+                .is_err());
+        }
+        assert!(!Path::new("./target/build/escape-100.snapshot").exists());
+
+        // a directory whose ROOT file names another root is not listed as this one
+        store
+            .save(
+                &test_snapshot("/etc", "1001", 100, &[("/etc/file", 1)]),
+                true,
+            )
+            .unwrap();
+        fs::write(store.root_dir("/etc").join(ROOT_FILE), "/other").unwrap();
+        assert!(store.list("/etc").unwrap().is_empty());
+        assert!(store
+            .save(
+                &test_snapshot("/etc", "2001", 200, &[("/etc/file", 1)]),
+                true
+            )
+            .is_err());
+        fs::remove_dir_all(Path::new("./target/build/test_store_uuid/")).unwrap();
+    }
+
+    #[test]
+    fn retention() {
+        let now = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let day = 86_400;
+        let record = |date_created: i64| SnapshotRecord {
+            root_path: "/etc".to_string(),
+            date_created,
+            uuid: date_created.to_string(),
+            path: PathBuf::new(),
+        };
+        // two snapshots a day for five days, newest last
+        let records: Vec<SnapshotRecord> = (0..10)
+            .map(|i| record(now.timestamp() - (4 - i / 2) * day - (1 - i % 2) * 3600))
+            .collect();
+
+        let keep_last = RetentionPolicy {
+            keep_last: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(expired_records(records.clone(), &keep_last, now).len(), 7);
+
+        let keep_daily = RetentionPolicy {
+            keep_daily: Some(3),
+            ..Default::default()
+        };
+        let expired = expired_records(records.clone(), &keep_daily, now);
+        assert_eq!(expired.len(), 7);
+        assert!(!expired.contains(&records[9]));
+        assert!(expired.contains(&records[8]));
+
+        let both = RetentionPolicy {
+            keep_last: Some(2),
+            keep_daily: Some(3),
+        };
+        assert_eq!(expired_records(records.clone(), &both, now).len(), 6);
+        assert!(expired_records(records, &RetentionPolicy::default(), now).is_empty());
+    }
+}
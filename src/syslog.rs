@@ -0,0 +1,303 @@
+use crate::snapshot::{ChangeRecord, SnapshotCompareResult};
+use anyhow::Error;
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::fs;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+
+/// SD-ID of the structured data element, using the documentation enterprise number of RFC 5612.
+pub const SD_ID: &str = "fshash@32473";
+pub const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+const SEVERITY_WARNING: u8 = 4;
+const SEVERITY_NOTICE: u8 = 5;
+const SEVERITY_INFO: u8 = 6;
+
+/// Header fields of the RFC 5424 messages.
+#[derive(Debug, Clone)]
+pub struct SyslogOptions {
+    pub app_name: String,
+    pub hostname: String,
+    /// Syslog facility, 3 (daemon) by default.
+    pub facility: u8,
+}
+
+impl Default for SyslogOptions {
+    fn default() -> Self {
+        SyslogOptions {
+            app_name: "fshash".to_string(),
+            hostname: hostname(),
+            facility: 3,
+        }
+    }
+}
+
+/// The kernel's host name, empty when it can't be read.
+pub(crate) fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|h| h.trim().to_string())
+        .unwrap_or_default()
+}
+
+/// An RFC 5424 header field: printable ASCII without spaces, `-` when empty.
+fn header_field(value: &str, max_len: usize) -> String {
+    let value: String = value
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(max_len)
+        .collect();
+    if value.is_empty() {
+        "-".to_string()
+    } else {
+        value
+    }
+}
+
+/// Escape a structured data parameter value: `"`, `\` and `]` get a backslash.
+pub fn escape_param_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | ']') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn rfc5424(
+    options: &SyslogOptions,
+    severity: u8,
+    msg_id: &str,
+    params: &[(&str, String)],
+    message: &str,
+    now: DateTime<Utc>,
+) -> String {
+    let structured_data: String = params
+        .iter()
+        .map(|(name, value)| format!(" {name}=\"{}\"", escape_param_value(value)))
+        .collect();
+    format!(
+        "<{}>1 {} {} {} {} {} [{SD_ID}{structured_data}] {message}",
+        options.facility as u32 * 8 + severity as u32,
+        now.to_rfc3339_opts(SecondsFormat::Micros, true),
+        header_field(&options.hostname, 255),
+        header_field(&options.app_name, 48),
+        std::process::id(),
+        header_field(msg_id, 32),
+    )
+}
+
+fn record_params<'a>(root_path: &str, record: &'a ChangeRecord) -> Vec<(&'a str, String)> {
+    let mut params = vec![
+        ("root", root_path.to_string()),
+        ("path", record.path.clone()),
+        ("change", record.change.to_string()),
+    ];
+    if let Some(old_digest) = &record.old_digest {
+        params.push(("old_digest", old_digest.clone()));
+    }
+    if let Some(new_digest) = &record.new_digest {
+        params.push(("new_digest", new_digest.clone()));
+    }
+    params
+}
+
+fn summary(result: &SnapshotCompareResult) -> (u8, String) {
+    let severity =
+        if result.created.is_empty() && result.deleted.is_empty() && result.changed.is_empty() {
+            SEVERITY_INFO
+        } else {
+            SEVERITY_NOTICE
+        };
+    let message = format!(
+        "{} created, {} deleted, {} changed",
+        result.created.len(),
+        result.deleted.len(),
+        result.changed.len()
+    );
+    (severity, message)
+}
+
+/// RFC 5424 messages for a compare result of `root_path`: one per record, then a summary.
+pub fn syslog_messages(
+    root_path: &str,
+    result: &SnapshotCompareResult,
+    records: &[ChangeRecord],
+    options: &SyslogOptions,
+) -> Vec<String> {
+    let now = Utc::now();
+    let mut messages: Vec<String> = records
+        .iter()
+        .map(|record| {
+            rfc5424(
+                options,
+                SEVERITY_WARNING,
+                "CHANGE",
+                &record_params(root_path, record),
+                &format!("{} {}", record.change, record.path),
+                now,
+            )
+        })
+        .collect();
+    let (severity, message) = summary(result);
+    let params = [
+        ("root", root_path.to_string()),
+        ("created", result.created.len().to_string()),
+        ("deleted", result.deleted.len().to_string()),
+        ("changed", result.changed.len().to_string()),
+    ];
+    messages.push(rfc5424(
+        options, severity, "SUMMARY", &params, &message, now,
+    ));
+    messages
+}
+
+/// Serialize fields in the journald native protocol. Values containing a newline use the binary
+/// form: the name, a newline, the length as little endian u64, the value and a newline.
+pub fn journald_entry(fields: &[(&str, String)]) -> Vec<u8> {
+    let mut entry = vec![];
+    for (name, value) in fields {
+        entry.extend_from_slice(name.as_bytes());
+        if value.contains('\n') {
+            entry.push(b'\n');
+            entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            entry.push(b'=');
+        }
+        entry.extend_from_slice(value.as_bytes());
+        entry.push(b'\n');
+    }
+    entry
+}
+
+/// journald entries for a compare result of `root_path`: one per record, with `FSHASH_PATH`,
+/// `FSHASH_CHANGE`, `FSHASH_OLD_DIGEST` and `FSHASH_NEW_DIGEST` fields, then a summary.
+pub fn journald_entries(
+    root_path: &str,
+    result: &SnapshotCompareResult,
+    records: &[ChangeRecord],
+    options: &SyslogOptions,
+) -> Vec<Vec<u8>> {
+    let common = |severity: u8, message: String| {
+        vec![
+            ("MESSAGE", message),
+            ("PRIORITY", severity.to_string()),
+            ("SYSLOG_FACILITY", options.facility.to_string()),
+            ("SYSLOG_IDENTIFIER", options.app_name.clone()),
+            ("FSHASH_ROOT", root_path.to_string()),
+        ]
+    };
+    let mut entries: Vec<Vec<u8>> = records
+        .iter()
+        .map(|record| {
+            let mut fields = common(
+                SEVERITY_WARNING,
+                format!("{} {}", record.change, record.path),
+            );
+            fields.push(("FSHASH_PATH", record.path.clone()));
+            fields.push(("FSHASH_CHANGE", record.change.to_string()));
+            if let Some(old_digest) = &record.old_digest {
+                fields.push(("FSHASH_OLD_DIGEST", old_digest.clone()));
+            }
+            if let Some(new_digest) = &record.new_digest {
+                fields.push(("FSHASH_NEW_DIGEST", new_digest.clone()));
+            }
+            journald_entry(&fields)
+        })
+        .collect();
+    let (severity, message) = summary(result);
+    let mut fields = common(severity, message);
+    fields.push(("FSHASH_CHANGE", "summary".to_string()));
+    fields.push(("FSHASH_CREATED", result.created.len().to_string()));
+    fields.push(("FSHASH_DELETED", result.deleted.len().to_string()));
+    fields.push(("FSHASH_CHANGED", result.changed.len().to_string()));
+    entries.push(journald_entry(&fields));
+    entries
+}
+
+/// Send each datagram to the Unix datagram socket at `socket`, e.g. `/dev/log` or
+/// [`JOURNALD_SOCKET`].
+pub fn send_datagrams<T: AsRef<[u8]>>(socket: &Path, datagrams: &[T]) -> Result<(), Error> {
+    let sender = UnixDatagram::unbound()?;
+    for datagram in datagrams {
+        sender.send_to(datagram.as_ref(), socket)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::{change_records, test_snapshot};
+
+    fn receive(socket: &UnixDatagram) -> Vec<u8> {
+        let mut buffer = vec![0; 64 * 1024];
+        let n = socket.recv(&mut buffer).unwrap();
+        buffer.truncate(n);
+        buffer
+    }
+
+    #[test]
+    fn syslog_and_journald_datagrams() {
+        assert!(!Path::new("./target/build/test_syslog/").exists());
+        fs::create_dir_all(Path::new("./target/build/test_syslog/")).unwrap();
+        let left = test_snapshot("/etc", "", 0, &[("/etc/passwd", 1), ("/etc/old", 2)]);
+        let right = test_snapshot("/etc", "", 0, &[("/etc/passwd", 3), ("/etc/we\"i]rd", 4)]);
+        let result = SnapshotCompareResult {
+            created: vec!["/etc/we\"i]rd".to_string()],
+            deleted: vec!["/etc/old".to_string()],
+            changed: vec!["/etc/passwd".to_string()],
+        };
+        let records = change_records(&result, &left, &right).unwrap();
+        assert_eq!(records[2].old_digest, Some("01".to_string()));
+        assert_eq!(records[2].new_digest, Some("03".to_string()));
+        let options = SyslogOptions {
+            hostname: "host name".to_string(),
+            ..Default::default()
+        };
+
+        let server = UnixDatagram::bind("./target/build/test_syslog/log").unwrap();
+        let messages = syslog_messages("/etc", &result, &records, &options);
+        send_datagrams(Path::new("./target/build/test_syslog/log"), &messages).unwrap();
+        let created = String::from_utf8(receive(&server)).unwrap();
+        assert!(created.starts_with("<28>1 "));
+        let fields: Vec<&str> = created.splitn(7, ' ').collect();
+        assert_eq!(fields[2], "hostname");
+        assert_eq!(fields[3], "fshash");
+        assert_eq!(fields[5], "CHANGE");
+        assert!(created.contains(r#"path="/etc/we\"i\]rd" change="created" new_digest="04"]"#));
+        assert!(!created.contains("old_digest"));
+        receive(&server);
+        let changed = String::from_utf8(receive(&server)).unwrap();
+        assert!(changed.contains(r#"old_digest="01" new_digest="03"] changed /etc/passwd"#));
+        let summary = String::from_utf8(receive(&server)).unwrap();
+        assert!(summary.starts_with("<29>1 "));
+        assert!(summary.ends_with(
+            r#"[fshash@32473 root="/etc" created="1" deleted="1" changed="1"] 1 created, 1 deleted, 1 changed"#
+        ));
+
+        let entries = journald_entries("/etc", &result, &records, &options);
+        send_datagrams(Path::new("./target/build/test_syslog/log"), &entries).unwrap();
+        receive(&server);
+        let deleted = String::from_utf8(receive(&server)).unwrap();
+        assert!(deleted.contains("\nFSHASH_PATH=/etc/old\nFSHASH_CHANGE=deleted\n"));
+        assert!(deleted.contains("\nFSHASH_OLD_DIGEST=02\n"));
+        assert!(!deleted.contains("FSHASH_NEW_DIGEST"));
+        assert!(deleted.contains("\nPRIORITY=4\n"));
+        fs::remove_dir_all(Path::new("./target/build/test_syslog/")).unwrap();
+    }
+
+    #[test]
+    fn journald_binary_fields() {
+        let entry = journald_entry(&[
+            ("MESSAGE", "two\nlines".to_string()),
+            ("FSHASH_PATH", "/etc/passwd".to_string()),
+        ]);
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&9u64.to_le_bytes());
+        expected.extend_from_slice(b"two\nlines\nFSHASH_PATH=/etc/passwd\n");
+        assert_eq!(entry, expected);
+        assert_eq!(escape_param_value(r#"a"b\c]d"#), r#"a\"b\\c\]d"#);
+    }
+}
@@ -0,0 +1,47 @@
+use anyhow::{anyhow, Error};
+
+/// Drop empty and `.` components, refusing paths that climb out of the root with `..`.
+pub fn normalize(path: &str) -> Result<String, Error> {
+    let mut parts = vec![];
+    for part in path.split('/') {
+        match part {
+            "" | "." => continue,
+            ".." => return Err(anyhow!("{path} leaves the root")),
+            part => parts.push(part),
+        }
+    }
+    Ok(parts.join("/"))
+}
+
+/// Look `id` up in the contents of an `/etc/passwd` or `/etc/group` file.
+pub fn owner_name(contents: &str, id: u32) -> Option<String> {
+    contents.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        let name = fields.next()?;
+        let line_id: u32 = fields.nth(1)?.parse().ok()?;
+        (line_id == id).then(|| name.to_string())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalized_paths() {
+        assert_eq!(normalize("./a//b/./c/").unwrap(), "a/b/c");
+        assert!(normalize("a/../../etc/shadow").is_err());
+    }
+
+    #[test]
+    fn owners() {
+        let passwd = "root:x:0:0:root:/root:/bin/bash\nbob:x:1000:1000::/home/bob:/bin/sh\n";
+        assert_eq!(owner_name(passwd, 1000), Some("bob".to_string()));
+        assert_eq!(owner_name(passwd, 0), Some("root".to_string()));
+        assert_eq!(owner_name(passwd, 7), None);
+        assert_eq!(
+            owner_name("wheel:x:10:bob\n", 10),
+            Some("wheel".to_string())
+        );
+    }
+}
@@ -0,0 +1,515 @@
+use crate::hasher::hash_path;
+use crate::snapshot::{FileMetadata, Snapshot};
+use anyhow::{anyhow, Error};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+/// Options for [`verify`].
+#[derive(Debug, Clone, Default)]
+pub struct VerifyOptions {
+    /// Stop walking at the first created, deleted or changed file.
+    pub fail_fast: bool,
+    /// Also treat differing size, ctime or mtime as a change.
+    pub check_metadata: bool,
+    /// Paths to skip in addition to the baseline's own black list.
+    pub black_list: Vec<String>,
+}
+
+/// One difference between the baseline and the live tree.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum VerifyEvent {
+    /// The file exists on disk but not in the baseline.
+    Created { path: String, actual: FileMetadata },
+    /// The file is in the baseline but missing from disk.
+    Deleted {
+        path: String,
+        expected: FileMetadata,
+    },
+    Changed {
+        path: String,
+        expected: FileMetadata,
+        actual: FileMetadata,
+    },
+    /// Only reported by [`verify_paths`]: the file, or a requested path, has no baseline entry.
+    /// `actual` is `None` when the path does not exist on disk either.
+    NotInBaseline {
+        path: String,
+        actual: Option<FileMetadata>,
+    },
+    /// The file or directory could not be read.
+    Error { path: String, message: String },
+}
+
+impl VerifyEvent {
+    pub fn path(&self) -> &str {
+        match self {
+            VerifyEvent::Created { path, .. }
+            | VerifyEvent::Deleted { path, .. }
+            | VerifyEvent::Changed { path, .. }
+            | VerifyEvent::NotInBaseline { path, .. }
+            | VerifyEvent::Error { path, .. } => path,
+        }
+    }
+
+    /// Whether the event is an integrity violation, as opposed to a read error.
+    pub fn is_violation(&self) -> bool {
+        !matches!(self, VerifyEvent::Error { .. })
+    }
+}
+
+/// Counts of what [`verify`] found.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct VerifyReport {
+    pub checked: u64,
+    pub created: u64,
+    pub deleted: u64,
+    pub changed: u64,
+    pub not_in_baseline: u64,
+    pub errors: u64,
+    /// Set when `fail_fast` stopped the walk before the whole tree was checked.
+    pub stopped_early: bool,
+}
+
+impl VerifyReport {
+    pub fn is_clean(&self) -> bool {
+        self.created == 0 && self.deleted == 0 && self.changed == 0 && self.not_in_baseline == 0
+    }
+
+    fn record(&mut self, event: &VerifyEvent) {
+        match event {
+            VerifyEvent::Created { .. } => self.created += 1,
+            VerifyEvent::Deleted { .. } => self.deleted += 1,
+            VerifyEvent::Changed { .. } => self.changed += 1,
+            VerifyEvent::NotInBaseline { .. } => self.not_in_baseline += 1,
+            VerifyEvent::Error { .. } => self.errors += 1,
+        }
+    }
+}
+
+/// Whether `actual` no longer matches `expected`.
+pub(crate) fn differs(
+    expected: &FileMetadata,
+    actual: &FileMetadata,
+    check_metadata: bool,
+) -> bool {
+    expected.check_sum != actual.check_sum
+        || (check_metadata
+            && (expected.size != actual.size
+                || expected.ctime != actual.ctime
+                || expected.mtime != actual.mtime))
+}
+
+pub(crate) fn blacklisted(path: &str, baseline: &Snapshot, options: &VerifyOptions) -> bool {
+    baseline
+        .black_list
+        .iter()
+        .chain(options.black_list.iter())
+        .any(|bl| path.starts_with(bl.as_str()))
+}
+
+/// Whether `path` is `prefix` itself or lies below it.
+pub(crate) fn under(path: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    path == prefix
+        || (path.starts_with(prefix) && path[prefix.len()..].starts_with('/'))
+        || prefix.is_empty()
+}
+
+struct Verifier<'a, F> {
+    baseline: &'a Snapshot,
+    expected: &'a HashMap<String, FileMetadata>,
+    options: &'a VerifyOptions,
+    verbose: bool,
+    on_event: F,
+    report: VerifyReport,
+    seen: HashSet<String>,
+    /// Report files missing from the baseline as `NotInBaseline` rather than `Created`.
+    selective: bool,
+}
+
+impl<F: FnMut(&VerifyEvent)> Verifier<'_, F> {
+    /// Pass `event` on, returning true when the walk should stop.
+    fn emit(&mut self, event: VerifyEvent) -> bool {
+        self.report.record(&event);
+        (self.on_event)(&event);
+        if self.options.fail_fast && event.is_violation() {
+            self.report.stopped_early = true;
+        }
+        self.report.stopped_early
+    }
+
+    fn walk(&mut self, root: &Path) -> bool {
+        for entry in walkdir::WalkDir::new(root).sort_by_file_name() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    let path = e
+                        .path()
+                        .map(|p| p.to_string_lossy().to_string())
+                        .unwrap_or_default();
+                    let event = VerifyEvent::Error {
+                        path,
+                        message: e.to_string(),
+                    };
+                    if self.emit(event) {
+                        return true;
+                    }
+                    continue;
+                }
+            };
+            if entry.path().is_file() && self.check(entry.path()) {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn check(&mut self, path: &Path) -> bool {
+        let key = match path.to_str() {
+            Some(key) => key.to_string(),
+            None => return false,
+        };
+        if blacklisted(&key, self.baseline, self.options) {
+            return false;
+        }
+        let actual = match hash_path(path, self.baseline.hash_type, self.verbose) {
+            Ok(actual) => actual,
+            Err(e) => {
+                let event = VerifyEvent::Error {
+                    path: key,
+                    message: e.to_string(),
+                };
+                return self.emit(event);
+            }
+        };
+        self.report.checked += 1;
+        self.seen.insert(key.clone());
+        let event = match self.expected.get(&key) {
+            None if self.selective => VerifyEvent::NotInBaseline {
+                path: key,
+                actual: Some(actual),
+            },
+            None => VerifyEvent::Created { path: key, actual },
+            Some(expected) if differs(expected, &actual, self.options.check_metadata) => {
+                VerifyEvent::Changed {
+                    path: key,
+                    expected: expected.clone(),
+                    actual,
+                }
+            }
+            Some(_) => return false,
+        };
+        self.emit(event)
+    }
+
+    /// Report baseline entries below `prefixes` that the walk did not see.
+    fn report_missing(&mut self, prefixes: &[&str]) {
+        let mut missing: Vec<&FileMetadata> = self
+            .expected
+            .iter()
+            .filter(|(path, _)| {
+                !self.seen.contains(*path)
+                    && prefixes.iter().any(|prefix| under(path, prefix))
+                    && !blacklisted(path, self.baseline, self.options)
+            })
+            .map(|(_, entry)| entry)
+            .collect();
+        missing.sort_by(|a, b| a.path.cmp(&b.path));
+        for entry in missing {
+            let event = VerifyEvent::Deleted {
+                path: entry.path.clone(),
+                expected: entry.clone(),
+            };
+            if self.emit(event) {
+                break;
+            }
+        }
+    }
+}
+
+/// Walk `baseline.root_path` and compare every file against the baseline as it goes, without
+/// building a second snapshot.
+///
+/// Each difference is passed to `on_event` as soon as it is found; files missing from disk are
+/// reported once the walk is done. With `options.fail_fast` the walk stops at the first
+/// violation.
+pub fn verify<F>(
+    baseline: &Snapshot,
+    options: &VerifyOptions,
+    verbose: bool,
+    on_event: F,
+) -> Result<VerifyReport, Error>
+where
+    F: FnMut(&VerifyEvent),
+{
+    let expected = baseline
+        .file_hashes
+        .lock()
+        .map_err(|_| anyhow!("unable to lock snapshot"))?;
+    let mut verifier = Verifier {
+        baseline,
+        expected: &expected,
+        options,
+        verbose,
+        on_event,
+        report: VerifyReport::default(),
+        seen: HashSet::new(),
+        selective: false,
+    };
+
+    if verbose {
+        eprintln!("Verifying Directory: {}", baseline.root_path);
+    }
+    if !verifier.walk(Path::new(&baseline.root_path)) {
+        verifier.report_missing(&[""]);
+    }
+    Ok(verifier.report)
+}
+
+/// Rehash only `paths`, each a file or a directory prefix, and compare them against the
+/// matching baseline entries.
+///
+/// Paths must be spelled the way the baseline keys them, i.e. below `baseline.root_path`. Files
+/// on disk without a baseline entry, and requested paths the baseline knows nothing about, are
+/// reported as [`VerifyEvent::NotInBaseline`].
+pub fn verify_paths<F>(
+    baseline: &Snapshot,
+    paths: &[&str],
+    options: &VerifyOptions,
+    verbose: bool,
+    on_event: F,
+) -> Result<VerifyReport, Error>
+where
+    F: FnMut(&VerifyEvent),
+{
+    let expected = baseline
+        .file_hashes
+        .lock()
+        .map_err(|_| anyhow!("unable to lock snapshot"))?;
+    let mut verifier = Verifier {
+        baseline,
+        expected: &expected,
+        options,
+        verbose,
+        on_event,
+        report: VerifyReport::default(),
+        seen: HashSet::new(),
+        selective: true,
+    };
+
+    let paths = requested_paths(paths);
+    for path in &paths {
+        let path = path.as_str();
+        if verbose {
+            eprintln!("Verifying: {}", path);
+        }
+        let on_disk = Path::new(path).exists();
+        let in_baseline = expected.keys().any(|key| under(key, path));
+        if !on_disk && !in_baseline {
+            let event = VerifyEvent::NotInBaseline {
+                path: path.to_string(),
+                actual: None,
+            };
+            if verifier.emit(event) {
+                return Ok(verifier.report);
+            }
+            continue;
+        }
+        if on_disk && verifier.walk(Path::new(path)) {
+            return Ok(verifier.report);
+        }
+    }
+    let paths: Vec<&str> = paths.iter().map(String::as_str).collect();
+    verifier.report_missing(&paths);
+    Ok(verifier.report)
+}
+
+/// Strip trailing slashes, keeping `/` itself, and drop paths that another requested path
+/// already covers so that nothing is hashed or counted twice.
+fn requested_paths(paths: &[&str]) -> Vec<String> {
+    let trimmed: Vec<&str> = paths
+        .iter()
+        .map(|path| match path.trim_end_matches('/') {
+            "" if path.starts_with('/') => "/",
+            trimmed => trimmed,
+        })
+        .collect();
+    trimmed
+        .iter()
+        .enumerate()
+        .filter(|(i, path)| {
+            !trimmed
+                .iter()
+                .enumerate()
+                .any(|(j, other)| j != *i && under(path, other) && (*path != other || j < *i))
+        })
+        .map(|(_, path)| path.to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hasher::HashType;
+    use std::fs;
+
+    #[test]
+    fn streams_differences() {
+        assert!(!Path::new("./target/build/test_verify/").exists());
+        fs::create_dir_all(Path::new("./target/build/test_verify/sub/")).unwrap();
+        fs::write("./target/build/test_verify/a", "a").unwrap();
+        fs::write("./target/build/test_verify/b", "b").unwrap();
+        fs::write("./target/build/test_verify/sub/c", "c").unwrap();
+        let baseline = Snapshot::new(
+            Path::new("./target/build/test_verify"),
+            HashType::BLAKE3,
+            vec![],
+            false,
+        )
+        .unwrap();
+
+        let mut events = vec![];
+        let report = verify(&baseline, &VerifyOptions::default(), true, |e| {
+            events.push(e.clone())
+        })
+        .unwrap();
+        assert!(report.is_clean());
+        assert_eq!(report.checked, 3);
+        assert!(events.is_empty());
+
+        fs::write("./target/build/test_verify/a", "changed").unwrap();
+        fs::remove_file("./target/build/test_verify/b").unwrap();
+        fs::write("./target/build/test_verify/sub/d", "d").unwrap();
+        let report = verify(&baseline, &VerifyOptions::default(), true, |e| {
+            events.push(e.clone())
+        })
+        .unwrap();
+        assert_eq!((report.changed, report.deleted, report.created), (1, 1, 1));
+        let paths: Vec<&str> = events.iter().map(|e| e.path()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "./target/build/test_verify/a",
+                "./target/build/test_verify/sub/d",
+                "./target/build/test_verify/b",
+            ]
+        );
+
+        let options = VerifyOptions {
+            fail_fast: true,
+            ..Default::default()
+        };
+        let mut count = 0;
+        let report = verify(&baseline, &options, true, |_| count += 1).unwrap();
+        assert!(report.stopped_early);
+        assert_eq!(count, 1);
+
+        let options = VerifyOptions {
+            black_list: vec!["./target/build/test_verify/sub".to_string()],
+            ..Default::default()
+        };
+        let report = verify(&baseline, &options, true, |_| {}).unwrap();
+        assert_eq!((report.changed, report.deleted, report.created), (1, 1, 0));
+        fs::remove_dir_all(Path::new("./target/build/test_verify/")).unwrap();
+    }
+
+    #[test]
+    fn selected_paths() {
+        assert!(!Path::new("./target/build/test_verify_paths/").exists());
+        fs::create_dir_all(Path::new("./target/build/test_verify_paths/pam.d/")).unwrap();
+        fs::write("./target/build/test_verify_paths/sudo", "sudo").unwrap();
+        fs::write("./target/build/test_verify_paths/other", "other").unwrap();
+        fs::write("./target/build/test_verify_paths/pam.d/login", "login").unwrap();
+        fs::write("./target/build/test_verify_paths/pam.d/su", "su").unwrap();
+        let baseline = Snapshot::new(
+            Path::new("./target/build/test_verify_paths"),
+            HashType::SHA3,
+            vec![],
+            false,
+        )
+        .unwrap();
+
+        fs::write("./target/build/test_verify_paths/sudo", "trojan").unwrap();
+        fs::write("./target/build/test_verify_paths/other", "changed").unwrap();
+        fs::write("./target/build/test_verify_paths/pam.d/extra", "extra").unwrap();
+        fs::remove_file("./target/build/test_verify_paths/pam.d/su").unwrap();
+
+        let mut events = vec![];
+        let report = verify_paths(
+            &baseline,
+            &[
+                "./target/build/test_verify_paths/sudo",
+                "./target/build/test_verify_paths/pam.d/",
+                "./target/build/test_verify_paths/missing",
+            ],
+            &VerifyOptions::default(),
+            true,
+            |e| events.push(e.clone()),
+        )
+        .unwrap();
+        assert_eq!(report.checked, 3);
+        assert_eq!((report.changed, report.deleted, report.created), (1, 1, 0));
+        assert_eq!(report.not_in_baseline, 2);
+        assert!(matches!(
+            &events[0],
+            VerifyEvent::Changed { path, .. } if path == "./target/build/test_verify_paths/sudo"
+        ));
+        assert!(events.iter().any(|e| matches!(
+            e,
+            VerifyEvent::NotInBaseline { path, actual: Some(_) }
+                if path == "./target/build/test_verify_paths/pam.d/extra"
+        )));
+        assert!(events.iter().any(|e| matches!(
+            e,
+            VerifyEvent::NotInBaseline { path, actual: None }
+                if path == "./target/build/test_verify_paths/missing"
+        )));
+        assert!(events.iter().any(|e| matches!(
+            e,
+            VerifyEvent::Deleted { path, .. } if path == "./target/build/test_verify_paths/pam.d/su"
+        )));
+        fs::remove_dir_all(Path::new("./target/build/test_verify_paths/")).unwrap();
+    }
+
+    #[test]
+    fn requested_paths_keep_the_root_and_drop_covered_paths() {
+        assert_eq!(requested_paths(&["/"]), vec!["/"]);
+        assert_eq!(
+            requested_paths(&["/etc/ssh", "/etc/", "/etc", "/etcetera"]),
+            vec!["/etc", "/etcetera"]
+        );
+        assert_eq!(requested_paths(&["/etc", "/"]), vec!["/"]);
+    }
+
+    #[test]
+    fn verifies_the_root_path_itself() {
+        assert!(!Path::new("./target/build/test_verify_root/").exists());
+        fs::create_dir_all(Path::new("./target/build/test_verify_root/sub/")).unwrap();
+        fs::write("./target/build/test_verify_root/a", "a").unwrap();
+        fs::write("./target/build/test_verify_root/sub/b", "b").unwrap();
+        let baseline = Snapshot::new(
+            Path::new("./target/build/test_verify_root"),
+            HashType::BLAKE3,
+            vec![],
+            false,
+        )
+        .unwrap();
+
+        let report = verify_paths(
+            &baseline,
+            &[
+                "./target/build/test_verify_root/",
+                "./target/build/test_verify_root/sub",
+            ],
+            &VerifyOptions::default(),
+            true,
+            |_| {},
+        )
+        .unwrap();
+        assert!(report.is_clean());
+        assert_eq!(report.checked, 2);
+        fs::remove_dir_all(Path::new("./target/build/test_verify_root/")).unwrap();
+    }
+}
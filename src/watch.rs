@@ -0,0 +1,568 @@
+use crate::hasher::hash_path;
+use crate::snapshot::{FileMetadata, Snapshot, SnapshotCompareResult};
+use crate::verify::{differs, under};
+use anyhow::{anyhow, Error};
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Options for [`Watcher`].
+#[derive(Debug, Clone)]
+pub struct WatchOptions {
+    /// Paths to ignore in addition to the baseline's own black list.
+    pub black_list: Vec<String>,
+    /// Also treat differing size, ctime or mtime as a change.
+    pub check_metadata: bool,
+    /// Stop adding inotify watches after this many, as if `fs.inotify.max_user_watches` had been
+    /// reached. `None` watches until the kernel refuses.
+    pub max_watches: Option<usize>,
+    /// How often directories that could not be watched are rescanned.
+    pub rescan_interval: Duration,
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        WatchOptions {
+            black_list: vec![],
+            check_metadata: false,
+            max_watches: None,
+            rescan_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+/// The live state of a watched tree, shared by the inotify and fanotify backends: rehashes the
+/// paths a backend reports, compares them with the last known entry and collects the differences
+/// until [`Tracker::take`] is called.
+#[derive(Debug)]
+pub(crate) struct Tracker {
+    snapshot: Snapshot,
+    options: WatchOptions,
+    /// The entry each path had when changes were last taken, `None` when it did not exist.
+    pending: BTreeMap<String, Option<FileMetadata>>,
+    dirty: bool,
+}
+
+impl Tracker {
+    pub(crate) fn new(baseline: &Snapshot, options: WatchOptions) -> Result<Tracker, Error> {
+        let file_hashes = baseline
+            .file_hashes
+            .lock()
+            .map_err(|_| anyhow!("unable to lock snapshot"))?
+            .clone();
+        let snapshot = Snapshot {
+            file_hashes: Arc::new(Mutex::new(file_hashes)),
+            ..baseline.clone()
+        };
+        Ok(Tracker {
+            snapshot,
+            options,
+            pending: BTreeMap::new(),
+            dirty: false,
+        })
+    }
+
+    pub(crate) fn root(&self) -> PathBuf {
+        PathBuf::from(&self.snapshot.root_path)
+    }
+
+    pub(crate) fn options(&self) -> &WatchOptions {
+        &self.options
+    }
+
+    pub(crate) fn is_blacklisted(&self, path: &str) -> bool {
+        self.snapshot
+            .black_list
+            .iter()
+            .chain(self.options.black_list.iter())
+            .any(|bl| path.starts_with(bl.as_str()))
+    }
+
+    fn record(&mut self, path: String, previous: Option<FileMetadata>) {
+        self.dirty = true;
+        self.pending.entry(path).or_insert(previous);
+    }
+
+    /// Rehash `path` and record how it differs from the last known entry. A path that is no
+    /// longer a file counts as deleted.
+    pub(crate) fn rehash(&mut self, path: &Path) {
+        let key = match path.to_str() {
+            Some(key) => key.to_string(),
+            None => return,
+        };
+        if self.is_blacklisted(&key) {
+            return;
+        }
+        if !path.is_file() {
+            self.remove(&key);
+            return;
+        }
+        let actual = match hash_path(path, self.snapshot.hash_type, false) {
+            Ok(actual) => actual,
+            Err(e) => {
+                eprintln!("Warning: {e}");
+                return;
+            }
+        };
+        let mut file_hashes = match self.snapshot.file_hashes.lock() {
+            Ok(file_hashes) => file_hashes,
+            Err(_) => return,
+        };
+        let changed = match file_hashes.get(&key) {
+            None => true,
+            Some(expected) => differs(expected, &actual, self.options.check_metadata),
+        };
+        let previous = file_hashes.insert(key.clone(), actual);
+        drop(file_hashes);
+        self.dirty = true;
+        if changed {
+            self.record(key, previous);
+        }
+    }
+
+    /// Forget `path` and everything below it.
+    pub(crate) fn remove(&mut self, path: &str) {
+        let removed: Vec<(String, FileMetadata)> = match self.snapshot.file_hashes.lock() {
+            Ok(mut file_hashes) => {
+                let keys: Vec<String> = file_hashes
+                    .keys()
+                    .filter(|key| under(key, path))
+                    .cloned()
+                    .collect();
+                keys.into_iter()
+                    .filter_map(|key| file_hashes.remove_entry(&key))
+                    .collect()
+            }
+            Err(_) => return,
+        };
+        for (key, entry) in removed {
+            self.record(key, Some(entry));
+        }
+    }
+
+    /// Walk `dir` and bring everything below it up to date, for when events may have been lost.
+    pub(crate) fn rescan(&mut self, dir: &Path) {
+        let prefix = dir.to_string_lossy().to_string();
+        let mut seen: HashSet<String> = HashSet::new();
+        for entry in walkdir::WalkDir::new(dir)
+            .sort_by_file_name()
+            .into_iter()
+            .flatten()
+        {
+            if entry.path().is_file() {
+                if let Some(key) = entry.path().to_str() {
+                    seen.insert(key.to_string());
+                }
+                self.rehash(entry.path());
+            }
+        }
+        let missing: Vec<String> = match self.snapshot.file_hashes.lock() {
+            Ok(file_hashes) => file_hashes
+                .keys()
+                .filter(|key| under(key, &prefix) && !seen.contains(*key))
+                .cloned()
+                .collect(),
+            Err(_) => return,
+        };
+        for key in missing {
+            self.remove(&key);
+        }
+    }
+
+    /// Return the changes since the last call, if there are any. Each touched path is compared
+    /// with the entry it had then, so a file deleted and recreated with the same content, or
+    /// changed and changed back, is not reported.
+    pub(crate) fn take(&mut self) -> Option<SnapshotCompareResult> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        let file_hashes = self.snapshot.file_hashes.lock().ok()?;
+        let mut result = SnapshotCompareResult {
+            created: vec![],
+            deleted: vec![],
+            changed: vec![],
+        };
+        for (path, previous) in std::mem::take(&mut self.pending) {
+            match (previous, file_hashes.get(&path)) {
+                (None, Some(_)) => result.created.push(path),
+                (Some(_), None) => result.deleted.push(path),
+                (Some(previous), Some(actual))
+                    if differs(&previous, actual, self.options.check_metadata) =>
+                {
+                    result.changed.push(path)
+                }
+                _ => {}
+            }
+        }
+        match result.created.is_empty() && result.deleted.is_empty() && result.changed.is_empty() {
+            true => None,
+            false => Some(result),
+        }
+    }
+
+    pub(crate) fn snapshot(&mut self) -> &Snapshot {
+        if self.dirty {
+            self.snapshot.update_merkle_root();
+            self.dirty = false;
+        }
+        &self.snapshot
+    }
+}
+
+/// Wait until `fd` is readable or `timeout` passes, returning whether it is readable.
+pub(crate) fn wait_readable(fd: i32, timeout: Duration) -> Result<bool, Error> {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
+    match unsafe { libc::poll(&mut pollfd, 1, timeout) } {
+        -1 => {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                Ok(false)
+            } else {
+                Err(e.into())
+            }
+        }
+        0 => Ok(false),
+        _ => Ok(true),
+    }
+}
+
+const WATCH_MASK: WatchMask = WatchMask::CLOSE_WRITE
+    .union(WatchMask::ATTRIB)
+    .union(WatchMask::CREATE)
+    .union(WatchMask::DELETE)
+    .union(WatchMask::MOVED_FROM)
+    .union(WatchMask::MOVED_TO)
+    .union(WatchMask::DELETE_SELF)
+    .union(WatchMask::ONLYDIR);
+
+/// Watches the tree of a baseline snapshot with inotify and rehashes files as they change.
+///
+/// Changes are reported in the same shape as [`crate::snapshot::compare_hashes`], relative to
+/// the last known state of each file, which starts out as the baseline. Directories that cannot
+/// be watched because the watch limit is reached are rescanned every
+/// `options.rescan_interval` instead, and an event queue overflow triggers a rescan of the whole
+/// tree.
+pub struct Watcher {
+    inotify: Inotify,
+    tracker: Tracker,
+    watches: HashMap<WatchDescriptor, PathBuf>,
+    unwatched: BTreeSet<PathBuf>,
+    last_rescan: Instant,
+    buffer: Vec<u8>,
+}
+
+impl Watcher {
+    pub fn new(baseline: &Snapshot, options: WatchOptions) -> Result<Watcher, Error> {
+        let mut watcher = Watcher {
+            inotify: Inotify::init()?,
+            tracker: Tracker::new(baseline, options)?,
+            watches: HashMap::new(),
+            unwatched: BTreeSet::new(),
+            last_rescan: Instant::now(),
+            buffer: vec![0; 64 * 1024],
+        };
+        let root = watcher.tracker.root();
+        watcher.add_watches(&root)?;
+        Ok(watcher)
+    }
+
+    /// Directories that are rescanned periodically because they could not be watched.
+    pub fn unwatched(&self) -> Vec<PathBuf> {
+        self.unwatched.iter().cloned().collect()
+    }
+
+    /// The current state of the watched tree.
+    pub fn snapshot(&mut self) -> &Snapshot {
+        self.tracker.snapshot()
+    }
+
+    fn add_watches(&mut self, dir: &Path) -> Result<(), Error> {
+        let mut walker = walkdir::WalkDir::new(dir).sort_by_file_name().into_iter();
+        while let Some(entry) = walker.next() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(_) => continue,
+            };
+            if !entry.file_type().is_dir() {
+                continue;
+            }
+            let path = entry.path().to_path_buf();
+            if self.tracker.is_blacklisted(&path.to_string_lossy()) {
+                walker.skip_current_dir();
+                continue;
+            }
+            let limit_reached = self
+                .tracker
+                .options()
+                .max_watches
+                .is_some_and(|max| self.watches.len() >= max);
+            let added = if limit_reached {
+                Err(io::Error::from_raw_os_error(libc::ENOSPC))
+            } else {
+                self.inotify.watches().add(&path, WATCH_MASK)
+            };
+            match added {
+                Ok(wd) => {
+                    self.watches.insert(wd, path);
+                }
+                Err(e) if e.raw_os_error() == Some(libc::ENOSPC) => {
+                    // the subtree is rescanned instead, so do not try to watch below it
+                    walker.skip_current_dir();
+                    self.unwatched.insert(path);
+                }
+                Err(e) if path == dir => return Err(e.into()),
+                Err(_) => continue,
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait up to `timeout` for filesystem events, handle them and any rescans that are due, and
+    /// return what changed. `None` when nothing did.
+    pub fn poll(&mut self, timeout: Duration) -> Result<Option<SnapshotCompareResult>, Error> {
+        if wait_readable(self.inotify.as_raw_fd(), timeout)? {
+            self.read_events()?;
+        }
+        if !self.unwatched.is_empty()
+            && self.last_rescan.elapsed() >= self.tracker.options().rescan_interval
+        {
+            for dir in self.unwatched() {
+                self.tracker.rescan(&dir);
+            }
+            self.last_rescan = Instant::now();
+        }
+        Ok(self.tracker.take())
+    }
+
+    /// Call `on_change` with each batch of changes until it returns false.
+    pub fn run<F>(&mut self, mut on_change: F) -> Result<(), Error>
+    where
+        F: FnMut(&SnapshotCompareResult) -> bool,
+    {
+        loop {
+            if let Some(result) = self.poll(Duration::from_secs(1))? {
+                if !on_change(&result) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Events were dropped, so anything may have changed: watch new directories and rescan the
+    /// whole tree.
+    fn handle_overflow(&mut self) -> Result<(), Error> {
+        let root = self.tracker.root();
+        self.add_watches(&root)?;
+        self.tracker.rescan(&root);
+        Ok(())
+    }
+
+    fn read_events(&mut self) -> Result<(), Error> {
+        let mut buffer = std::mem::take(&mut self.buffer);
+        let events: Vec<(WatchDescriptor, EventMask, Option<PathBuf>)> =
+            match self.inotify.read_events(&mut buffer) {
+                Ok(events) => events
+                    .map(|e| (e.wd, e.mask, e.name.map(PathBuf::from)))
+                    .collect(),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => vec![],
+                Err(e) => {
+                    self.buffer = buffer;
+                    return Err(e.into());
+                }
+            };
+        self.buffer = buffer;
+
+        for (wd, mask, name) in events {
+            if mask.contains(EventMask::Q_OVERFLOW) {
+                self.handle_overflow()?;
+                continue;
+            }
+            if mask.contains(EventMask::IGNORED) {
+                self.watches.remove(&wd);
+                continue;
+            }
+            let dir = match self.watches.get(&wd) {
+                Some(dir) => dir.clone(),
+                None => continue,
+            };
+            let path = match name {
+                Some(name) => dir.join(name),
+                None => dir,
+            };
+            if mask.contains(EventMask::DELETE_SELF) {
+                self.tracker.remove(&path.to_string_lossy());
+            } else if mask.contains(EventMask::ISDIR) {
+                if mask.intersects(EventMask::CREATE | EventMask::MOVED_TO) {
+                    self.add_watches(&path)?;
+                    self.tracker.rescan(&path);
+                } else if mask.intersects(EventMask::DELETE | EventMask::MOVED_FROM) {
+                    self.tracker.remove(&path.to_string_lossy());
+                }
+            } else if mask.intersects(EventMask::DELETE | EventMask::MOVED_FROM) {
+                self.tracker.remove(&path.to_string_lossy());
+            } else if mask
+                .intersects(EventMask::CLOSE_WRITE | EventMask::MOVED_TO | EventMask::ATTRIB)
+            {
+                self.tracker.rehash(&path);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hasher::HashType;
+    use std::fs;
+
+    fn poll_until<F>(watcher: &mut Watcher, mut done: F)
+    where
+        F: FnMut(&SnapshotCompareResult) -> bool,
+    {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(10) {
+            if let Some(result) = watcher.poll(Duration::from_millis(100)).unwrap() {
+                if done(&result) {
+                    return;
+                }
+            }
+        }
+        panic!("timed out waiting for changes");
+    }
+
+    #[test]
+    fn reports_changes_as_they_happen() {
+        assert!(!Path::new("./target/build/test_watch/").exists());
+        fs::create_dir_all(Path::new("./target/build/test_watch/sub/")).unwrap();
+        fs::write("./target/build/test_watch/a", "a").unwrap();
+        fs::write("./target/build/test_watch/sub/b", "b").unwrap();
+        let baseline = Snapshot::new(
+            Path::new("./target/build/test_watch"),
+            HashType::BLAKE3,
+            vec![],
+            false,
+        )
+        .unwrap();
+        let mut watcher = Watcher::new(&baseline, WatchOptions::default()).unwrap();
+
+        fs::write("./target/build/test_watch/a", "changed").unwrap();
+        poll_until(&mut watcher, |r| {
+            r.changed == vec!["./target/build/test_watch/a".to_string()]
+        });
+
+        fs::remove_file("./target/build/test_watch/sub/b").unwrap();
+        fs::create_dir_all(Path::new("./target/build/test_watch/new/")).unwrap();
+        fs::write("./target/build/test_watch/new/c", "c").unwrap();
+        let mut created = vec![];
+        let mut deleted = vec![];
+        poll_until(&mut watcher, |r| {
+            created.extend(r.created.clone());
+            deleted.extend(r.deleted.clone());
+            !created.is_empty() && !deleted.is_empty()
+        });
+        assert_eq!(created, vec!["./target/build/test_watch/new/c".to_string()]);
+        assert_eq!(deleted, vec!["./target/build/test_watch/sub/b".to_string()]);
+
+        // rewriting identical content is not a change
+        fs::write("./target/build/test_watch/a", "changed").unwrap();
+        assert!(watcher.poll(Duration::from_millis(300)).unwrap().is_none());
+
+        let snapshot = watcher.snapshot().clone();
+        assert_eq!(snapshot.file_hashes.lock().unwrap().len(), 2);
+        assert_ne!(snapshot.merkle_root, baseline.merkle_root);
+        fs::remove_dir_all(Path::new("./target/build/test_watch/")).unwrap();
+    }
+
+    #[test]
+    fn compares_with_the_entry_before_pending_changes() {
+        assert!(!Path::new("./target/build/test_watch_tracker/").exists());
+        fs::create_dir_all(Path::new("./target/build/test_watch_tracker/")).unwrap();
+        fs::write("./target/build/test_watch_tracker/a", "a").unwrap();
+        let baseline = Snapshot::new(
+            Path::new("./target/build/test_watch_tracker"),
+            HashType::BLAKE3,
+            vec![],
+            false,
+        )
+        .unwrap();
+        let mut tracker = Tracker::new(&baseline, WatchOptions::default()).unwrap();
+        let path = Path::new("./target/build/test_watch_tracker/a");
+
+        // deleted and recreated with the same content
+        tracker.remove("./target/build/test_watch_tracker/a");
+        tracker.rehash(path);
+        assert!(tracker.take().is_none());
+
+        // changed and changed back
+        fs::write(path, "b").unwrap();
+        tracker.rehash(path);
+        fs::write(path, "a").unwrap();
+        tracker.rehash(path);
+        assert!(tracker.take().is_none());
+
+        tracker.remove("./target/build/test_watch_tracker/a");
+        fs::write(path, "c").unwrap();
+        tracker.rehash(path);
+        let result = tracker.take().unwrap();
+        assert!(result.created.is_empty() && result.deleted.is_empty());
+        assert_eq!(
+            result.changed,
+            vec!["./target/build/test_watch_tracker/a".to_string()]
+        );
+        fs::remove_dir_all(Path::new("./target/build/test_watch_tracker/")).unwrap();
+    }
+
+    #[test]
+    fn rescans_when_watches_run_out() {
+        assert!(!Path::new("./target/build/test_watch_limit/").exists());
+        fs::create_dir_all(Path::new("./target/build/test_watch_limit/sub/")).unwrap();
+        fs::write("./target/build/test_watch_limit/sub/b", "b").unwrap();
+        let baseline = Snapshot::new(
+            Path::new("./target/build/test_watch_limit"),
+            HashType::BLAKE3,
+            vec![],
+            false,
+        )
+        .unwrap();
+        let options = WatchOptions {
+            max_watches: Some(1),
+            rescan_interval: Duration::ZERO,
+            ..Default::default()
+        };
+        let mut watcher = Watcher::new(&baseline, options).unwrap();
+        assert_eq!(
+            watcher.unwatched(),
+            vec![PathBuf::from("./target/build/test_watch_limit/sub")]
+        );
+
+        fs::write("./target/build/test_watch_limit/sub/b", "changed").unwrap();
+        poll_until(&mut watcher, |r| {
+            r.changed == vec!["./target/build/test_watch_limit/sub/b".to_string()]
+        });
+
+        // an overflow rescans everything
+        fs::write("./target/build/test_watch_limit/sub/b", "again").unwrap();
+        fs::write("./target/build/test_watch_limit/c", "c").unwrap();
+        watcher.handle_overflow().unwrap();
+        let result = watcher.tracker.take().unwrap();
+        assert_eq!(
+            result.created,
+            vec!["./target/build/test_watch_limit/c".to_string()]
+        );
+        assert_eq!(
+            result.changed,
+            vec!["./target/build/test_watch_limit/sub/b".to_string()]
+        );
+        fs::remove_dir_all(Path::new("./target/build/test_watch_limit/")).unwrap();
+    }
+}
@@ -0,0 +1,231 @@
+use filesystem_hashing::encryption::EncryptionKey;
+use filesystem_hashing::hasher::HashType;
+use filesystem_hashing::mac::MacKey;
+use filesystem_hashing::signing::{generate_signing_key, save_signing_key, save_verifying_key};
+use filesystem_hashing::snapshot::{export_with_options, ExportOptions, Snapshot};
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn fshash(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_fshash"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+fn json_lines(output: &Output) -> Vec<Value> {
+    stdout(output)
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+fn create_tree(root: &Path) {
+    fs::create_dir_all(root.join("ssh")).unwrap();
+    fs::write(root.join("hosts"), "127.0.0.1 localhost\n").unwrap();
+    fs::write(root.join("ssh/sshd_config"), "PermitRootLogin no\n").unwrap();
+}
+
+#[test]
+fn snapshots() {
+    let base = Path::new("./target/build/test_cli_snapshots");
+    assert!(!base.exists());
+    let root = base.join("root");
+    create_tree(&root);
+    let root = root.to_str().unwrap();
+    let first = base.join("first.snapshot");
+    let first = first.to_str().unwrap();
+    let second = base.join("second.snapshot");
+    let second = second.to_str().unwrap();
+
+    let output = fshash(&["snap", root, "-o", first, "--json", "--verbose"]);
+    assert_eq!(output.status.code(), Some(0));
+    let stats = json_lines(&output);
+    assert_eq!(stats.len(), 1);
+    assert_eq!(stats[0]["files"], 2);
+    assert!(!output.stderr.is_empty());
+
+    let output = fshash(&["stats", first, "--json", "--verbose"]);
+    assert_eq!(json_lines(&output)[0]["files"], 2);
+    let output = fshash(&["show", first, "--json"]);
+    assert_eq!(json_lines(&output).len(), 2);
+    assert!(fshash(&["show", "./target/build/test_cli_snapshots/none"])
+        .status
+        .code()
+        .is_some_and(|code| code == 2));
+
+    fs::write(base.join("root/hosts"), "10.0.0.1 localhost\n").unwrap();
+    let output = fshash(&["verify", first, "--json"]);
+    assert_eq!(output.status.code(), Some(1));
+    let events = json_lines(&output);
+    assert_eq!(events.last().unwrap()["kind"], "summary");
+
+    assert_eq!(fshash(&["snap", root, "-o", second]).status.code(), Some(0));
+    let output = fshash(&["compare", first, second]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        stdout(&output),
+        "changed\t./target/build/test_cli_snapshots/root/hosts\n"
+    );
+    assert_eq!(fshash(&["compare", second, second]).status.code(), Some(0));
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn protected_snapshots() {
+    let base = Path::new("./target/build/test_cli_keys");
+    assert!(!base.exists());
+    create_tree(&base.join("root"));
+    let snapshot = Snapshot::new(&base.join("root"), HashType::BLAKE3, vec![], false).unwrap();
+    let signing_key = generate_signing_key();
+    save_signing_key(&signing_key, &base.join("key.pem")).unwrap();
+    save_verifying_key(&signing_key.verifying_key(), &base.join("key.pub.pem")).unwrap();
+    fs::write(base.join("mac.secret"), "secret\n").unwrap();
+    fs::write(base.join("key.hex"), hex::encode([7u8; 32])).unwrap();
+    let options = ExportOptions {
+        signing_key: Some(signing_key),
+        mac_key: Some(MacKey::from_secret(b"secret")),
+        encryption_key: Some(EncryptionKey::Key([7u8; 32])),
+        ..Default::default()
+    };
+    export_with_options(
+        snapshot,
+        "./target/build/test_cli_keys/root.snapshot".to_string(),
+        &options,
+        false,
+    )
+    .unwrap();
+
+    let snapshot = "./target/build/test_cli_keys/root.snapshot";
+    assert_eq!(fshash(&["stats", snapshot]).status.code(), Some(2));
+    let keys = [
+        "--verifying-key",
+        "./target/build/test_cli_keys/key.pub.pem",
+        "--mac-key",
+        "./target/build/test_cli_keys/mac.secret",
+        "--encryption-key",
+        "./target/build/test_cli_keys/key.hex",
+    ];
+    let output = fshash(&[&["stats", snapshot, "--json"], &keys[..]].concat());
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(json_lines(&output)[0]["files"], 2);
+    assert_eq!(
+        fshash(&[&["verify", snapshot], &keys[..]].concat())
+            .status
+            .code(),
+        Some(0)
+    );
+
+    // snap protects its own export with the same keys
+    let snapped = "./target/build/test_cli_keys/snapped.snapshot";
+    let snap = [
+        "snap",
+        "./target/build/test_cli_keys/root",
+        "-o",
+        snapped,
+        "--signing-key",
+        "./target/build/test_cli_keys/key.pem",
+        "--detached",
+    ];
+    assert_eq!(
+        fshash(&[&snap[..], &keys[..]].concat()).status.code(),
+        Some(0)
+    );
+    assert!(base.join("snapped.snapshot.sig").exists());
+    assert_eq!(fshash(&["stats", snapped]).status.code(), Some(2));
+    assert_eq!(
+        fshash(&[&["verify", snapped], &keys[..]].concat())
+            .status
+            .code(),
+        Some(0)
+    );
+
+    fs::write(base.join("mac.secret"), "other\n").unwrap();
+    assert_eq!(
+        fshash(&[&["stats", snapshot], &keys[..]].concat())
+            .status
+            .code(),
+        Some(2)
+    );
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn daemon_scans_once() {
+    let base = Path::new("./target/build/test_cli_daemon");
+    assert!(!base.exists());
+    create_tree(&base.join("root"));
+    fs::write(
+        base.join("fshash.toml"),
+        "state_dir = \"./target/build/test_cli_daemon/state\"\n\n\
+         [[root]]\npath = \"./target/build/test_cli_daemon/root\"\n\n\
+         [[sink]]\ntype = \"stdout\"\n",
+    )
+    .unwrap();
+    let daemon = [
+        "daemon",
+        "./target/build/test_cli_daemon/fshash.toml",
+        "--once",
+    ];
+
+    let output = fshash(&daemon);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(json_lines(&output)[0]["files"], 2);
+    fs::write(base.join("root/hosts"), "10.0.0.1 localhost\n").unwrap();
+    let output = fshash(&daemon);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        json_lines(&output)[0]["changed"][0],
+        "./target/build/test_cli_daemon/root/hosts"
+    );
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn daemon_stops_on_sigterm() {
+    let base = Path::new("./target/build/test_cli_daemon_stop");
+    assert!(!base.exists());
+    create_tree(&base.join("root"));
+    fs::write(
+        base.join("fshash.toml"),
+        "state_dir = \"./target/build/test_cli_daemon_stop/state\"\n\n\
+         [[root]]\npath = \"./target/build/test_cli_daemon_stop/root\"\n\n\
+         [[sink]]\ntype = \"stdout\"\n",
+    )
+    .unwrap();
+    let mut daemon = Command::new(env!("CARGO_BIN_EXE_fshash"))
+        .args(["daemon", "./target/build/test_cli_daemon_stop/fshash.toml"])
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    // let it install its handlers and finish the first scan
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(unsafe { libc::kill(daemon.id() as i32, libc::SIGTERM) }, 0);
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = daemon.try_wait().unwrap() {
+            break status;
+        }
+        if start.elapsed() > Duration::from_secs(10) {
+            daemon.kill().unwrap();
+            panic!("daemon ignored SIGTERM");
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    assert_eq!(status.code(), Some(0));
+
+    fs::remove_dir_all(base).unwrap();
+}